@@ -0,0 +1,841 @@
+use anyhow::Result;
+use std::io::Write;
+
+const RES_STRING_POOL_TYPE: u16 = 0x0001;
+const RES_TABLE_TYPE: u16 = 0x0002;
+const RES_XML_TYPE: u16 = 0x0003;
+const RES_XML_START_NAMESPACE_TYPE: u16 = 0x0100;
+const RES_XML_END_NAMESPACE_TYPE: u16 = 0x0101;
+const RES_XML_START_ELEMENT_TYPE: u16 = 0x0102;
+const RES_XML_END_ELEMENT_TYPE: u16 = 0x0103;
+const RES_XML_RESOURCE_MAP_TYPE: u16 = 0x0180;
+const RES_TABLE_PACKAGE_TYPE: u16 = 0x0200;
+const RES_TABLE_TYPE_TYPE: u16 = 0x0201;
+const RES_TABLE_TYPE_SPEC_TYPE: u16 = 0x0202;
+
+const TYPE_REFERENCE: u8 = 0x01;
+const TYPE_STRING: u8 = 0x03;
+const TYPE_INT_DEC: u8 = 0x10;
+const TYPE_INT_BOOLEAN: u8 = 0x12;
+
+const NO_INDEX: u32 = 0xffffffff;
+/// Size of the `ResTable_config` written for each resource type.
+const CONFIG_SIZE: usize = 64;
+const CONFIG_DENSITY: u32 = 0x0100;
+
+pub const ANDROID_NAMESPACE: &str = "http://schemas.android.com/apk/res/android";
+/// Package ID of an app's own resources.
+pub const APP_PACKAGE_ID: u32 = 0x7f;
+pub const DENSITY_XXHDPI: u16 = 480;
+
+// android.R.attr IDs of the manifest attributes fripack writes.
+pub const ATTR_LABEL: u32 = 0x01010001;
+pub const ATTR_ICON: u32 = 0x01010002;
+pub const ATTR_NAME: u32 = 0x01010003;
+pub const ATTR_DEBUGGABLE: u32 = 0x0101000f;
+pub const ATTR_VALUE: u32 = 0x01010024;
+pub const ATTR_MIN_SDK_VERSION: u32 = 0x0101020c;
+pub const ATTR_VERSION_CODE: u32 = 0x0101021b;
+pub const ATTR_VERSION_NAME: u32 = 0x0101021c;
+pub const ATTR_TARGET_SDK_VERSION: u32 = 0x01010270;
+pub const ATTR_EXTRACT_NATIVE_LIBS: u32 = 0x010104ea;
+pub const ATTR_ROUND_ICON: u32 = 0x0101052c;
+
+/// Stored entries are aligned like `zipalign -P 16` does, so they can be
+/// mapped straight from the APK.
+const STORED_ALIGNMENT: u16 = 4;
+const STORED_LIBRARY_ALIGNMENT: u16 = 16384;
+
+#[derive(Debug, Clone)]
+pub enum XmlValue {
+    String(String),
+    Int(u32),
+    Bool(bool),
+    Reference(u32),
+}
+
+/// An attribute in the android namespace when it has a resource ID, or a
+/// plain one like `package` otherwise.
+#[derive(Debug, Clone)]
+pub struct XmlAttribute {
+    pub name: String,
+    pub resource_id: Option<u32>,
+    pub value: XmlValue,
+}
+
+#[derive(Debug, Clone)]
+pub struct XmlElement {
+    pub name: String,
+    pub attributes: Vec<XmlAttribute>,
+    pub children: Vec<XmlElement>,
+}
+
+impl XmlElement {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            attributes: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    pub fn android_attribute(mut self, name: &str, resource_id: u32, value: XmlValue) -> Self {
+        self.attributes.push(XmlAttribute {
+            name: name.to_string(),
+            resource_id: Some(resource_id),
+            value,
+        });
+        self
+    }
+
+    pub fn attribute(mut self, name: &str, value: XmlValue) -> Self {
+        self.attributes.push(XmlAttribute {
+            name: name.to_string(),
+            resource_id: None,
+            value,
+        });
+        self
+    }
+
+    pub fn child(mut self, child: XmlElement) -> Self {
+        self.children.push(child);
+        self
+    }
+
+    fn walk<'a>(&'a self, elements: &mut Vec<&'a XmlElement>) {
+        elements.push(self);
+        for child in &self.children {
+            child.walk(elements);
+        }
+    }
+}
+
+/// Strings in first-use order, deduplicated.
+#[derive(Default)]
+struct StringPool {
+    strings: Vec<String>,
+}
+
+impl StringPool {
+    fn index(&mut self, value: &str) -> u32 {
+        match self.strings.iter().position(|s| s == value) {
+            Some(index) => index as u32,
+            None => {
+                self.strings.push(value.to_string());
+                (self.strings.len() - 1) as u32
+            }
+        }
+    }
+
+    /// `ResStringPool` chunk with UTF-16 strings.
+    fn to_bytes(&self) -> Vec<u8> {
+        const HEADER_SIZE: usize = 28;
+        let mut strings = Vec::new();
+        let mut offsets = Vec::with_capacity(self.strings.len());
+        for string in &self.strings {
+            offsets.push(strings.len() as u32);
+            let units: Vec<u16> = string.encode_utf16().collect();
+            if units.len() > 0x7fff {
+                write_u16(&mut strings, 0x8000 | (units.len() >> 16) as u16);
+            }
+            write_u16(&mut strings, units.len() as u16);
+            for unit in units {
+                write_u16(&mut strings, unit);
+            }
+            write_u16(&mut strings, 0);
+        }
+        strings.resize(strings.len().next_multiple_of(4), 0);
+
+        let strings_start = HEADER_SIZE + offsets.len() * 4;
+        let mut chunk = chunk_header(
+            RES_STRING_POOL_TYPE,
+            HEADER_SIZE,
+            strings_start + strings.len(),
+        );
+        write_u32(&mut chunk, self.strings.len() as u32);
+        // styleCount, flags
+        write_u32(&mut chunk, 0);
+        write_u32(&mut chunk, 0);
+        write_u32(&mut chunk, strings_start as u32);
+        // stylesStart
+        write_u32(&mut chunk, 0);
+        for offset in offsets {
+            write_u32(&mut chunk, offset);
+        }
+        chunk.extend_from_slice(&strings);
+        chunk
+    }
+}
+
+/// Compile `root` to the binary XML format of `AndroidManifest.xml` in an
+/// APK, declaring the android namespace on the root element.
+pub fn compile_xml(root: &XmlElement) -> Vec<u8> {
+    let mut elements = Vec::new();
+    root.walk(&mut elements);
+
+    // Attribute names with a resource ID come first, in the same order as
+    // the resource map that follows the string pool.
+    let mut resource_ids: Vec<(u32, &str)> = elements
+        .iter()
+        .flat_map(|element| &element.attributes)
+        .filter_map(|attribute| Some((attribute.resource_id?, attribute.name.as_str())))
+        .collect();
+    resource_ids.sort();
+    resource_ids.dedup();
+    let mut pool = StringPool::default();
+    for (_, name) in &resource_ids {
+        pool.index(name);
+    }
+    let prefix = pool.index("android");
+    let uri = pool.index(ANDROID_NAMESPACE);
+
+    let mut body = Vec::new();
+    let mut namespace = chunk_header(RES_XML_START_NAMESPACE_TYPE, 16, 24);
+    write_node_header(&mut namespace, 1);
+    write_u32(&mut namespace, prefix);
+    write_u32(&mut namespace, uri);
+    body.extend_from_slice(&namespace);
+    let mut line = 1;
+    write_element(&mut body, root, &mut pool, uri, &mut line);
+    let mut namespace = chunk_header(RES_XML_END_NAMESPACE_TYPE, 16, 24);
+    write_node_header(&mut namespace, line);
+    write_u32(&mut namespace, prefix);
+    write_u32(&mut namespace, uri);
+    body.extend_from_slice(&namespace);
+
+    let strings = pool.to_bytes();
+    let mut resource_map = chunk_header(RES_XML_RESOURCE_MAP_TYPE, 8, 8 + resource_ids.len() * 4);
+    for (resource_id, _) in &resource_ids {
+        write_u32(&mut resource_map, *resource_id);
+    }
+
+    let mut xml = chunk_header(
+        RES_XML_TYPE,
+        8,
+        8 + strings.len() + resource_map.len() + body.len(),
+    );
+    xml.extend_from_slice(&strings);
+    xml.extend_from_slice(&resource_map);
+    xml.extend_from_slice(&body);
+    xml
+}
+
+fn write_element(
+    body: &mut Vec<u8>,
+    element: &XmlElement,
+    pool: &mut StringPool,
+    uri: u32,
+    line: &mut u32,
+) {
+    const ATTRIBUTE_SIZE: usize = 20;
+
+    *line += 1;
+    // Attributes are sorted by resource ID, plain ones last, as aapt does
+    let mut attributes: Vec<&XmlAttribute> = element.attributes.iter().collect();
+    attributes.sort_by_key(|attribute| attribute.resource_id.unwrap_or(u32::MAX));
+
+    let mut start = chunk_header(
+        RES_XML_START_ELEMENT_TYPE,
+        16,
+        36 + attributes.len() * ATTRIBUTE_SIZE,
+    );
+    write_node_header(&mut start, *line);
+    write_u32(&mut start, NO_INDEX);
+    write_u32(&mut start, pool.index(&element.name));
+    // attributeStart, attributeSize
+    write_u16(&mut start, 20);
+    write_u16(&mut start, ATTRIBUTE_SIZE as u16);
+    write_u16(&mut start, attributes.len() as u16);
+    // idIndex, classIndex, styleIndex
+    write_u16(&mut start, 0);
+    write_u16(&mut start, 0);
+    write_u16(&mut start, 0);
+    for attribute in attributes {
+        let namespace = if attribute.resource_id.is_some() {
+            uri
+        } else {
+            NO_INDEX
+        };
+        write_u32(&mut start, namespace);
+        write_u32(&mut start, pool.index(&attribute.name));
+        let (raw_value, data_type, data) = match &attribute.value {
+            XmlValue::String(value) => {
+                let index = pool.index(value);
+                (index, TYPE_STRING, index)
+            }
+            XmlValue::Int(value) => (NO_INDEX, TYPE_INT_DEC, *value),
+            XmlValue::Bool(value) => (
+                NO_INDEX,
+                TYPE_INT_BOOLEAN,
+                if *value { NO_INDEX } else { 0 },
+            ),
+            XmlValue::Reference(id) => (NO_INDEX, TYPE_REFERENCE, *id),
+        };
+        write_u32(&mut start, raw_value);
+        write_res_value(&mut start, data_type, data);
+    }
+    body.extend_from_slice(&start);
+
+    for child in &element.children {
+        write_element(body, child, pool, uri, line);
+    }
+
+    let mut end = chunk_header(RES_XML_END_ELEMENT_TYPE, 16, 24);
+    write_node_header(&mut end, *line);
+    write_u32(&mut end, NO_INDEX);
+    write_u32(&mut end, pool.index(&element.name));
+    body.extend_from_slice(&end);
+}
+
+/// `ResXMLTree_node` fields after the chunk header: line number and an
+/// empty comment.
+fn write_node_header(chunk: &mut Vec<u8>, line: u32) {
+    write_u32(chunk, line);
+    write_u32(chunk, NO_INDEX);
+}
+
+struct ResourceType {
+    name: String,
+    density: u16,
+    /// Entry name and string value, the value of a file resource being its
+    /// path in the APK.
+    entries: Vec<(String, String)>,
+}
+
+/// A `resources.arsc` with a single package of string-valued resources,
+/// one configuration per type.
+pub struct ResourceTable {
+    package_name: String,
+    types: Vec<ResourceType>,
+}
+
+impl ResourceTable {
+    pub fn new(package_name: &str) -> Self {
+        Self {
+            package_name: package_name.to_string(),
+            types: Vec::new(),
+        }
+    }
+
+    /// Add a resource and return its ID. `density` is 0 for the default
+    /// configuration; every entry of a type shares the same one.
+    pub fn add(&mut self, type_name: &str, density: u16, name: &str, value: &str) -> Result<u32> {
+        let type_index = match self.types.iter().position(|t| t.name == type_name) {
+            Some(index) => index,
+            None => {
+                self.types.push(ResourceType {
+                    name: type_name.to_string(),
+                    density,
+                    entries: Vec::new(),
+                });
+                self.types.len() - 1
+            }
+        };
+        let resource_type = &mut self.types[type_index];
+        if resource_type.density != density {
+            anyhow::bail!("Resources of type {type_name} must share one density");
+        }
+        if resource_type.entries.iter().any(|(entry, _)| entry == name) {
+            anyhow::bail!("Duplicate resource {type_name}/{name}");
+        }
+        resource_type
+            .entries
+            .push((name.to_string(), value.to_string()));
+        let entry_index = resource_type.entries.len() - 1;
+        Ok(APP_PACKAGE_ID << 24 | (type_index as u32 + 1) << 16 | entry_index as u32)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        const PACKAGE_HEADER_SIZE: usize = 288;
+        const TYPE_HEADER_SIZE: usize = 20 + CONFIG_SIZE;
+        const ENTRY_SIZE: usize = 16;
+
+        let mut values = StringPool::default();
+        let mut type_names = StringPool::default();
+        let mut keys = StringPool::default();
+        let mut chunks = Vec::new();
+        for (type_index, resource_type) in self.types.iter().enumerate() {
+            type_names.index(&resource_type.name);
+            let id = type_index as u8 + 1;
+            let count = resource_type.entries.len();
+
+            let mut spec = chunk_header(RES_TABLE_TYPE_SPEC_TYPE, 16, 16 + count * 4);
+            spec.extend_from_slice(&[id, 0, 0, 0]);
+            write_u32(&mut spec, count as u32);
+            let flags = if resource_type.density == 0 {
+                0
+            } else {
+                CONFIG_DENSITY
+            };
+            for _ in 0..count {
+                write_u32(&mut spec, flags);
+            }
+            chunks.extend_from_slice(&spec);
+
+            let entries_start = TYPE_HEADER_SIZE + count * 4;
+            let mut chunk = chunk_header(
+                RES_TABLE_TYPE_TYPE,
+                TYPE_HEADER_SIZE,
+                entries_start + count * ENTRY_SIZE,
+            );
+            chunk.extend_from_slice(&[id, 0, 0, 0]);
+            write_u32(&mut chunk, count as u32);
+            write_u32(&mut chunk, entries_start as u32);
+            let mut config = [0u8; CONFIG_SIZE];
+            config[..4].copy_from_slice(&(CONFIG_SIZE as u32).to_le_bytes());
+            config[14..16].copy_from_slice(&resource_type.density.to_le_bytes());
+            chunk.extend_from_slice(&config);
+            for index in 0..count {
+                write_u32(&mut chunk, (index * ENTRY_SIZE) as u32);
+            }
+            for (name, value) in &resource_type.entries {
+                // ResTable_entry: size, flags, key
+                write_u16(&mut chunk, 8);
+                write_u16(&mut chunk, 0);
+                write_u32(&mut chunk, keys.index(name));
+                write_res_value(&mut chunk, TYPE_STRING, values.index(value));
+            }
+            chunks.extend_from_slice(&chunk);
+        }
+
+        let type_strings = type_names.to_bytes();
+        let key_strings = keys.to_bytes();
+        let mut package = chunk_header(
+            RES_TABLE_PACKAGE_TYPE,
+            PACKAGE_HEADER_SIZE,
+            PACKAGE_HEADER_SIZE + type_strings.len() + key_strings.len() + chunks.len(),
+        );
+        write_u32(&mut package, APP_PACKAGE_ID);
+        let mut name: Vec<u16> = self.package_name.encode_utf16().take(127).collect();
+        name.resize(128, 0);
+        for unit in name {
+            write_u16(&mut package, unit);
+        }
+        write_u32(&mut package, PACKAGE_HEADER_SIZE as u32);
+        write_u32(&mut package, self.types.len() as u32);
+        write_u32(
+            &mut package,
+            (PACKAGE_HEADER_SIZE + type_strings.len()) as u32,
+        );
+        write_u32(&mut package, keys.strings.len() as u32);
+        // typeIdOffset
+        write_u32(&mut package, 0);
+        debug_assert_eq!(package.len(), PACKAGE_HEADER_SIZE);
+        package.extend_from_slice(&type_strings);
+        package.extend_from_slice(&key_strings);
+        package.extend_from_slice(&chunks);
+
+        let value_strings = values.to_bytes();
+        let mut table = chunk_header(RES_TABLE_TYPE, 12, 12 + value_strings.len() + package.len());
+        // packageCount
+        write_u32(&mut table, 1);
+        table.extend_from_slice(&value_strings);
+        table.extend_from_slice(&package);
+        table
+    }
+}
+
+pub struct ApkEntry {
+    pub name: String,
+    pub data: Vec<u8>,
+    pub compress: bool,
+}
+
+/// Write `entries` to a zip in order, with fixed timestamps. Stored
+/// entries are aligned as `zipalign` would.
+pub fn write_apk(entries: &[ApkEntry]) -> Result<Vec<u8>> {
+    let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    for entry in entries {
+        let options = zip::write::FileOptions::default()
+            .last_modified_time(zip::DateTime::default())
+            .unix_permissions(0o644);
+        if entry.compress {
+            writer.start_file(
+                entry.name.as_str(),
+                options.compression_method(zip::CompressionMethod::Deflated),
+            )?;
+        } else {
+            let alignment = if entry.name.ends_with(".so") {
+                STORED_LIBRARY_ALIGNMENT
+            } else {
+                STORED_ALIGNMENT
+            };
+            writer.start_file_aligned(
+                entry.name.as_str(),
+                options.compression_method(zip::CompressionMethod::Stored),
+                alignment,
+            )?;
+        }
+        writer.write_all(&entry.data)?;
+    }
+    Ok(writer.finish()?.into_inner())
+}
+
+fn chunk_header(chunk_type: u16, header_size: usize, size: usize) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(size);
+    write_u16(&mut chunk, chunk_type);
+    write_u16(&mut chunk, header_size as u16);
+    write_u32(&mut chunk, size as u32);
+    chunk
+}
+
+fn write_res_value(data: &mut Vec<u8>, data_type: u8, value: u32) {
+    write_u16(data, 8);
+    data.extend_from_slice(&[0, data_type]);
+    write_u32(data, value);
+}
+
+fn write_u16(data: &mut Vec<u8>, value: u16) {
+    data.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(data: &mut Vec<u8>, value: u32) {
+    data.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{u16_at, u32_at};
+    use std::io::Read;
+
+    /// Parse a UTF-16 `ResStringPool` chunk.
+    fn parse_string_pool(chunk: &[u8]) -> Vec<String> {
+        assert_eq!(u16_at(chunk, 0), RES_STRING_POOL_TYPE);
+        assert_eq!(u16_at(chunk, 2), 28);
+        assert_eq!(u32_at(chunk, 4) % 4, 0);
+        assert_eq!(u32_at(chunk, 16), 0, "expected UTF-16 strings");
+        let strings_start = u32_at(chunk, 20) as usize;
+        (0..u32_at(chunk, 8) as usize)
+            .map(|index| {
+                let mut at = strings_start + u32_at(chunk, 28 + index * 4) as usize;
+                let mut len = u16_at(chunk, at) as usize;
+                at += 2;
+                if len & 0x8000 != 0 {
+                    len = (len & 0x7fff) << 16 | u16_at(chunk, at) as usize;
+                    at += 2;
+                }
+                let units: Vec<u16> = (0..len).map(|i| u16_at(chunk, at + i * 2)).collect();
+                assert_eq!(u16_at(chunk, at + len * 2), 0);
+                String::from_utf16(&units).unwrap()
+            })
+            .collect()
+    }
+
+    /// Element name, attributes (name, resource ID, value) and children,
+    /// with the value printed since `XmlValue` has no `PartialEq`.
+    #[derive(Debug, PartialEq)]
+    struct Node {
+        name: String,
+        attributes: Vec<(String, Option<u32>, String)>,
+        children: Vec<Node>,
+    }
+
+    fn expected_node(element: &XmlElement) -> Node {
+        let mut attributes: Vec<&XmlAttribute> = element.attributes.iter().collect();
+        attributes.sort_by_key(|attribute| attribute.resource_id.unwrap_or(u32::MAX));
+        Node {
+            name: element.name.clone(),
+            attributes: attributes
+                .into_iter()
+                .map(|a| (a.name.clone(), a.resource_id, format!("{:?}", a.value)))
+                .collect(),
+            children: element.children.iter().map(expected_node).collect(),
+        }
+    }
+
+    /// Parse binary XML back into a tree.
+    fn parse_xml(xml: &[u8]) -> Node {
+        assert_eq!(u16_at(xml, 0), RES_XML_TYPE);
+        assert_eq!(u32_at(xml, 4) as usize, xml.len());
+        let mut at = u16_at(xml, 2) as usize;
+        let pool_size = u32_at(xml, at + 4) as usize;
+        let strings = parse_string_pool(&xml[at..at + pool_size]);
+        at += pool_size;
+        assert_eq!(u16_at(xml, at), RES_XML_RESOURCE_MAP_TYPE);
+        let map_size = u32_at(xml, at + 4) as usize;
+        let resource_map: Vec<u32> = (8..map_size)
+            .step_by(4)
+            .map(|i| u32_at(xml, at + i))
+            .collect();
+        at += map_size;
+
+        let mut uri = None;
+        let mut stack: Vec<Node> = Vec::new();
+        let mut root = None;
+        while at < xml.len() {
+            let size = u32_at(xml, at + 4) as usize;
+            let chunk = &xml[at..at + size];
+            match u16_at(chunk, 0) {
+                RES_XML_START_NAMESPACE_TYPE => {
+                    assert_eq!(strings[u32_at(chunk, 16) as usize], "android");
+                    uri = Some(u32_at(chunk, 20));
+                    assert_eq!(strings[uri.unwrap() as usize], ANDROID_NAMESPACE);
+                }
+                RES_XML_END_NAMESPACE_TYPE => assert_eq!(Some(u32_at(chunk, 20)), uri),
+                RES_XML_START_ELEMENT_TYPE => {
+                    let attribute_start = 16 + u16_at(chunk, 24) as usize;
+                    let attribute_size = u16_at(chunk, 26) as usize;
+                    let attributes = (0..u16_at(chunk, 28) as usize)
+                        .map(|index| {
+                            let attribute = &chunk[attribute_start + index * attribute_size..];
+                            let name_index = u32_at(attribute, 4) as usize;
+                            let resource_id = resource_map.get(name_index).copied();
+                            let namespace = u32_at(attribute, 0);
+                            assert_eq!(
+                                namespace,
+                                if resource_id.is_some() {
+                                    uri.unwrap()
+                                } else {
+                                    NO_INDEX
+                                }
+                            );
+                            let data = u32_at(attribute, 16);
+                            let value = match attribute[15] {
+                                TYPE_STRING => {
+                                    assert_eq!(u32_at(attribute, 8), data);
+                                    XmlValue::String(strings[data as usize].clone())
+                                }
+                                TYPE_INT_DEC => XmlValue::Int(data),
+                                TYPE_INT_BOOLEAN => XmlValue::Bool(data != 0),
+                                TYPE_REFERENCE => XmlValue::Reference(data),
+                                other => panic!("unexpected value type {other:#x}"),
+                            };
+                            (
+                                strings[name_index].clone(),
+                                resource_id,
+                                format!("{value:?}"),
+                            )
+                        })
+                        .collect();
+                    stack.push(Node {
+                        name: strings[u32_at(chunk, 20) as usize].clone(),
+                        attributes,
+                        children: Vec::new(),
+                    });
+                }
+                RES_XML_END_ELEMENT_TYPE => {
+                    let node = stack.pop().unwrap();
+                    assert_eq!(strings[u32_at(chunk, 20) as usize], node.name);
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(node),
+                        None => root = Some(node),
+                    }
+                }
+                other => panic!("unexpected chunk {other:#x}"),
+            }
+            at += size;
+        }
+        assert!(stack.is_empty());
+        root.unwrap()
+    }
+
+    #[test]
+    fn compile_xml_round_trips() {
+        let manifest = XmlElement::new("manifest")
+            .attribute(
+                "package",
+                XmlValue::String("com.example.module".to_string()),
+            )
+            .android_attribute(
+                "versionName",
+                ATTR_VERSION_NAME,
+                XmlValue::String("1.0".to_string()),
+            )
+            .android_attribute("versionCode", ATTR_VERSION_CODE, XmlValue::Int(1))
+            .child(XmlElement::new("uses-sdk").android_attribute(
+                "minSdkVersion",
+                ATTR_MIN_SDK_VERSION,
+                XmlValue::Int(24),
+            ))
+            .child(
+                XmlElement::new("application")
+                    .android_attribute(
+                        "label",
+                        ATTR_LABEL,
+                        XmlValue::String("Module ✓ 😀".to_string()),
+                    )
+                    .android_attribute("icon", ATTR_ICON, XmlValue::Reference(0x7f010000))
+                    .android_attribute("debuggable", ATTR_DEBUGGABLE, XmlValue::Bool(false))
+                    .android_attribute(
+                        "extractNativeLibs",
+                        ATTR_EXTRACT_NATIVE_LIBS,
+                        XmlValue::Bool(true),
+                    )
+                    .child(
+                        XmlElement::new("meta-data")
+                            .android_attribute("value", ATTR_VALUE, XmlValue::Bool(true))
+                            .android_attribute(
+                                "name",
+                                ATTR_NAME,
+                                XmlValue::String("xposedmodule".to_string()),
+                            ),
+                    )
+                    .child(
+                        XmlElement::new("meta-data")
+                            .android_attribute(
+                                "name",
+                                ATTR_NAME,
+                                XmlValue::String("xposedscope".to_string()),
+                            )
+                            .android_attribute(
+                                "value",
+                                ATTR_VALUE,
+                                XmlValue::String("1.0".to_string()),
+                            ),
+                    ),
+            );
+
+        assert_eq!(parse_xml(&compile_xml(&manifest)), expected_node(&manifest));
+    }
+
+    #[test]
+    fn resource_table_round_trips() {
+        let mut table = ResourceTable::new("com.example.module");
+        assert_eq!(
+            table
+                .add("drawable", DENSITY_XXHDPI, "icon", "res/icon.png")
+                .unwrap(),
+            0x7f010000
+        );
+        assert_eq!(
+            table.add("string", 0, "app_name", "Module ✓").unwrap(),
+            0x7f020000
+        );
+        assert_eq!(
+            table
+                .add("string", 0, "description", "res/icon.png")
+                .unwrap(),
+            0x7f020001
+        );
+        assert!(table.add("string", 0, "app_name", "Other").is_err());
+        assert!(table
+            .add("drawable", 0, "round_icon", "res/round.png")
+            .is_err());
+
+        let arsc = table.to_bytes();
+        assert_eq!(u16_at(&arsc, 0), RES_TABLE_TYPE);
+        assert_eq!(u32_at(&arsc, 4) as usize, arsc.len());
+        assert_eq!(u32_at(&arsc, 8), 1);
+        let pool_size = u32_at(&arsc, 16) as usize;
+        let values = parse_string_pool(&arsc[12..12 + pool_size]);
+
+        let package = &arsc[12 + pool_size..];
+        assert_eq!(u16_at(package, 0), RES_TABLE_PACKAGE_TYPE);
+        assert_eq!(u32_at(package, 4) as usize, package.len());
+        assert_eq!(u32_at(package, 8), APP_PACKAGE_ID);
+        let name: Vec<u16> = (0..128)
+            .map(|i| u16_at(package, 12 + i * 2))
+            .take_while(|&unit| unit != 0)
+            .collect();
+        assert_eq!(String::from_utf16(&name).unwrap(), "com.example.module");
+        let type_strings_offset = u32_at(package, 268) as usize;
+        let key_strings_offset = u32_at(package, 276) as usize;
+        let type_names = parse_string_pool(&package[type_strings_offset..]);
+        let keys = parse_string_pool(&package[key_strings_offset..]);
+        assert_eq!(type_names, ["drawable", "string"]);
+
+        let mut resources = Vec::new();
+        let mut at = key_strings_offset + u32_at(package, key_strings_offset + 4) as usize;
+        while at < package.len() {
+            let chunk = &package[at..at + u32_at(package, at + 4) as usize];
+            let type_id = chunk[8] as u32;
+            let count = u32_at(chunk, 12) as usize;
+            match u16_at(chunk, 0) {
+                RES_TABLE_TYPE_SPEC_TYPE => assert_eq!(chunk.len(), 16 + count * 4),
+                RES_TABLE_TYPE_TYPE => {
+                    let header_size = u16_at(chunk, 2) as usize;
+                    let entries_start = u32_at(chunk, 16) as usize;
+                    assert_eq!(u32_at(chunk, 20) as usize, CONFIG_SIZE);
+                    let density = u16_at(chunk, 20 + 14);
+                    for index in 0..count {
+                        let entry = &chunk
+                            [entries_start + u32_at(chunk, header_size + index * 4) as usize..];
+                        assert_eq!(u16_at(entry, 0), 8);
+                        assert_eq!(entry[8 + 3], TYPE_STRING);
+                        resources.push((
+                            APP_PACKAGE_ID << 24 | type_id << 16 | index as u32,
+                            type_names[type_id as usize - 1].clone(),
+                            density,
+                            keys[u32_at(entry, 4) as usize].clone(),
+                            values[u32_at(entry, 12) as usize].clone(),
+                        ));
+                    }
+                }
+                other => panic!("unexpected chunk {other:#x}"),
+            }
+            at += chunk.len();
+        }
+
+        let expected = [
+            (
+                0x7f010000,
+                "drawable",
+                DENSITY_XXHDPI,
+                "icon",
+                "res/icon.png",
+            ),
+            (0x7f020000, "string", 0, "app_name", "Module ✓"),
+            (0x7f020001, "string", 0, "description", "res/icon.png"),
+        ];
+        assert_eq!(resources.len(), expected.len());
+        for (resource, (id, type_name, density, key, value)) in resources.iter().zip(expected) {
+            assert_eq!(
+                resource,
+                &(
+                    id,
+                    type_name.to_string(),
+                    density,
+                    key.to_string(),
+                    value.to_string()
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn write_apk_aligns_stored_entries() {
+        let entries = [
+            ("AndroidManifest.xml", vec![1; 100], true),
+            ("resources.arsc", vec![2; 5], false),
+            ("lib/arm64-v8a/libx.so", vec![3; 3], false),
+            ("assets/a", vec![4; 7], false),
+            ("lib/x86_64/libyy.so", vec![5; 9], false),
+            ("classes.dex", vec![6; 50], true),
+        ]
+        .map(|(name, data, compress)| ApkEntry {
+            name: name.to_string(),
+            data,
+            compress,
+        });
+        let apk = write_apk(&entries).unwrap();
+
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(&apk)).unwrap();
+        assert_eq!(archive.len(), entries.len());
+        for (index, entry) in entries.iter().enumerate() {
+            let mut file = archive.by_index(index).unwrap();
+            assert_eq!(file.name(), entry.name);
+            let data_start = file.data_start() as usize;
+            if entry.compress {
+                assert_eq!(file.compression(), zip::CompressionMethod::Deflated);
+            } else {
+                assert_eq!(file.compression(), zip::CompressionMethod::Stored);
+                let alignment = if entry.name.ends_with(".so") {
+                    16384
+                } else {
+                    4
+                };
+                assert_eq!(
+                    data_start % alignment,
+                    0,
+                    "{} at {data_start:#x}",
+                    entry.name
+                );
+                assert_eq!(&apk[data_start..data_start + entry.data.len()], entry.data);
+            }
+            let mut data = Vec::new();
+            file.read_to_end(&mut data).unwrap();
+            assert_eq!(data, entry.data);
+        }
+    }
+}
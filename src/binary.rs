@@ -1,16 +1,111 @@
+use crate::codesign;
+use crate::compression::{self, Codec, Compression};
+use crate::crypto::{self, PayloadEncryption};
+use crate::macho;
+use crate::pe_resource::{self, VersionInfo};
+use crate::validate;
 use anyhow::{Context, Result};
-use log::info;
+use log::{info, warn};
 use object::{
     build::{elf::Dynamic, ByteString},
     elf::{PF_R, PF_W, PT_DYNAMIC, PT_LOAD, PT_PHDR},
     pe,
     read::{
         coff::CoffHeader,
-        pe::{ImageNtHeaders, ImageOptionalHeader},
+        pe::{ImageNtHeaders, ImageOptionalHeader, ImageThunkData},
     },
-    LittleEndian as LE, Object, ObjectSymbol,
+    LittleEndian as LE, Object, ObjectSection, ObjectSegment, ObjectSymbol,
 };
 use rand::Rng;
+/// Keywords rewritten by `anti_anti_frida`, with their fixed replacement if
+/// any. Everything else gets a random string of the same length.
+pub const DEFAULT_ANTI_FRIDA_KEYWORDS: &[(&str, Option<&str>)] = &[
+    ("frida", None),
+    ("GMainLoop", Some("pool-6-th")),
+    ("gum-js-loop", Some("pool-6-thre")),
+    ("gmain", Some("Timer")),
+    ("gum-js", None),
+    ("gum", None),
+    ("gdbus", None),
+    ("Gum", None),
+    ("Frida", None),
+    ("GUM", None),
+    ("GDBus", None),
+    ("g_dbus", None),
+    ("g_main", None),
+    ("GMain", None),
+    ("solist", None),
+    ("GLib-GIO", None),
+    ("GLib", None),
+    ("agent", None),
+    ("_Worker", None),
+];
+
+#[derive(Debug, Clone)]
+pub struct KeywordRule {
+    pub keyword: String,
+    /// Must be as long as `keyword`; random when `None`.
+    pub replacement: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AntiFridaOptions {
+    /// Applied in order, so longer keywords must come before their substrings.
+    pub keywords: Vec<KeywordRule>,
+    /// Scanned in addition to `.rodata`/`.dynstr` (ELF) or `.rdata` and the
+    /// export names (PE).
+    pub extra_sections: Vec<String>,
+}
+
+impl Default for AntiFridaOptions {
+    fn default() -> Self {
+        Self {
+            keywords: DEFAULT_ANTI_FRIDA_KEYWORDS
+                .iter()
+                .map(|(keyword, replacement)| KeywordRule {
+                    keyword: keyword.to_string(),
+                    replacement: replacement.map(str::to_string),
+                })
+                .collect(),
+            extra_sections: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct KeywordReport {
+    pub keyword: String,
+    pub replacement: String,
+    /// Section name and offset inside that section of each replaced occurrence.
+    pub occurrences: Vec<(String, usize)>,
+}
+
+/// Header version of plain (optionally xz compressed) payloads.
+pub const EMBEDDED_CONFIG_VERSION: i32 = 1;
+/// Header version of encrypted payloads; the data at `data_offset` starts
+/// with a `crypto::EncryptionHeader`.
+pub const EMBEDDED_CONFIG_VERSION_ENCRYPTED: i32 = 2;
+/// Header version where `codec` holds a `Codec` id, or'ed with
+/// `CODEC_FLAG_ENCRYPTED`. Only used for codecs older runtimes don't know.
+pub const EMBEDDED_CONFIG_VERSION_CODEC: i32 = 3;
+/// Header version where the data at `data_offset` starts with
+/// `PayloadChecksums`. `codec` is encoded as in version 3.
+pub const EMBEDDED_CONFIG_VERSION_CHECKSUM: i32 = 4;
+pub const CODEC_FLAG_ENCRYPTED: u8 = 0x80;
+/// Magic values of the empty header compiled into the prebuilts.
+pub const EMBEDDED_CONFIG_MAGIC1: i32 = 0x0d000721;
+pub const EMBEDDED_CONFIG_MAGIC2: i32 = 0x1f8a4e2b;
+
+/// The `magic2` that goes with a randomized `magic1`. Runtimes accept a
+/// header whose magic values are either the fixed pair or satisfy
+/// `magic2 == randomized_magic2(magic1)`. The relation is public and the
+/// same for every build, so the outputs still share a pattern: a scanner
+/// only has to test it at each offset.
+pub fn randomized_magic2(magic1: i32) -> i32 {
+    let mixed = (magic1 as u32 ^ EMBEDDED_CONFIG_MAGIC2 as u32).wrapping_mul(0x9e3779b1);
+    (mixed ^ (mixed >> 16)) as i32
+}
+
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy)]
 pub struct EmbeddedConfig {
@@ -19,34 +114,92 @@ pub struct EmbeddedConfig {
     pub version: i32,
     pub data_size: i32,
     pub data_offset: i32,
-    pub data_xz: bool,
+    /// Version 1 and 2: 1 if xz compressed. Version 3: see
+    /// `EMBEDDED_CONFIG_VERSION_CODEC`.
+    pub codec: u8,
 }
 
 impl Default for EmbeddedConfig {
     fn default() -> Self {
         Self {
-            magic1: 0x0d000721,
-            magic2: 0x1f8a4e2b,
-            version: 1,
+            magic1: EMBEDDED_CONFIG_MAGIC1,
+            magic2: EMBEDDED_CONFIG_MAGIC2,
+            version: EMBEDDED_CONFIG_VERSION,
             data_size: 0,
             data_offset: 0,
-            data_xz: false,
+            codec: Codec::None as u8,
         }
     }
 }
 
 impl EmbeddedConfig {
-    pub fn new(data_size: i32, data_offset: i32, data_xz: bool) -> Self {
+    pub fn new(
+        data_size: i32,
+        data_offset: i32,
+        codec: Codec,
+        encrypted: bool,
+        checksum: bool,
+    ) -> Self {
+        let flags = if encrypted { CODEC_FLAG_ENCRYPTED } else { 0 };
+        let (version, codec) = match (codec, encrypted) {
+            _ if checksum => (EMBEDDED_CONFIG_VERSION_CHECKSUM, codec as u8 | flags),
+            (Codec::None | Codec::Xz, false) => (EMBEDDED_CONFIG_VERSION, codec as u8),
+            (Codec::None | Codec::Xz, true) => (EMBEDDED_CONFIG_VERSION_ENCRYPTED, codec as u8),
+            _ => (EMBEDDED_CONFIG_VERSION_CODEC, codec as u8 | flags),
+        };
         Self {
-            magic1: 0x0d000721,
-            magic2: 0x1f8a4e2b,
-            version: 1,
+            magic1: EMBEDDED_CONFIG_MAGIC1,
+            magic2: EMBEDDED_CONFIG_MAGIC2,
+            version,
             data_size,
             data_offset,
-            data_xz,
+            codec,
         }
     }
 
+    pub fn codec(&self) -> Result<Codec> {
+        match self.version {
+            EMBEDDED_CONFIG_VERSION_CODEC | EMBEDDED_CONFIG_VERSION_CHECKSUM => {
+                Codec::from_id(self.codec & !CODEC_FLAG_ENCRYPTED)
+            }
+            _ if self.codec != 0 => Ok(Codec::Xz),
+            _ => Ok(Codec::None),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        match self.version {
+            EMBEDDED_CONFIG_VERSION_CODEC | EMBEDDED_CONFIG_VERSION_CHECKSUM => {
+                self.codec & CODEC_FLAG_ENCRYPTED != 0
+            }
+            version => version == EMBEDDED_CONFIG_VERSION_ENCRYPTED,
+        }
+    }
+
+    pub fn has_checksums(&self) -> bool {
+        self.version == EMBEDDED_CONFIG_VERSION_CHECKSUM
+    }
+
+    pub fn has_randomized_magic(&self) -> bool {
+        self.magic1 != EMBEDDED_CONFIG_MAGIC1 && self.magic2 == randomized_magic2(self.magic1)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let field = |i: usize| {
+            Some(i32::from_le_bytes(
+                bytes.get(i * 4..i * 4 + 4)?.try_into().ok()?,
+            ))
+        };
+        Some(Self {
+            magic1: field(0)?,
+            magic2: field(1)?,
+            version: field(2)?,
+            data_size: field(3)?,
+            data_offset: field(4)?,
+            codec: *bytes.get(20)?,
+        })
+    }
+
     pub fn as_bytes(&self) -> Vec<u8> {
         let mut bytes = vec![0; std::mem::size_of::<EmbeddedConfig>()];
         unsafe {
@@ -61,222 +214,1074 @@ impl EmbeddedConfig {
     }
 }
 
+/// Placed in front of the stored data for `EmbeddedConfig` version 4, so a
+/// truncated or corrupted payload is refused instead of being run.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct PayloadChecksums {
+    /// CRC-32 of the stored bytes that follow, after compression and
+    /// encryption.
+    pub stored_crc32: u32,
+    /// CRC-32 and size of the config after decryption and decompression.
+    pub content_crc32: u32,
+    pub content_size: u32,
+}
+
+impl PayloadChecksums {
+    pub const SIZE: usize = std::mem::size_of::<PayloadChecksums>();
+
+    pub fn new(content: &[u8], stored: &[u8]) -> Self {
+        Self {
+            stored_crc32: crc32fast::hash(stored),
+            content_crc32: crc32fast::hash(content),
+            content_size: content.len() as u32,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::SIZE);
+        bytes.extend_from_slice(&{ self.stored_crc32 }.to_le_bytes());
+        bytes.extend_from_slice(&{ self.content_crc32 }.to_le_bytes());
+        bytes.extend_from_slice(&{ self.content_size }.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let field = |i: usize| {
+            Some(u32::from_le_bytes(
+                bytes.get(i * 4..i * 4 + 4)?.try_into().ok()?,
+            ))
+        };
+        Some(Self {
+            stored_crc32: field(0)?,
+            content_crc32: field(1)?,
+            content_size: field(2)?,
+        })
+    }
+
+    pub fn verify_stored(&self, stored: &[u8]) -> Result<()> {
+        let actual = crc32fast::hash(stored);
+        if actual != self.stored_crc32 {
+            anyhow::bail!(
+                "Payload is corrupted or truncated: stored data CRC-32 is {actual:08x}, expected {:08x}",
+                { self.stored_crc32 }
+            );
+        }
+        Ok(())
+    }
+
+    pub fn verify_content(&self, content: &[u8]) -> Result<()> {
+        let actual = crc32fast::hash(content);
+        if content.len() != self.content_size as usize || actual != self.content_crc32 {
+            anyhow::bail!(
+                "Decoded payload does not match its checksum: {} bytes with CRC-32 {actual:08x}, expected {} bytes with {:08x}",
+                content.len(),
+                { self.content_size },
+                { self.content_crc32 }
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Section appended by `copy_pe_file`. Its contents are built once the
+/// section's RVA is known.
+struct PeNewSection {
+    name: [u8; 8],
+    characteristics: u32,
+    size: u32,
+    contents: Box<dyn FnOnce(u32) -> Vec<u8>>,
+    /// Data directories pointing into the section: (index, offset, size).
+    data_directories: Vec<(usize, u32, u32)>,
+    /// Drop a trailing section of the same name left by a previous run.
+    replaces_existing: bool,
+}
+
+/// Where the keyword rewrite may touch the binary.
+#[derive(Default)]
+struct KeywordTargets {
+    sections: Vec<(String, std::ops::Range<usize>)>,
+    /// Occurrences overlapping these ranges are left alone.
+    protected: Vec<std::ops::Range<usize>>,
+    /// PE export name pointer and ordinal tables, re-sorted afterwards.
+    export_names: Option<(std::ops::Range<usize>, std::ops::Range<usize>)>,
+}
+
+/// Changes queued on a `BinaryProcessor`, applied together by `finish`.
+#[derive(Default)]
+struct Transforms {
+    /// Compressed and possibly encrypted payload, and its header.
+    payload: Option<(Vec<u8>, EmbeddedConfig)>,
+    /// Libraries to load on startup, with the symbol to import (PE only).
+    needed: Vec<(String, Option<String>)>,
+    /// Libraries to load on startup if present (Mach-O only).
+    weak_needed: Vec<String>,
+    strings: Option<AntiFridaOptions>,
+    /// Version resource to write into `.rsrc` (PE only).
+    version_info: Option<VersionInfo>,
+}
+
+impl Transforms {
+    fn magic_change(&self) -> Option<String> {
+        let (_, embedded_config) = self.payload.as_ref()?;
+        embedded_config.has_randomized_magic().then(|| {
+            format!(
+                "Replace the header magic with {:#010x} {:#010x}",
+                { embedded_config.magic1 } as u32,
+                { embedded_config.magic2 } as u32
+            )
+        })
+    }
+}
+
+/// What `BinaryProcessor::finish` changed, or `plan` would change.
+#[derive(Debug, Default)]
+pub struct PipelineReport {
+    /// One line per layout change.
+    pub changes: Vec<String>,
+    pub keywords: Vec<KeywordReport>,
+}
+
 pub enum ObjectFormat {
     Elf,
     Pe,
+    MachO,
 }
 
+/// Smallest page size we lay out ELF segments for.
+const MIN_PAGE_SIZE: u64 = 0x1000;
+
+/// Collects transforms on a binary and applies them with one parse and one
+/// write in `finish`.
 pub struct BinaryProcessor {
     data: Vec<u8>,
     format: ObjectFormat,
+    /// ELF page size override; detected from the PT_LOAD alignment otherwise.
+    page_size: Option<u64>,
+    /// Replace an existing Mach-O code signature with an ad-hoc one.
+    resign: bool,
+    /// Identifier to ad-hoc sign an unsigned Mach-O with.
+    adhoc_identifier: Option<String>,
+    transforms: Transforms,
 }
 
 impl BinaryProcessor {
     pub fn new(data: Vec<u8>) -> Result<Self> {
+        macho::ensure_thin(&data)?;
         let format = match object::read::File::parse(data.as_slice())? {
             object::read::File::Elf32(_) | object::read::File::Elf64(_) => ObjectFormat::Elf,
             object::read::File::Pe32(_) | object::read::File::Pe64(_) => ObjectFormat::Pe,
-            _ => anyhow::bail!("Invalid ELF/PE binary"),
+            object::read::File::MachO64(_) => ObjectFormat::MachO,
+            _ => anyhow::bail!("Invalid ELF/PE/Mach-O binary"),
         };
 
-        Ok(Self { data, format })
+        Ok(Self {
+            data,
+            format,
+            page_size: None,
+            resign: true,
+            adhoc_identifier: None,
+            transforms: Transforms::default(),
+        })
+    }
+
+    /// Whether `finish` replaces the code signature of a signed Mach-O with
+    /// an ad-hoc one (the default). Without it, the original signature is
+    /// kept even though it no longer matches, for callers that re-sign the
+    /// output themselves.
+    pub fn set_resign(&mut self, resign: bool) {
+        self.resign = resign;
+    }
+
+    /// Ad-hoc sign an unsigned Mach-O output with `identifier` too, so that
+    /// arm64 macOS loads it. Only applies while re-signing is enabled.
+    pub fn set_adhoc_identifier(&mut self, identifier: &str) {
+        self.adhoc_identifier = Some(identifier.to_string());
+    }
+
+    /// The ad-hoc signature `finish` gives the Mach-O: its identifier and
+    /// whether it replaces an existing signature.
+    fn adhoc_signature(&self) -> Result<Option<(String, bool)>> {
+        if !self.resign {
+            return Ok(None);
+        }
+        Ok(match codesign::identifier(&self.data)? {
+            Some(identifier) => Some((identifier, true)),
+            None => self
+                .adhoc_identifier
+                .clone()
+                .map(|identifier| (identifier, false)),
+        })
+    }
+
+    /// Use `page_size` instead of the PT_LOAD alignment of the input for ELF
+    /// segment layout.
+    pub fn set_page_size(&mut self, page_size: Option<u64>) -> Result<()> {
+        if let Some(page_size) = page_size {
+            if !page_size.is_power_of_two() || page_size < MIN_PAGE_SIZE {
+                anyhow::bail!(
+                    "Invalid page size {page_size:#x}, expected a power of two of at least {MIN_PAGE_SIZE:#x}"
+                );
+            }
+        }
+        self.page_size = page_size;
+        Ok(())
+    }
+
+    /// The page size ELF segments are aligned to: the configured one, or the
+    /// largest PT_LOAD alignment of the input (16K on recent Android builds).
+    fn elf_page_size(&self) -> Result<u64> {
+        if let Some(page_size) = self.page_size {
+            return Ok(page_size);
+        }
+        Ok(object::read::File::parse(self.data.as_slice())?
+            .segments()
+            .map(|segment| segment.align())
+            .max()
+            .unwrap_or(0)
+            .max(MIN_PAGE_SIZE))
+    }
+
+    pub fn architecture(&self) -> Result<object::Architecture> {
+        Ok(object::read::File::parse(self.data.as_slice())?.architecture())
     }
 
+    pub fn export_names(&self) -> Result<Vec<String>> {
+        Ok(object::read::File::parse(self.data.as_slice())?
+            .exports()?
+            .iter()
+            .map(|export| String::from_utf8_lossy(export.name()).to_string())
+            .collect())
+    }
+
+    /// Find the empty `EmbeddedConfig` header left in the prebuilt.
     pub fn find_embedded_config(&self) -> Option<usize> {
-        let magic1_bytes = (0x0d000721i32).to_le_bytes();
-        let magic2_bytes = (0x1f8a4e2bi32).to_le_bytes();
+        self.find_embedded_config_header(false)
+    }
+
+    /// Find an `EmbeddedConfig` header that has already been filled in.
+    pub fn find_filled_embedded_config(&self) -> Option<usize> {
+        self.find_embedded_config_header(true)
+    }
+
+    fn find_embedded_config_header(&self, filled: bool) -> Option<usize> {
+        let magic1_bytes = EMBEDDED_CONFIG_MAGIC1.to_le_bytes();
+        let magic2_bytes = EMBEDDED_CONFIG_MAGIC2.to_le_bytes();
+        let field = |i: usize| i32::from_le_bytes(self.data[i..i + 4].try_into().unwrap());
+
+        // Prebuilts always carry an empty version 1 header with the fixed
+        // magic values; filled headers may use randomized ones.
+        let versions = if filled {
+            EMBEDDED_CONFIG_VERSION..=EMBEDDED_CONFIG_VERSION_CHECKSUM
+        } else {
+            EMBEDDED_CONFIG_VERSION..=EMBEDDED_CONFIG_VERSION
+        };
 
         (0..self
             .data
             .len()
             .saturating_sub(std::mem::size_of::<EmbeddedConfig>()))
             .find(|&i| {
-                self.data[i..i + 4] == magic1_bytes
-                    && self.data[i + 4..i + 8] == magic2_bytes
-                    && self.data[i + 8..i + 12] == (1i32).to_le_bytes()
-                    && self.data[i + 12..i + 16] == [0, 0, 0, 0]
-                    && self.data[i + 16..i + 20] == [0, 0, 0, 0]
+                let fixed_magic =
+                    self.data[i..i + 4] == magic1_bytes && self.data[i + 4..i + 8] == magic2_bytes;
+                let randomized_magic = filled && field(i + 4) == randomized_magic2(field(i));
+                (fixed_magic || randomized_magic)
+                    && versions.contains(&field(i + 8))
+                    && (self.data[i + 12..i + 16] != [0, 0, 0, 0]) == filled
+                    && (self.data[i + 16..i + 20] != [0, 0, 0, 0]) == filled
             })
     }
 
-    pub fn add_needed_library(&mut self, lib_name: &str) -> Result<()> {
-        match self.format {
-            ObjectFormat::Elf => {
-                let data_cloned = self.data.clone();
-                let mut elf = object_rewrite::Rewriter::read(&data_cloned)?;
-                elf.elf_add_needed(vec![lib_name.as_bytes().to_vec()].as_ref())?;
-                self.data = vec![];
-                elf.write(&mut self.data)?;
-
-                // Fix .dynamic section size
-                let data_cloned = self.data.clone();
-                let mut elf = object::build::elf::Builder::read(data_cloned.as_slice())?;
-                elf.delete_orphan_symbols();
-                elf.delete_unused_versions();
-                elf.set_section_sizes();
-                if let Some(dynamic_segment) =
-                    elf.segments.iter_mut().find(|seg| seg.p_type == PT_DYNAMIC)
-                {
-                    let dynamic_section = elf
-                        .sections
-                        .iter_mut()
-                        .find(|sec| sec.sh_type == object::elf::SHT_DYNAMIC)
-                        .context("Failed to find .dynamic section")?;
-                    let dynamic_data_size = dynamic_section.sh_size;
-                    dynamic_segment.p_filesz = dynamic_data_size;
-                    dynamic_segment.p_memsz = dynamic_data_size;
-                    dynamic_section.sh_size = dynamic_data_size;
-
-                    info!("Updated .dynamic section size to {}", dynamic_data_size);
-                }
+    /// Read back the payload of an already packed binary, following
+    /// `data_offset` through the segment/section mapping. `key` is the
+    /// passphrase or package name for payloads encrypted with a derived key.
+    pub fn read_embedded_payload(&self, key: Option<&str>) -> Result<(EmbeddedConfig, Vec<u8>)> {
+        let (_, config, payload_range) = self.locate_embedded_payload()?;
+        let mut data = &self.data[payload_range];
+        let checksums = if config.has_checksums() {
+            let checksums =
+                PayloadChecksums::from_bytes(data).context("Payload checksums are truncated")?;
+            data = &data[PayloadChecksums::SIZE..];
+            checksums.verify_stored(data)?;
+            Some(checksums)
+        } else {
+            None
+        };
+        let data = if config.is_encrypted() {
+            crypto::open(data, key)?
+        } else {
+            data.to_vec()
+        };
+        let data = compression::decompress(config.codec()?, &data)?;
+        if let Some(checksums) = checksums {
+            checksums.verify_content(&data)?;
+        }
+
+        Ok((config, data))
+    }
+
+    /// Returns the file offset of the filled header, the header itself and
+    /// the file range of the payload it points to.
+    fn locate_embedded_payload(&self) -> Result<(usize, EmbeddedConfig, std::ops::Range<usize>)> {
+        let config_offset = self
+            .find_filled_embedded_config()
+            .context("No embedded payload found")?;
+        let config = EmbeddedConfig::from_bytes(&self.data[config_offset..])
+            .context("Embedded config header is truncated")?;
+
+        let file = object::read::File::parse(self.data.as_slice())?;
+        let config_address = file
+            .segments()
+            .find_map(|segment| {
+                let (start, size) = segment.file_range();
+                (start <= config_offset as u64 && (config_offset as u64) < start + size)
+                    .then(|| segment.address() + (config_offset as u64 - start))
+            })
+            .context("Failed to find segment containing embedded config")?;
+
+        let data_address = config_address.wrapping_add_signed(config.data_offset as i64);
+        let data_size = config.data_size as u64;
+        let data_offset = file
+            .segments()
+            .find_map(|segment| {
+                let (start, size) = segment.file_range();
+                (segment.address() <= data_address
+                    && data_address + data_size <= segment.address() + size)
+                    .then(|| start + (data_address - segment.address()))
+            })
+            .context("Embedded payload is not mapped from the file")?;
+
+        let payload_range = data_offset as usize..(data_offset + data_size) as usize;
+        if payload_range.end > self.data.len() {
+            anyhow::bail!("Embedded payload is out of range");
+        }
 
-                self.data = vec![];
-                elf.write(&mut self.data)?;
+        Ok((config_offset, config, payload_range))
+    }
+
+    /// Queue `config_data` to be embedded. `compressed` is `config_data`
+    /// already compressed with `compression`; it is encrypted right away so
+    /// `plan` can report the final size. With `checksum`, the stored data is
+    /// prefixed with `PayloadChecksums`.
+    pub fn add_embedded_config_data(
+        &mut self,
+        config_data: &[u8],
+        compressed: Vec<u8>,
+        compression: Compression,
+        encryption: Option<&PayloadEncryption>,
+        checksum: bool,
+    ) -> Result<()> {
+        let data = match encryption {
+            Some(encryption) => {
+                info!(
+                    "Encrypting payload (key source: {:?})",
+                    encryption.key_source()
+                );
+                encryption.seal(&compressed)?
             }
+            None => compressed,
+        };
+        let data = if checksum {
+            let mut stored = PayloadChecksums::new(config_data, &data).as_bytes();
+            stored.extend_from_slice(&data);
+            stored
+        } else {
+            data
+        };
+
+        let embedded_config = EmbeddedConfig::new(
+            data.len() as i32,
+            0,
+            compression.codec,
+            encryption.is_some(),
+            checksum,
+        );
+        self.transforms.payload = Some((data, embedded_config));
+        Ok(())
+    }
+
+    /// Give the queued payload header per-build magic values instead of the
+    /// fixed ones from the prebuilt.
+    pub fn randomize_magic(&mut self, rng: &mut impl Rng) -> Result<()> {
+        let (_, embedded_config) = self
+            .transforms
+            .payload
+            .as_mut()
+            .context("No payload queued to randomize the magic values of")?;
+        let magic1 = loop {
+            let magic1: i32 = rng.gen();
+            if magic1 != EMBEDDED_CONFIG_MAGIC1 {
+                break magic1;
+            }
+        };
+        embedded_config.magic1 = magic1;
+        embedded_config.magic2 = randomized_magic2(magic1);
+        Ok(())
+    }
+
+    /// Queue loading `lib_name` on startup.
+    ///
+    /// PE imports need at least one symbol, so `import_symbol` names an
+    /// export of `lib_name` to import; ordinal 1 is used when it is `None`.
+    /// For Mach-O, `lib_name` is the install name of an `LC_LOAD_DYLIB`
+    /// command, e.g. `@loader_path/libfoo.dylib`.
+    pub fn add_needed_library(
+        &mut self,
+        lib_name: &str,
+        import_symbol: Option<&str>,
+    ) -> Result<()> {
+        match self.format {
+            ObjectFormat::Elf => {}
             ObjectFormat::Pe => {
-                anyhow::bail!("Adding needed library is not supported for PE format");
+                if !self.transforms.needed.is_empty() {
+                    anyhow::bail!("Only one library can be added to a PE import directory");
+                }
             }
+            ObjectFormat::MachO => self.check_new_dylib(lib_name)?,
         }
+
+        self.transforms
+            .needed
+            .push((lib_name.to_string(), import_symbol.map(str::to_string)));
         Ok(())
     }
 
-    pub fn add_embedded_config_data(&mut self, config_data: &[u8], use_xz: bool) -> Result<()> {
-        let data = if use_xz {
-            self.compress_xz(config_data)?
-        } else {
-            config_data.to_vec()
-        };
-        let mut embedded_config = EmbeddedConfig::new(data.len() as i32, 0, use_xz);
+    /// Queue an `LC_LOAD_WEAK_DYLIB` command: dyld loads `lib_name` on
+    /// startup but keeps going if it is missing. Mach-O only.
+    pub fn add_weak_needed_library(&mut self, lib_name: &str) -> Result<()> {
+        if !matches!(self.format, ObjectFormat::MachO) {
+            anyhow::bail!("Weak libraries are only supported for Mach-O format");
+        }
+        self.check_new_dylib(lib_name)?;
+        self.transforms.weak_needed.push(lib_name.to_string());
+        Ok(())
+    }
+
+    fn check_new_dylib(&self, lib_name: &str) -> Result<()> {
+        if macho::dylibs(&self.data)?
+            .iter()
+            .any(|dylib| dylib == lib_name)
+        {
+            anyhow::bail!("Binary already loads {lib_name}");
+        }
+        Ok(())
+    }
+
+    /// Queue the anti-detection keyword rewrite. Random replacements are
+    /// picked here so that `plan` and `finish` report the same ones.
+    pub fn anti_anti_frida(&mut self, options: &AntiFridaOptions) {
+        let mut options = options.clone();
+        for rule in &mut options.keywords {
+            rule.replacement
+                .get_or_insert_with(|| Self::generate_random_string(rule.keyword.len()));
+        }
+        self.transforms.strings = Some(options);
+    }
+
+    /// Queue writing `version_info` as the `VS_VERSIONINFO` resource,
+    /// replacing any existing one.
+    pub fn set_version_info(&mut self, version_info: VersionInfo) -> Result<()> {
+        if !matches!(self.format, ObjectFormat::Pe) {
+            anyhow::bail!("Version resources are only supported for PE files");
+        }
+        // Fail on a bad version string now rather than in `finish`.
+        version_info.to_resource()?;
+        self.transforms.version_info = Some(version_info);
+        Ok(())
+    }
+
+    /// Dry run of `finish`: describe the queued changes without touching
+    /// the binary.
+    pub fn plan(&self) -> Result<PipelineReport> {
+        let mut report = PipelineReport::default();
+
+        let old_payload = self.old_payload()?;
+        if let Some((_, payload_range)) = &old_payload {
+            report.changes.push(format!(
+                "Replace the existing {}-byte payload",
+                payload_range.len()
+            ));
+        }
+        if let Some(change) = self.transforms.magic_change() {
+            report.changes.push(change);
+        }
+        if let Some(options) = &self.transforms.strings {
+            let targets = self.keyword_targets(options)?;
+            report.keywords = self.find_keywords(options, &targets).0;
+        }
 
         match self.format {
             ObjectFormat::Elf => {
-                let data_cloned = self.data.clone();
-                let elf = object::build::elf::Builder::read(data_cloned.as_slice())?;
-                self.data = vec![];
-                // re-write ELF to ensure segments are properly aligned
-                elf.write(&mut self.data)?;
-                let data_cloned = self.data.clone();
-                let mut elf = object::build::elf::Builder::read(data_cloned.as_slice())?;
-                let vaddr_spare_area = elf
-                    .segments
-                    .iter()
-                    .map(|seg| seg.p_vaddr + seg.p_memsz)
-                    .max()
-                    .unwrap_or(0);
-
-                let vaddr_spare_area = (vaddr_spare_area + 0xfff) & !0xfff;
-                info!("vaddr_spare_area: {vaddr_spare_area:#x}");
-
-                let mut offset_spare_area = self.data.len() as u64;
-
-                let fripack_section_id = {
-                    let new_segment = elf.segments.add_load_segment(PF_R | PF_W, 4096);
-                    let new_section = elf.sections.add();
-
-                    offset_spare_area = (offset_spare_area + 0xfff) & !0xfff;
-
-                    new_section.sh_size = data.len() as u64;
-                    new_section.data = object::build::elf::SectionData::Data(data.into());
-                    new_section.sh_flags = (object::elf::SHF_ALLOC | object::elf::SHF_WRITE) as u64;
-                    new_section.sh_type = object::elf::SHT_PROGBITS;
-                    new_section.sh_addralign = 4096;
-                    new_section.sh_offset = offset_spare_area;
-                    new_section.sh_addr = vaddr_spare_area;
-                    new_segment.p_vaddr = vaddr_spare_area;
-                    new_segment.append_section(new_section);
-                    new_section.sh_addr = vaddr_spare_area;
-                    new_segment.p_vaddr = vaddr_spare_area;
-                    offset_spare_area += new_section.sh_size;
-                    offset_spare_area = (offset_spare_area + 0xfff) & !0xfff;
-
-                    new_section.id()
-                };
-
-                let header_size = elf.file_header_size() as u64 + elf.program_headers_size() as u64;
-                // move sections overlapped with the header to the end of file
-                for section in elf.sections.iter_mut() {
-                    if section.sh_offset < header_size {
-                        info!("Moving section {}", section.name);
-                        section.sh_offset = offset_spare_area;
-                        offset_spare_area = (section.sh_offset + section.sh_size + 0xfff) & !0xfff;
+                for (lib_name, _) in &self.transforms.needed {
+                    report.changes.push(format!("Add DT_NEEDED {lib_name}"));
+                }
+                if !self.transforms.needed.is_empty() {
+                    report.changes.push(
+                        "Rewrite the ELF in two passes: DT_NEEDED entries first, then the remaining changes"
+                            .to_string(),
+                    );
+                }
+                if !self.transforms.needed.is_empty() || self.transforms.payload.is_some() {
+                    // Lay out on a scratch copy that went through the same
+                    // DT_NEEDED pass as in `elf_rewrite`, so the offsets match
+                    let data = if self.transforms.needed.is_empty() {
+                        std::borrow::Cow::Borrowed(self.data.as_slice())
+                    } else {
+                        std::borrow::Cow::Owned(Self::elf_add_needed(
+                            &self.data,
+                            &self.transforms.needed,
+                        )?)
+                    };
+                    let mut elf = object::build::elf::Builder::read(&*data)?;
+                    if let Some((_, payload_range)) = old_payload {
+                        Self::elf_delete_payload(&mut elf, payload_range)?;
                     }
+                    let page_size = self.elf_page_size()?;
+                    if !self.transforms.needed.is_empty() {
+                        Self::elf_fix_needed(&mut elf, page_size, &mut report)?;
+                    }
+                    if let Some((payload, _)) = &self.transforms.payload {
+                        Self::elf_add_payload(&mut elf, payload, page_size, &mut report)?;
+                    }
+                }
+            }
+            ObjectFormat::Pe => {
+                for (lib_name, _) in &self.transforms.needed {
+                    report.changes.push(format!(
+                        "Add import of {lib_name} in a new .fripimp section"
+                    ));
+                }
+                if self.transforms.version_info.is_some() {
+                    report
+                        .changes
+                        .push("Write the version resource into a new .rsrc section".to_string());
+                }
+                if let Some((payload, _)) = &self.transforms.payload {
+                    report.changes.push(format!(
+                        "Add .fripac section holding the {}-byte payload",
+                        payload.len()
+                    ));
+                }
+                report.changes.extend(self.certificate_table_change()?);
+            }
+            ObjectFormat::MachO => {
+                for (lib_name, _) in &self.transforms.needed {
+                    report.changes.push(format!("Add LC_LOAD_DYLIB {lib_name}"));
+                }
+                for lib_name in &self.transforms.weak_needed {
+                    report
+                        .changes
+                        .push(format!("Add LC_LOAD_WEAK_DYLIB {lib_name}"));
                 }
+                if let Some((payload, _)) = &self.transforms.payload {
+                    report.changes.push(format!(
+                        "Store the {}-byte payload in the __FRIPACK segment",
+                        payload.len()
+                    ));
+                }
+                if let Some((identifier, replaced)) = self.adhoc_signature()? {
+                    report
+                        .changes
+                        .push(adhoc_signature_change(&identifier, replaced));
+                }
+            }
+        }
 
-                let size = elf.program_headers_size() as u64;
+        Ok(report)
+    }
 
-                let size_diff = if let Some(phdr_segment) =
-                    elf.segments.iter_mut().find(|seg| seg.p_type == PT_PHDR)
-                {
-                    let size_diff = size - phdr_segment.p_filesz;
-                    phdr_segment.p_filesz = size;
-                    phdr_segment.p_memsz = size;
-                    size_diff
-                } else {
-                    size
-                };
+    /// Apply the queued changes: keyword rewrites in place, a single
+    /// structural rewrite of the binary, then the payload header.
+    pub fn finish(mut self) -> Result<(Vec<u8>, PipelineReport)> {
+        let mut report = PipelineReport::default();
+
+        let old_payload = self.old_payload()?;
+        if let Some((config_offset, payload_range)) = &old_payload {
+            info!("→ Binary already carries a payload, replacing it");
+            report.changes.push(format!(
+                "Replace the existing {}-byte payload",
+                payload_range.len()
+            ));
+            let empty_config = EmbeddedConfig::default().as_bytes();
+            self.data[*config_offset..*config_offset + empty_config.len()]
+                .copy_from_slice(&empty_config);
+        }
+        if let Some(change) = self.transforms.magic_change() {
+            report.changes.push(change);
+        }
 
-                let header_load_segment = elf
-                    .segments
-                    .iter_mut()
-                    .find(|seg| seg.p_type == PT_LOAD && seg.p_offset == 0)
-                    .context("Failed to find PT_LOAD segment covering header (p_offset == 0)")?;
-
-                header_load_segment.p_filesz += size_diff;
-                header_load_segment.p_memsz += size_diff;
-
-                self.data = vec![];
-                elf.write(&mut self.data)?;
-                let data_cloned = self.data.clone();
-                let elf = object::build::elf::Builder::read(data_cloned.as_slice())?;
-                // update embedded config offset
-                let embedded_config_offset = self
-                    .find_embedded_config()
-                    .context("Failed to find embedded config after adding data")?;
-
-                let data_section_segment = elf
-                    .segments
-                    .iter()
-                    .find(|seg| {
-                        seg.p_offset <= embedded_config_offset as u64
-                            && (embedded_config_offset as u64) < seg.p_offset + seg.p_filesz
-                    })
-                    .context("Failed to find data segment")?;
-
-                let fripack_section_segment = elf
-                    .segments
-                    .iter()
-                    .find(|seg| seg.sections.contains(&fripack_section_id))
-                    .context("Failed to find fripack_config segment")?;
-
-                embedded_config.data_offset = (fripack_section_segment.p_offset as i32
-                    - embedded_config_offset as i32)
-                    - (fripack_section_segment.p_offset as i32
-                        - data_section_segment.p_offset as i32)
-                    + (fripack_section_segment.p_vaddr as i32
-                        - data_section_segment.p_vaddr as i32);
-                let embedded_config_bytes = embedded_config.as_bytes();
-                self.data
-                    [embedded_config_offset..embedded_config_offset + embedded_config_bytes.len()]
-                    .copy_from_slice(&embedded_config_bytes);
+        let transforms = std::mem::take(&mut self.transforms);
+        if let Some(options) = &transforms.strings {
+            let targets = self.keyword_targets(options)?;
+            let (reports, edits) = self.find_keywords(options, &targets);
+            for (offset, rule) in edits {
+                let replacement = reports[rule].replacement.as_bytes();
+                self.data[offset..offset + replacement.len()].copy_from_slice(replacement);
             }
-            ObjectFormat::Pe => {
-                // Parse the PE file
-                let kind = object::FileKind::parse(self.data.as_slice())?;
-                let out_data = match kind {
+
+            let replacements: usize = reports.iter().map(|r| r.occurrences.len()).sum();
+            info!("Replaced {} occurrences of keywords", replacements);
+            report.keywords = reports;
+
+            if let Some((names, ordinals)) = targets.export_names {
+                match object::FileKind::parse(self.data.as_slice())? {
                     object::FileKind::Pe32 => {
-                        self.copy_pe_file::<pe::ImageNtHeaders32>(&data, &embedded_config)?
+                        self.sort_pe_export_names::<pe::ImageNtHeaders32>(names, ordinals)?
                     }
                     object::FileKind::Pe64 => {
-                        self.copy_pe_file::<pe::ImageNtHeaders64>(&data, &embedded_config)?
+                        self.sort_pe_export_names::<pe::ImageNtHeaders64>(names, ordinals)?
                     }
                     _ => anyhow::bail!("Not a PE file"),
-                };
-                self.data = out_data;
+                }
+            }
+        }
+
+        let payload_offset = match self.format {
+            ObjectFormat::Elf => self.elf_rewrite(
+                &transforms,
+                old_payload.map(|(_, payload_range)| payload_range),
+                &mut report,
+            )?,
+            ObjectFormat::Pe => {
+                report.changes.extend(self.strip_pe_certificates()?);
+                self.pe_rewrite(&transforms, &mut report)?
+            }
+            ObjectFormat::MachO => self.macho_rewrite(&transforms, &mut report)?,
+        };
+
+        if let (Some((_, embedded_config)), Some(payload_offset)) =
+            (transforms.payload, payload_offset)
+        {
+            self.write_payload_offset(embedded_config, payload_offset)?;
+        }
+        match self.format {
+            ObjectFormat::Pe => update_pe_checksum(&mut self.data)?,
+            ObjectFormat::MachO => {
+                // Every change above invalidates the page hashes, and arm64
+                // macOS refuses to load code with a broken signature.
+                if let Some((identifier, replaced)) = self.adhoc_signature()? {
+                    codesign::adhoc_sign(&mut self.data, &identifier)?;
+                    report
+                        .changes
+                        .push(adhoc_signature_change(&identifier, replaced));
+                }
+            }
+            ObjectFormat::Elf => {}
+        }
+
+        validate::validate(&self.data)?;
+        Ok((self.data, report))
+    }
+
+    /// The filled header and payload range of a binary that is being
+    /// repacked, i.e. one with a payload queued but no empty header left.
+    fn old_payload(&self) -> Result<Option<(usize, std::ops::Range<usize>)>> {
+        if self.transforms.payload.is_none()
+            || self.find_embedded_config().is_some()
+            || self.find_filled_embedded_config().is_none()
+        {
+            return Ok(None);
+        }
+
+        let (config_offset, _, payload_range) = self.locate_embedded_payload()?;
+        Ok(Some((config_offset, payload_range)))
+    }
+
+    /// Apply the ELF changes with a single `Builder` pass, preceded by an
+    /// object-rewrite pass when DT_NEEDED entries are added. Returns the
+    /// file offset of the new payload, if any.
+    fn elf_rewrite(
+        &mut self,
+        transforms: &Transforms,
+        old_payload: Option<std::ops::Range<usize>>,
+        report: &mut PipelineReport,
+    ) -> Result<Option<u64>> {
+        let page_size = self.elf_page_size()?;
+
+        // object-rewrite moves .dynamic and .dynstr when they outgrow their
+        // place, so DT_NEEDED entries are added ahead of the main pass.
+        if !transforms.needed.is_empty() {
+            self.data = Self::elf_add_needed(&self.data, &transforms.needed)?;
+            for (lib_name, _) in &transforms.needed {
+                report.changes.push(format!("Add DT_NEEDED {lib_name}"));
+            }
+            report.changes.push(
+                "Rewrite the ELF in two passes: DT_NEEDED entries first, then the remaining changes"
+                    .to_string(),
+            );
+        }
+
+        let mut out_data = Vec::new();
+        let mut elf = object::build::elf::Builder::read(self.data.as_slice())?;
+        if let Some(payload_range) = old_payload {
+            Self::elf_delete_payload(&mut elf, payload_range)?;
+        }
+        if !transforms.needed.is_empty() {
+            Self::elf_fix_needed(&mut elf, page_size, report)?;
+        }
+        if transforms.strings.is_some() {
+            // Symbol names changed, the hash tables are rebuilt from them
+            elf.delete_orphan_dynamics();
+            elf.delete_orphan_symbols();
+            elf.set_section_sizes();
+        }
+        let payload_offset = match &transforms.payload {
+            Some((payload, _)) => {
+                Some(Self::elf_add_payload(&mut elf, payload, page_size, report)?)
+            }
+            None => None,
+        };
+        elf.write(&mut out_data)?;
+
+        self.data = out_data;
+        Ok(payload_offset)
+    }
+
+    /// Add DT_NEEDED entries with object-rewrite, which makes room for them
+    /// by moving .dynamic and .dynstr.
+    fn elf_add_needed(data: &[u8], needed: &[(String, Option<String>)]) -> Result<Vec<u8>> {
+        let names: Vec<Vec<u8>> = needed
+            .iter()
+            .map(|(lib_name, _)| lib_name.as_bytes().to_vec())
+            .collect();
+        let mut rewriter = object_rewrite::Rewriter::read(data)?;
+        rewriter.elf_add_needed(&names)?;
+        let mut out_data = Vec::new();
+        rewriter.write(&mut out_data)?;
+        Ok(out_data)
+    }
+
+    /// Drop the section holding the payload of a previous run, and its
+    /// segment if that maps nothing else.
+    fn elf_delete_payload(
+        elf: &mut object::build::elf::Builder<'_>,
+        payload_range: std::ops::Range<usize>,
+    ) -> Result<()> {
+        let section = elf
+            .sections
+            .iter_mut()
+            .find(|sec| {
+                sec.sh_offset == payload_range.start as u64
+                    && sec.sh_type == object::elf::SHT_PROGBITS
+            })
+            .context("Failed to find section holding the old payload")?;
+        section.delete = true;
+        let section_id = section.id();
+
+        if let Some(segment) = elf
+            .segments
+            .iter_mut()
+            .find(|seg| seg.sections == [section_id])
+        {
+            segment.delete = true;
+        }
+
+        Ok(())
+    }
+
+    /// Fix up the output of object-rewrite: the .dynamic size and the
+    /// alignment of segments it added.
+    fn elf_fix_needed(
+        elf: &mut object::build::elf::Builder<'_>,
+        page_size: u64,
+        report: &mut PipelineReport,
+    ) -> Result<()> {
+        elf.delete_orphan_symbols();
+        elf.delete_unused_versions();
+        elf.set_section_sizes();
+        if let Some(dynamic_segment) = elf.segments.iter_mut().find(|seg| seg.p_type == PT_DYNAMIC)
+        {
+            let dynamic_section = elf
+                .sections
+                .iter_mut()
+                .find(|sec| sec.sh_type == object::elf::SHT_DYNAMIC)
+                .context("Failed to find .dynamic section")?;
+            let dynamic_data_size = dynamic_section.sh_size;
+            dynamic_segment.p_filesz = dynamic_data_size;
+            dynamic_segment.p_memsz = dynamic_data_size;
+            dynamic_section.sh_size = dynamic_data_size;
+
+            report
+                .changes
+                .push(format!("Resize .dynamic to {dynamic_data_size} bytes"));
+        }
+
+        // Segments added by the rewriter must still load on `page_size` pages.
+        for segment in elf.segments.iter_mut().filter(|seg| seg.p_type == PT_LOAD) {
+            if segment.p_align >= page_size {
+                continue;
+            }
+            if segment.p_offset % page_size != segment.p_vaddr % page_size {
+                anyhow::bail!(
+                    "PT_LOAD at {:#x} added while injecting a library cannot be aligned to {page_size:#x} pages",
+                    segment.p_vaddr
+                );
             }
+            segment.p_align = page_size;
         }
 
         Ok(())
     }
+
+    /// Map `payload` with a new PT_LOAD segment past the end of the image.
+    /// Returns the file offset of the payload.
+    fn elf_add_payload<'data>(
+        elf: &mut object::build::elf::Builder<'data>,
+        payload: &'data [u8],
+        page_size: u64,
+        report: &mut PipelineReport,
+    ) -> Result<u64> {
+        let page_mask = page_size - 1;
+
+        let vaddr_spare_area = elf
+            .segments
+            .iter()
+            .map(|seg| seg.p_vaddr + seg.p_memsz)
+            .max()
+            .unwrap_or(0);
+        let vaddr_spare_area = (vaddr_spare_area + page_mask) & !page_mask;
+
+        // Non-alloc sections are placed after the alloc ones when writing,
+        // so only the mapped part of the file counts here.
+        let offset_spare_area = elf
+            .segments
+            .iter()
+            .map(|seg| seg.p_offset + seg.p_filesz)
+            .chain(
+                elf.sections
+                    .iter()
+                    .filter(|sec| sec.is_alloc() && sec.sh_type != object::elf::SHT_NOBITS)
+                    .map(|sec| sec.sh_offset + sec.sh_size),
+            )
+            .max()
+            .unwrap_or(0);
+        let payload_offset = (offset_spare_area + page_mask) & !page_mask;
+
+        {
+            let new_segment = elf.segments.add_load_segment(PF_R | PF_W, page_size);
+            let new_section = elf.sections.add();
+
+            new_section.sh_size = payload.len() as u64;
+            new_section.data = object::build::elf::SectionData::Data(payload.into());
+            new_section.sh_flags = (object::elf::SHF_ALLOC | object::elf::SHF_WRITE) as u64;
+            new_section.sh_type = object::elf::SHT_PROGBITS;
+            new_section.sh_addralign = page_size;
+            new_segment.p_offset = payload_offset;
+            new_segment.p_vaddr = vaddr_spare_area;
+            new_segment.p_paddr = vaddr_spare_area;
+            new_segment.append_section(new_section);
+        }
+        report.changes.push(format!(
+            "Add PT_LOAD segment at offset {payload_offset:#x}, vaddr {vaddr_spare_area:#x} holding the {}-byte payload ({page_size:#x} pages)",
+            payload.len()
+        ));
+
+        let mut offset_spare_area =
+            (payload_offset + payload.len() as u64 + page_mask) & !page_mask;
+        let header_size = elf.file_header_size() as u64 + elf.program_headers_size() as u64;
+        // move sections overlapped with the header to the end of file
+        for section in elf.sections.iter_mut() {
+            if section.sh_offset < header_size {
+                report.changes.push(format!(
+                    "Move section {} out of the program headers to {offset_spare_area:#x}",
+                    section.name
+                ));
+                section.sh_offset = offset_spare_area;
+                offset_spare_area = (section.sh_offset + section.sh_size + page_mask) & !page_mask;
+            }
+        }
+
+        let size = elf.program_headers_size() as u64;
+
+        let size_diff =
+            if let Some(phdr_segment) = elf.segments.iter_mut().find(|seg| seg.p_type == PT_PHDR) {
+                let size_diff = size - phdr_segment.p_filesz;
+                phdr_segment.p_filesz = size;
+                phdr_segment.p_memsz = size;
+                size_diff
+            } else {
+                size
+            };
+
+        let header_load_segment = elf
+            .segments
+            .iter_mut()
+            .find(|seg| seg.p_type == PT_LOAD && seg.p_offset == 0)
+            .context("Failed to find PT_LOAD segment covering header (p_offset == 0)")?;
+
+        header_load_segment.p_filesz += size_diff;
+        header_load_segment.p_memsz += size_diff;
+
+        Ok(payload_offset)
+    }
+
+    /// Add the PE sections for the queued import and payload with a single
+    /// copy of the image. Returns the file offset of the payload, if any.
+    fn pe_rewrite(
+        &mut self,
+        transforms: &Transforms,
+        report: &mut PipelineReport,
+    ) -> Result<Option<u64>> {
+        let mut new_sections = Vec::new();
+        for (lib_name, import_symbol) in &transforms.needed {
+            let new_section = match object::FileKind::parse(self.data.as_slice())? {
+                object::FileKind::Pe32 => self.pe_import_section::<pe::ImageNtHeaders32>(
+                    lib_name,
+                    import_symbol.as_deref(),
+                )?,
+                object::FileKind::Pe64 => self.pe_import_section::<pe::ImageNtHeaders64>(
+                    lib_name,
+                    import_symbol.as_deref(),
+                )?,
+                _ => anyhow::bail!("Not a PE file"),
+            };
+            new_sections.push(new_section);
+        }
+        if let Some(version_info) = &transforms.version_info {
+            let new_section = match object::FileKind::parse(self.data.as_slice())? {
+                object::FileKind::Pe32 => {
+                    self.pe_resource_section::<pe::ImageNtHeaders32>(version_info)?
+                }
+                object::FileKind::Pe64 => {
+                    self.pe_resource_section::<pe::ImageNtHeaders64>(version_info)?
+                }
+                _ => anyhow::bail!("Not a PE file"),
+            };
+            new_sections.push(new_section);
+        }
+        // The payload section goes last so that a later repack can replace it.
+        if let Some((payload, _)) = &transforms.payload {
+            let payload = payload.clone();
+            new_sections.push(PeNewSection {
+                name: *b".fripac\0",
+                characteristics: pe::IMAGE_SCN_CNT_INITIALIZED_DATA
+                    | pe::IMAGE_SCN_MEM_READ
+                    | pe::IMAGE_SCN_MEM_WRITE,
+                size: payload.len() as u32,
+                contents: Box::new(move |_| payload),
+                data_directories: vec![],
+                replaces_existing: true,
+            });
+        }
+        if new_sections.is_empty() {
+            return Ok(None);
+        }
+
+        let (out_data, ranges) = self.copy_pe(new_sections)?;
+        self.data = out_data;
+
+        for ((lib_name, _), range) in transforms.needed.iter().zip(&ranges) {
+            report.changes.push(format!(
+                "Add import of {lib_name} in a new .fripimp section at RVA {:#x}",
+                range.virtual_address
+            ));
+        }
+        if transforms.version_info.is_some() {
+            let range = &ranges[transforms.needed.len()];
+            report.changes.push(format!(
+                "Write the version resource into a new .rsrc section at RVA {:#x}",
+                range.virtual_address
+            ));
+        }
+        let payload_range = match &transforms.payload {
+            Some((payload, _)) => {
+                let range = ranges.last().unwrap();
+                report.changes.push(format!(
+                    "Add .fripac section at RVA {:#x} holding the {}-byte payload",
+                    range.virtual_address,
+                    payload.len()
+                ));
+                Some(range.file_offset as u64)
+            }
+            None => None,
+        };
+
+        Ok(payload_range)
+    }
+
+    /// Add the queued dylib load commands and store the queued payload in
+    /// the `__FRIPACK` segment, reusing the one of a previous run. Returns
+    /// the file offset of the payload, if any.
+    fn macho_rewrite(
+        &mut self,
+        transforms: &Transforms,
+        report: &mut PipelineReport,
+    ) -> Result<Option<u64>> {
+        // Load commands go first, while the header padding is untouched.
+        let dylibs = transforms
+            .needed
+            .iter()
+            .map(|(lib_name, _)| (lib_name, false))
+            .chain(
+                transforms
+                    .weak_needed
+                    .iter()
+                    .map(|lib_name| (lib_name, true)),
+            );
+        for (lib_name, weak) in dylibs {
+            macho::add_dylib(&mut self.data, lib_name, weak)?;
+            let command = if weak {
+                "LC_LOAD_WEAK_DYLIB"
+            } else {
+                "LC_LOAD_DYLIB"
+            };
+            report.changes.push(format!("Add {command} {lib_name}"));
+        }
+
+        let Some((payload, _)) = &transforms.payload else {
+            return Ok(None);
+        };
+
+        let fripack_segment = if macho::segments(&self.data)?
+            .iter()
+            .any(|seg| seg.name == "__FRIPACK")
+        {
+            macho::set_segment_payload(&mut self.data, "__FRIPACK", payload)?
+        } else {
+            macho::add_segment(&mut self.data, "__FRIPACK", "__config", payload)?
+        };
+        report.changes.push(format!(
+            "Store the {}-byte payload in the __FRIPACK segment at offset {:#x}",
+            payload.len(),
+            fripack_segment.fileoff
+        ));
+
+        Ok(Some(fripack_segment.fileoff))
+    }
+
+    /// Fill in the empty header now that the payload has its final place.
+    /// `data_offset` is the distance between the header and the payload in
+    /// memory.
+    fn write_payload_offset(
+        &mut self,
+        mut embedded_config: EmbeddedConfig,
+        payload_offset: u64,
+    ) -> Result<()> {
+        let embedded_config_offset = self
+            .find_embedded_config()
+            .context("Failed to find embedded config after adding data")?;
+
+        let file = object::read::File::parse(self.data.as_slice())?;
+        let address_of = |offset: u64| {
+            file.segments().find_map(|segment| {
+                let (start, size) = segment.file_range();
+                (start <= offset && offset < start + size)
+                    .then(|| segment.address() + (offset - start))
+            })
+        };
+        let config_address = address_of(embedded_config_offset as u64)
+            .context("Failed to find segment containing embedded config")?;
+        let payload_address =
+            address_of(payload_offset).context("Failed to find segment containing the payload")?;
+
+        embedded_config.data_offset = (payload_address as i64 - config_address as i64) as i32;
+        let embedded_config_bytes = embedded_config.as_bytes();
+        self.data[embedded_config_offset..embedded_config_offset + embedded_config_bytes.len()]
+            .copy_from_slice(&embedded_config_bytes);
+
+        Ok(())
+    }
+
     fn generate_random_string(len: usize) -> String {
         rand::thread_rng()
             .sample_iter(&rand::distributions::Alphanumeric)
@@ -285,97 +1290,416 @@ impl BinaryProcessor {
             .collect()
     }
 
-    pub fn anti_anti_frida(&mut self) -> Result<()> {
-        if let ObjectFormat::Elf = self.format {
-            let cloned_data = self.data.clone();
-            let obj = object::build::elf::Builder::read(cloned_data.as_slice())?;
-            let rodata_section_range = {
-                let rodata_section = obj
-                    .sections
-                    .iter()
-                    .find(|sec| sec.name == ".rodata".into())
-                    .context("Failed to find .rodata section")?;
-                (rodata_section.sh_offset as usize)
-                    ..(rodata_section.sh_offset as usize + rodata_section.sh_size as usize)
-            };
+    /// Where the keyword rewrite may touch the binary.
+    fn keyword_targets(&self, options: &AntiFridaOptions) -> Result<KeywordTargets> {
+        match self.format {
+            ObjectFormat::Elf => self.elf_keyword_targets(options),
+            ObjectFormat::Pe => match object::FileKind::parse(self.data.as_slice())? {
+                object::FileKind::Pe32 => self.pe_keyword_targets::<pe::ImageNtHeaders32>(options),
+                object::FileKind::Pe64 => self.pe_keyword_targets::<pe::ImageNtHeaders64>(options),
+                _ => anyhow::bail!("Not a PE file"),
+            },
+            ObjectFormat::MachO => Ok(KeywordTargets::default()),
+        }
+    }
 
-            let dynstr_section_range = {
-                let dynstr_section = obj
-                    .sections
-                    .iter()
-                    .find(|sec| sec.name == ".dynstr".into())
-                    .context("Failed to find .dynstr section")?;
-                (dynstr_section.sh_offset as usize)
-                    ..(dynstr_section.sh_offset as usize + dynstr_section.sh_size as usize)
-            };
+    fn elf_keyword_targets(&self, options: &AntiFridaOptions) -> Result<KeywordTargets> {
+        let file = object::read::File::parse(self.data.as_slice())?;
+        let section_range = |name: &str| {
+            file.section_by_name(name)
+                .and_then(|section| section.file_range())
+                .map(|(offset, size)| offset as usize..(offset + size) as usize)
+        };
+
+        let mut targets = KeywordTargets::default();
+        for name in [".rodata", ".dynstr"] {
+            let range =
+                section_range(name).with_context(|| format!("Failed to find {name} section"))?;
+            targets.sections.push((name.to_string(), range));
+        }
+        for name in &options.extra_sections {
+            if targets
+                .sections
+                .iter()
+                .any(|(existing, _)| existing == name)
+            {
+                continue;
+            }
+            match section_range(name) {
+                Some(range) => targets.sections.push((name.clone(), range)),
+                None => warn!("Section {name} not found, skipping keyword scan"),
+            }
+        }
+
+        Ok(targets)
+    }
 
-            let mut replacements = 0;
-
-            let kwd = |s: &'static str| (s.as_bytes(), Self::generate_random_string(s.len()));
-
-            // Define keywords to replace
-            let keywords = [
-                kwd("frida"),
-                (b"GMainLoop", "pool-6-th".to_string()),
-                (b"gum-js-loop", "pool-6-thre".to_string()),
-                (b"gmain", "Timer".to_string()),
-                kwd("gum-js"),
-                kwd("gum"),
-                kwd("gdbus"),
-                kwd("Gum"),
-                kwd("Frida"),
-                kwd("GUM"),
-                kwd("GDBus"),
-                kwd("g_dbus"),
-                kwd("g_main"),
-                kwd("GMain"),
-                kwd("solist"),
-                kwd("GLib-GIO"),
-                kwd("GLib"),
-                kwd("agent"),
-                kwd("_Worker")
-            ];
-
-            for (keyword_bytes, replacement_str) in &keywords {
-                let replace_bytes = replacement_str.as_bytes();
-
-                // Use a sliding window approach with memchr for faster searching
-                let mut pos = 0;
-                while let Some(offset) = memchr::memmem::find(&self.data[pos..], keyword_bytes) {
-                    if !rodata_section_range.contains(&(pos + offset))
-                        && !dynstr_section_range.contains(&(pos + offset))
-                    {
-                        pos += offset + keyword_bytes.len();
+    fn pe_keyword_targets<Pe: ImageNtHeaders>(
+        &self,
+        options: &AntiFridaOptions,
+    ) -> Result<KeywordTargets> {
+        let file = object::read::pe::PeFile::<Pe>::parse(self.data.as_slice())?;
+        let section_table = file.section_table();
+        let file_range = |rva: u32, len: usize| {
+            section_table
+                .pe_file_range_at(rva)
+                .filter(|&(_, size)| size as usize >= len)
+                .map(|(offset, _)| offset as usize..offset as usize + len)
+        };
+
+        let mut sections = Vec::new();
+        let section_names =
+            std::iter::once(".rdata").chain(options.extra_sections.iter().map(String::as_str));
+        for name in section_names {
+            if sections.iter().any(|(existing, _)| existing == name) {
+                continue;
+            }
+            match file
+                .section_by_name(name)
+                .and_then(|section| section.file_range())
+            {
+                Some((offset, size)) => {
+                    sections.push((name.to_string(), offset as usize..(offset + size) as usize))
+                }
+                None => warn!("Section {name} not found, skipping keyword scan"),
+            }
+        }
+
+        // Export names may live in .edata, so they are scanned on their own.
+        let mut export_names = None;
+        if let Some(export_table) = file.export_table()? {
+            let directory = export_table.directory();
+            let name_rva = directory.name.get(LE);
+            if let Ok(name) = export_table.name_from_pointer(name_rva) {
+                if let Some(range) = file_range(name_rva, name.len()) {
+                    sections.push(("export dll name".to_string(), range));
+                }
+            }
+            for (pointer, _) in export_table.name_iter() {
+                let name = export_table.name_from_pointer(pointer)?;
+                if let Some(range) = file_range(pointer, name.len()) {
+                    sections.push((format!("export {}", String::from_utf8_lossy(name)), range));
+                }
+            }
+            export_names = Some((
+                directory.address_of_names.get(LE),
+                directory.address_of_name_ordinals.get(LE),
+                directory.number_of_names.get(LE) as usize,
+            ));
+        }
+
+        // Names the loader resolves against other modules must stay intact.
+        let mut protected = Vec::new();
+        if let Some(import_table) = file.import_table()? {
+            let mut descriptors = import_table.descriptors()?;
+            while let Some(descriptor) = descriptors.next()? {
+                let name_rva = descriptor.name.get(LE);
+                let name = import_table.name(name_rva)?;
+                protected.extend(file_range(name_rva, name.len()));
+
+                let thunks_rva = match descriptor.original_first_thunk.get(LE) {
+                    0 => descriptor.first_thunk.get(LE),
+                    rva => rva,
+                };
+                let mut thunks = import_table.thunks(thunks_rva)?;
+                while let Some(thunk) = thunks.next::<Pe>()? {
+                    if thunk.is_ordinal() {
+                        continue;
+                    }
+                    let (_, name) = import_table.hint_name(thunk.address())?;
+                    protected.extend(file_range(thunk.address(), 2 + name.len()));
+                }
+            }
+        }
+        if let Some(delay_load_table) = file
+            .data_directories()
+            .delay_load_import_table(self.data.as_slice(), &section_table)?
+        {
+            let mut descriptors = delay_load_table.descriptors()?;
+            while let Some(descriptor) = descriptors.next()? {
+                let name_rva = descriptor.dll_name_rva.get(LE);
+                let name = delay_load_table.name(name_rva)?;
+                protected.extend(file_range(name_rva, name.len()));
+
+                let mut thunks =
+                    delay_load_table.thunks(descriptor.import_name_table_rva.get(LE))?;
+                while let Some(thunk) = thunks.next::<Pe>()? {
+                    if thunk.is_ordinal() {
                         continue;
                     }
+                    let (_, name) = delay_load_table.hint_name(thunk.address())?;
+                    protected.extend(file_range(thunk.address(), 2 + name.len()));
+                }
+            }
+        }
+
+        let export_names = export_names.and_then(|(names_rva, ordinals_rva, count)| {
+            Some((
+                file_range(names_rva, count * 4)?,
+                file_range(ordinals_rva, count * 2)?,
+            ))
+        });
+
+        Ok(KeywordTargets {
+            sections,
+            protected,
+            export_names,
+        })
+    }
+
+    /// Find keyword occurrences inside the target sections, skipping any
+    /// that overlap a protected range or an earlier match. Returns the
+    /// reports and the offset and rule index of each match.
+    fn find_keywords(
+        &self,
+        options: &AntiFridaOptions,
+        targets: &KeywordTargets,
+    ) -> (Vec<KeywordReport>, Vec<(usize, usize)>) {
+        let mut reports = Vec::new();
+        let mut edits = Vec::new();
+        // Start -> end of every match so far; a keyword must not match
+        // inside the replacement of an earlier one.
+        let mut claimed = std::collections::BTreeMap::new();
+
+        for (rule_index, rule) in options.keywords.iter().enumerate() {
+            let keyword_bytes = rule.keyword.as_bytes();
+            let mut report = KeywordReport {
+                keyword: rule.keyword.clone(),
+                replacement: rule.replacement.clone().unwrap_or_default(),
+                occurrences: Vec::new(),
+            };
 
-                    let i = pos + offset;
-                    self.data[i..i + keyword_bytes.len()].copy_from_slice(replace_bytes);
-                    replacements += 1;
-                    pos = i + keyword_bytes.len();
+            // Use a sliding window approach with memchr for faster searching
+            let mut pos = 0;
+            while let Some(offset) = memchr::memmem::find(&self.data[pos..], keyword_bytes) {
+                let i = pos + offset;
+                let end = i + keyword_bytes.len();
+                pos = end;
+
+                let Some((section, range)) = targets
+                    .sections
+                    .iter()
+                    .find(|(_, range)| range.contains(&i))
+                else {
+                    continue;
+                };
+                if targets.protected.iter().any(|p| p.start < end && i < p.end) {
+                    continue;
+                }
+                if claimed
+                    .range(..end)
+                    .next_back()
+                    .is_some_and(|(_, &claimed_end)| claimed_end > i)
+                {
+                    continue;
                 }
+
+                claimed.insert(i, end);
+                edits.push((i, rule_index));
+                report.occurrences.push((section.clone(), i - range.start));
             }
 
-            info!("Replaced {} occurrences of keywords", replacements);
+            reports.push(report);
+        }
 
-            // Fix GNU_HASH as we changed the string table
-            let cloned_data = self.data.clone();
-            let mut obj = object::build::elf::Builder::read(cloned_data.as_slice())?;
-            obj.delete_orphan_dynamics();
-            obj.delete_orphan_symbols();
-            obj.set_section_sizes();
-            self.data = vec![];
-            obj.write(&mut self.data)?;
+        (reports, edits)
+    }
+
+    /// The loader binary searches export names, so re-sort the name pointer
+    /// and ordinal tables after names have been rewritten.
+    fn sort_pe_export_names<Pe: ImageNtHeaders>(
+        &mut self,
+        names: std::ops::Range<usize>,
+        ordinals: std::ops::Range<usize>,
+    ) -> Result<()> {
+        let file = object::read::pe::PeFile::<Pe>::parse(self.data.as_slice())?;
+        let section_table = file.section_table();
+
+        let mut entries = Vec::new();
+        for (pointer, ordinal) in self.data[names.clone()]
+            .chunks_exact(4)
+            .zip(self.data[ordinals.clone()].chunks_exact(2))
+        {
+            let pointer = u32::from_le_bytes(pointer.try_into().unwrap());
+            let ordinal = u16::from_le_bytes(ordinal.try_into().unwrap());
+            let name = section_table
+                .pe_data_at(self.data.as_slice(), pointer)
+                .and_then(|data| data.split(|&b| b == 0).next())
+                .context("Invalid export name pointer")?
+                .to_vec();
+            entries.push((name, pointer, ordinal));
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for (index, (_, pointer, ordinal)) in entries.into_iter().enumerate() {
+            let name_at = names.start + index * 4;
+            let ordinal_at = ordinals.start + index * 2;
+            self.data[name_at..name_at + 4].copy_from_slice(&pointer.to_le_bytes());
+            self.data[ordinal_at..ordinal_at + 2].copy_from_slice(&ordinal.to_le_bytes());
         }
 
         Ok(())
     }
 
+    /// Drop the Authenticode certificate table. Any change to the image
+    /// invalidates the signatures in it, and a broken signature is worse
+    /// than none. Returns the report line, if there was a table.
+    fn strip_pe_certificates(&mut self) -> Result<Option<String>> {
+        let change = self.certificate_table_change()?;
+        let Some((entry_offset, table)) = pe_certificate_table(&self.data)? else {
+            return Ok(None);
+        };
+        for (revision, certificate_type, length) in
+            pe_certificates(self.data.get(table.clone()).unwrap_or_default())
+        {
+            warn!(
+                "Removing certificate (revision {revision:#x}, type {certificate_type}, {length} bytes): the rewrite invalidates its signature"
+            );
+        }
+
+        self.data[entry_offset..entry_offset + 8].fill(0);
+        // The table is normally the last thing in the file; anything else
+        // is left in place as overlay data.
+        if table.end == self.data.len() {
+            self.data.truncate(table.start);
+        }
+        Ok(change)
+    }
+
+    fn certificate_table_change(&self) -> Result<Option<String>> {
+        let Some((_, table)) = pe_certificate_table(&self.data)? else {
+            return Ok(None);
+        };
+        let certificates = pe_certificates(self.data.get(table.clone()).unwrap_or_default());
+        Ok(Some(format!(
+            "Remove the certificate table ({} certificate(s), {} bytes)",
+            certificates.len(),
+            table.len()
+        )))
+    }
+
+    fn copy_pe(
+        &self,
+        new_sections: Vec<PeNewSection>,
+    ) -> Result<(Vec<u8>, Vec<object::write::pe::SectionRange>)> {
+        match object::FileKind::parse(self.data.as_slice())? {
+            object::FileKind::Pe32 => self.copy_pe_file::<pe::ImageNtHeaders32>(new_sections),
+            object::FileKind::Pe64 => self.copy_pe_file::<pe::ImageNtHeaders64>(new_sections),
+            _ => anyhow::bail!("Not a PE file"),
+        }
+    }
+
+    /// Build a section holding a copy of the import directory with one more
+    /// descriptor for `lib_name`.
+    fn pe_import_section<Pe: ImageNtHeaders>(
+        &self,
+        lib_name: &str,
+        import_symbol: Option<&str>,
+    ) -> Result<PeNewSection> {
+        let file = object::read::pe::PeFile::<Pe>::parse(self.data.as_slice())?;
+
+        let mut descriptors = Vec::new();
+        if let Some(import_table) = file.import_table()? {
+            let mut iter = import_table.descriptors()?;
+            while let Some(descriptor) = iter.next()? {
+                descriptors.extend_from_slice(object::pod::bytes_of(descriptor));
+            }
+        }
+
+        let descriptor_size = std::mem::size_of::<pe::ImageImportDescriptor>() as u32;
+        let thunk_size = std::mem::size_of::<Pe::ImageThunkData>() as u32;
+        let table_size = descriptors.len() as u32 + 2 * descriptor_size;
+        let ilt_offset = (table_size + 7) & !7;
+        let iat_offset = ilt_offset + 2 * thunk_size;
+        let hint_name_offset = iat_offset + 2 * thunk_size;
+        let hint_name_size = match import_symbol {
+            Some(symbol) => (2 + symbol.len() as u32 + 1 + 1) & !1,
+            None => 0,
+        };
+        let dll_name_offset = hint_name_offset + hint_name_size;
+        let size = dll_name_offset + lib_name.len() as u32 + 1;
+
+        let is_64 = file.is_64();
+        let lib_name = lib_name.to_string();
+        let import_symbol = import_symbol.map(str::to_string);
+        let contents = move |rva: u32| {
+            let mut out = descriptors;
+
+            // New descriptor followed by the null terminator.
+            for value in [
+                rva + ilt_offset,
+                0,
+                0,
+                rva + dll_name_offset,
+                rva + iat_offset,
+            ] {
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+            out.resize(ilt_offset as usize, 0);
+
+            let thunk = match &import_symbol {
+                Some(_) => (rva + hint_name_offset) as u64,
+                None if is_64 => pe::IMAGE_ORDINAL_FLAG64 | 1,
+                None => (pe::IMAGE_ORDINAL_FLAG32 | 1) as u64,
+            };
+            // Import lookup table, then import address table.
+            for _ in 0..2 {
+                if is_64 {
+                    out.extend_from_slice(&thunk.to_le_bytes());
+                    out.extend_from_slice(&0u64.to_le_bytes());
+                } else {
+                    out.extend_from_slice(&(thunk as u32).to_le_bytes());
+                    out.extend_from_slice(&0u32.to_le_bytes());
+                }
+            }
+
+            if let Some(symbol) = &import_symbol {
+                out.extend_from_slice(&0u16.to_le_bytes());
+                out.extend_from_slice(symbol.as_bytes());
+                out.resize((hint_name_offset + hint_name_size) as usize, 0);
+            }
+            out.extend_from_slice(lib_name.as_bytes());
+            out.push(0);
+            out
+        };
+
+        Ok(PeNewSection {
+            name: *b".fripimp",
+            characteristics: pe::IMAGE_SCN_CNT_INITIALIZED_DATA
+                | pe::IMAGE_SCN_MEM_READ
+                | pe::IMAGE_SCN_MEM_WRITE,
+            size,
+            contents: Box::new(contents),
+            data_directories: vec![(pe::IMAGE_DIRECTORY_ENTRY_IMPORT, 0, table_size)],
+            replaces_existing: false,
+        })
+    }
+
+    /// Build a `.rsrc` section holding the existing resources with the
+    /// version resource replaced by `version_info`.
+    fn pe_resource_section<Pe: ImageNtHeaders>(
+        &self,
+        version_info: &VersionInfo,
+    ) -> Result<PeNewSection> {
+        let mut resources = pe_resource::read_resources::<Pe>(&self.data)?;
+        pe_resource::set_version_info(&mut resources, version_info)?;
+        let size = pe_resource::build_section(&resources, 0).len() as u32;
+
+        Ok(PeNewSection {
+            name: *b".rsrc\0\0\0",
+            characteristics: pe::IMAGE_SCN_CNT_INITIALIZED_DATA | pe::IMAGE_SCN_MEM_READ,
+            size,
+            contents: Box::new(move |rva| pe_resource::build_section(&resources, rva)),
+            data_directories: vec![(pe::IMAGE_DIRECTORY_ENTRY_RESOURCE, 0, size)],
+            replaces_existing: true,
+        })
+    }
+
+    /// Copy the image with `new_sections` appended, in order.
     fn copy_pe_file<Pe: ImageNtHeaders>(
         &self,
-        data: &[u8],
-        embedded_config: &EmbeddedConfig,
-    ) -> Result<Vec<u8>> {
+        new_sections: Vec<PeNewSection>,
+    ) -> Result<(Vec<u8>, Vec<object::write::pe::SectionRange>)> {
         let in_data = self.data.as_slice();
         let in_dos_header = pe::ImageDosHeader::parse(in_data)?;
         let mut offset = in_dos_header.nt_headers_offset().into();
@@ -396,21 +1720,23 @@ impl BinaryProcessor {
         // Reserve file ranges and virtual addresses.
         writer.reserve_dos_header_and_stub();
         if let Some(in_rich_header) = in_rich_header.as_ref() {
-            writer.reserve(in_rich_header.length as u32 + 8, 4);
+            writer.reserve(in_rich_header.length as u32, 4);
         }
         writer.reserve_nt_headers(in_data_directories.len());
 
-        // Copy data directories that don't have special handling.
-        let cert_dir = in_data_directories
-            .get(pe::IMAGE_DIRECTORY_ENTRY_SECURITY)
-            .map(pe::ImageDataDirectory::address_range);
+        // Copy data directories that don't have special handling. The
+        // certificate table has been stripped by `strip_pe_certificates`.
         let reloc_dir = in_data_directories
             .get(pe::IMAGE_DIRECTORY_ENTRY_BASERELOC)
-            .map(pe::ImageDataDirectory::address_range);
+            .map(pe::ImageDataDirectory::address_range)
+            .filter(|&(virtual_address, _)| virtual_address != 0);
+        // Bound imports live in the header area, which is rebuilt here, so
+        // they are dropped as well.
         for (i, dir) in in_data_directories.iter().enumerate() {
             if dir.virtual_address.get(LE) == 0
                 || i == pe::IMAGE_DIRECTORY_ENTRY_SECURITY
                 || i == pe::IMAGE_DIRECTORY_ENTRY_BASERELOC
+                || i == pe::IMAGE_DIRECTORY_ENTRY_BOUND_IMPORT
             {
                 continue;
             }
@@ -419,25 +1745,43 @@ impl BinaryProcessor {
 
         // Determine which sections to copy.
         // We ignore any existing ".reloc" section since we recreate it ourselves.
+        // Linkers may size the section larger than the directory, so only
+        // the start has to match.
         let mut in_sections_index = Vec::new();
         for (index, in_section) in in_sections.enumerate() {
-            if reloc_dir == Some(in_section.pe_address_range()) {
+            if reloc_dir.map(|(virtual_address, _)| virtual_address)
+                == Some(in_section.virtual_address.get(LE))
+            {
                 continue;
             }
             in_sections_index.push(index);
         }
+        // A section we added earlier can only be dropped if nothing but
+        // other replaced sections follows it, otherwise the RVAs of
+        // everything behind it would change.
+        while let Some(&last) = in_sections_index.last() {
+            let last_name = in_sections.section(last)?.name;
+            if !new_sections
+                .iter()
+                .any(|new_section| new_section.replaces_existing && new_section.name == last_name)
+            {
+                break;
+            }
+            in_sections_index.pop();
+        }
 
         let mut out_sections_len = in_sections_index.len();
         if reloc_dir.is_some() {
             out_sections_len += 1;
         }
 
-        // Add one more section for our embedded data
-        out_sections_len += 1;
+        // Add our own sections
+        out_sections_len += new_sections.len();
 
         writer.reserve_section_headers(out_sections_len as u16);
 
         let mut in_sections_data = Vec::new();
+        let mut moved_sections = Vec::new();
         for index in &in_sections_index {
             let in_section = in_sections.section(*index)?;
             let range = writer.reserve_section(
@@ -446,23 +1790,36 @@ impl BinaryProcessor {
                 in_section.virtual_size.get(LE),
                 in_section.size_of_raw_data.get(LE),
             );
+            // File offsets may move if the extra section header no longer
+            // fits in the original header area; RVAs must not.
             debug_assert_eq!(range.virtual_address, in_section.virtual_address.get(LE));
-            debug_assert_eq!(range.file_offset, in_section.pointer_to_raw_data.get(LE));
             debug_assert_eq!(range.file_size, in_section.size_of_raw_data.get(LE));
+            moved_sections.push(MovedSection {
+                virtual_address: range.virtual_address,
+                virtual_size: in_section.virtual_size.get(LE),
+                old_file_offset: in_section.pointer_to_raw_data.get(LE),
+                new_file_offset: range.file_offset,
+                file_size: range.file_size,
+            });
             in_sections_data.push((range.file_offset, in_section.pe_data(in_data)?));
         }
 
-        // Add our new section for embedded data
-        let mut new_section_name = [0u8; 8];
-        new_section_name[..8].copy_from_slice(&b".fripac\0"[..8]);
-        let new_section_characteristics =
-            pe::IMAGE_SCN_CNT_INITIALIZED_DATA | pe::IMAGE_SCN_MEM_READ | pe::IMAGE_SCN_MEM_WRITE;
-        let new_section_range = writer.reserve_section(
-            new_section_name,
-            new_section_characteristics,
-            data.len() as u32,
-            data.len() as u32,
-        );
+        // Add our new sections
+        let mut new_sections_data = Vec::new();
+        for new_section in new_sections {
+            let range = writer.reserve_section(
+                new_section.name,
+                new_section.characteristics,
+                new_section.size,
+                new_section.size,
+            );
+            for (index, offset, size) in &new_section.data_directories {
+                writer.set_data_directory(*index, range.virtual_address + offset, *size);
+            }
+            let data = (new_section.contents)(range.virtual_address);
+            debug_assert_eq!(data.len(), new_section.size as usize);
+            new_sections_data.push((range, data));
+        }
 
         if reloc_dir.is_some() {
             let mut blocks = in_data_directories
@@ -476,17 +1833,13 @@ impl BinaryProcessor {
             writer.reserve_reloc_section();
         }
 
-        if let Some((_, size)) = cert_dir {
-            // TODO: reserve individual certificates
-            writer.reserve_certificate_table(size);
-        }
-
         // Start writing.
         writer.write_dos_header_and_stub()?;
         if let Some(in_rich_header) = in_rich_header.as_ref() {
-            // TODO: recalculate xor key
+            // The key is fixed up by `update_rich_header_key` once the DOS
+            // header is final.
             writer.write_align(4);
-            writer.write(&in_data[in_rich_header.offset..][..in_rich_header.length + 8]);
+            writer.write(&in_data[in_rich_header.offset..][..in_rich_header.length]);
         }
         writer.write_nt_headers(object::write::pe::NtHeaders {
             machine: in_file_header.machine.get(LE),
@@ -514,62 +1867,350 @@ impl BinaryProcessor {
             writer.write_section(offset, data);
         }
 
-        // Write our new section with embedded data
-        writer.write_section(new_section_range.file_offset, data);
+        // Write our new sections
+        for (range, data) in &new_sections_data {
+            writer.write_section(range.file_offset, data);
+        }
 
         writer.write_reloc_section();
-        if let Some((address, size)) = cert_dir {
-            // TODO: write individual certificates
-            writer.write_certificate_table(&in_data[address as usize..][..size as usize]);
-        }
 
         debug_assert_eq!(writer.reserved_len() as usize, writer.len());
+        if let Some(debug_dir) = in_data_directories.get(pe::IMAGE_DIRECTORY_ENTRY_DEBUG) {
+            relocate_debug_directory(&mut out_data, debug_dir.address_range(), &moved_sections)?;
+        }
+        if in_rich_header.is_some() {
+            update_rich_header_key(&mut out_data)?;
+        }
 
-        // Now update the embedded config offset
-        let embedded_config_offset = self
-            .find_embedded_config()
-            .context("Failed to find embedded config after adding data")?;
+        let new_section_ranges = new_sections_data
+            .into_iter()
+            .map(|(range, _)| range)
+            .collect();
+        Ok((out_data, new_section_ranges))
+    }
 
-        let config_data_offset = new_section_range.file_offset as i32;
-        let config_data_rva = new_section_range.virtual_address as i32;
+}
 
-        // Find the section containing the embedded config
-        let mut config_section_rva = 0;
-        let mut config_section_offset = 0;
-        for index in &in_sections_index {
-            let in_section = in_sections.section(*index)?;
-            let section_start = in_section.pointer_to_raw_data.get(LE) as usize;
-            let section_end = section_start + in_section.size_of_raw_data.get(LE) as usize;
-            if embedded_config_offset >= section_start && embedded_config_offset < section_end {
-                config_section_rva = in_section.virtual_address.get(LE) as i32;
-                config_section_offset = in_section.pointer_to_raw_data.get(LE) as i32;
-                break;
-            }
+/// Where a copied section was in the input file and where it is now.
+struct MovedSection {
+    virtual_address: u32,
+    virtual_size: u32,
+    old_file_offset: u32,
+    new_file_offset: u32,
+    file_size: u32,
+}
+
+fn adhoc_signature_change(identifier: &str, replaced: bool) -> String {
+    if replaced {
+        format!("Replace the code signature with an ad-hoc one ({identifier})")
+    } else {
+        format!("Add an ad-hoc code signature ({identifier})")
+    }
+}
+
+/// `IMAGE_DEBUG_DIRECTORY` entries carry the file offset of their data
+/// (e.g. the CodeView record pointing at the PDB) next to its RVA. Shift
+/// those offsets when the section headers grew and moved the sections.
+fn relocate_debug_directory(
+    data: &mut [u8],
+    (virtual_address, size): (u32, u32),
+    sections: &[MovedSection],
+) -> Result<()> {
+    if virtual_address == 0
+        || sections
+            .iter()
+            .all(|section| section.old_file_offset == section.new_file_offset)
+    {
+        return Ok(());
+    }
+    let directory_offset = sections
+        .iter()
+        .find(|section| {
+            virtual_address >= section.virtual_address
+                && virtual_address - section.virtual_address < section.virtual_size
+        })
+        .map(|section| section.new_file_offset + (virtual_address - section.virtual_address))
+        .context("Debug directory is outside every section")? as usize;
+
+    let entry_size = std::mem::size_of::<pe::ImageDebugDirectory>();
+    for index in 0..size as usize / entry_size {
+        let offset = directory_offset + index * entry_size;
+        let (entry, _) = object::pod::from_bytes_mut::<pe::ImageDebugDirectory>(
+            data.get_mut(offset..)
+                .context("Debug directory is truncated")?,
+        )
+        .map_err(|_| anyhow::anyhow!("Debug directory is truncated"))?;
+        let pointer = entry.pointer_to_raw_data.get(LE);
+        if pointer == 0 {
+            continue;
+        }
+        let Some(section) = sections.iter().find(|section| {
+            pointer >= section.old_file_offset
+                && pointer - section.old_file_offset < section.file_size
+        }) else {
+            anyhow::bail!(
+                "Debug data at file offset {pointer:#x} is outside every section and cannot be moved"
+            );
+        };
+        entry.pointer_to_raw_data.set(
+            LE,
+            section.new_file_offset + (pointer - section.old_file_offset),
+        );
+    }
+    Ok(())
+}
+
+/// The PE image checksum: a 16-bit ones' complement style sum over the file,
+/// skipping the checksum field itself, plus the file length.
+fn pe_checksum(data: &[u8], checksum_offset: usize) -> u32 {
+    let mut sum: u64 = 0;
+    for (index, chunk) in data.chunks(2).enumerate() {
+        let offset = index * 2;
+        if offset >= checksum_offset && offset < checksum_offset + 4 {
+            continue;
+        }
+        let word = match chunk {
+            [lo, hi] => u16::from_le_bytes([*lo, *hi]),
+            [lo] => *lo as u16,
+            _ => unreachable!(),
+        };
+        sum += word as u64;
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum = (sum & 0xffff) + (sum >> 16);
+    sum as u32 + data.len() as u32
+}
+
+/// Re-mask the Rich header for the DOS header and stub in front of it. The
+/// key is a checksum of those bytes (with `e_lfanew` skipped) and of the
+/// unmasked entries, so it changes whenever the stub is rewritten.
+fn update_rich_header_key(data: &mut [u8]) -> Result<()> {
+    let nt_headers_offset = pe::ImageDosHeader::parse(&*data)?.nt_headers_offset();
+    let Some(rich_header) =
+        object::read::pe::RichHeaderInfo::parse(&*data, nt_headers_offset.into())
+    else {
+        return Ok(());
+    };
+    let entries: Vec<_> = rich_header.unmasked_entries().collect();
+    let start = rich_header.offset;
+
+    let e_lfanew = std::mem::offset_of!(pe::ImageDosHeader, e_lfanew);
+    let mut key = start as u32;
+    for (index, &byte) in data[..start].iter().enumerate() {
+        if (e_lfanew..e_lfanew + 4).contains(&index) {
+            continue;
+        }
+        key = key.wrapping_add((byte as u32).rotate_left(index as u32));
+    }
+    for entry in &entries {
+        key = key.wrapping_add(entry.comp_id.rotate_left(entry.count));
+    }
+
+    // "DanS" and three zero padding words, then the entries, all masked;
+    // "Rich" and the key itself are stored as is.
+    let mut words = vec![0x536e_6144 ^ key, key, key, key];
+    for entry in &entries {
+        words.push(entry.comp_id ^ key);
+        words.push(entry.count ^ key);
+    }
+    words.push(u32::from_le_bytes(*b"Rich"));
+    words.push(key);
+    for (index, word) in words.into_iter().enumerate() {
+        let at = start + index * 4;
+        data[at..at + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    Ok(())
+}
+
+/// File offset of `OptionalHeader.CheckSum`, which sits at the same offset in
+/// the PE32 and PE32+ headers.
+pub fn pe_checksum_offset(data: &[u8]) -> Result<usize> {
+    Ok(
+        pe::ImageDosHeader::parse(data)?.nt_headers_offset() as usize
+            + 4
+            + std::mem::size_of::<pe::ImageFileHeader>()
+            + 64,
+    )
+}
+
+/// Recompute the optional header checksum over the final image.
+pub fn update_pe_checksum(data: &mut [u8]) -> Result<()> {
+    let checksum_offset = pe_checksum_offset(data)?;
+    let checksum = pe_checksum(data, checksum_offset);
+    data[checksum_offset..checksum_offset + 4].copy_from_slice(&checksum.to_le_bytes());
+    Ok(())
+}
+
+/// File offset of the certificate table entry in the data directories, if
+/// the image has room for one.
+pub fn pe_certificate_entry_offset(data: &[u8]) -> Result<Option<usize>> {
+    let optional_header_offset = pe::ImageDosHeader::parse(data)?.nt_headers_offset() as usize
+        + 4
+        + std::mem::size_of::<pe::ImageFileHeader>();
+    let read_u32 = |at: usize| -> Result<u32> {
+        Ok(u32::from_le_bytes(
+            data.get(at..at + 4)
+                .context("PE optional header is truncated")?
+                .try_into()
+                .unwrap(),
+        ))
+    };
+    let magic = read_u32(optional_header_offset)? as u16;
+    // NumberOfRvaAndSizes, followed by the data directories.
+    let directories_count_offset = optional_header_offset
+        + match magic {
+            pe::IMAGE_NT_OPTIONAL_HDR64_MAGIC => 108,
+            _ => 92,
+        };
+    if read_u32(directories_count_offset)? as usize <= pe::IMAGE_DIRECTORY_ENTRY_SECURITY {
+        return Ok(None);
+    }
+
+    Ok(Some(
+        directories_count_offset
+            + 4
+            + pe::IMAGE_DIRECTORY_ENTRY_SECURITY * std::mem::size_of::<pe::ImageDataDirectory>(),
+    ))
+}
+
+/// File offset of the certificate table entry in the data directories, and
+/// the file range of the table, if the image has one.
+pub fn pe_certificate_table(data: &[u8]) -> Result<Option<(usize, std::ops::Range<usize>)>> {
+    let Some(entry_offset) = pe_certificate_entry_offset(data)? else {
+        return Ok(None);
+    };
+    let field = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap()) as usize;
+    let (address, size) = (field(entry_offset), field(entry_offset + 4));
+    if address == 0 || size == 0 {
+        return Ok(None);
+    }
+    Ok(Some((entry_offset, address..address + size)))
+}
+
+/// `(wRevision, wCertificateType, dwLength)` of each `WIN_CERTIFICATE` in
+/// the certificate table.
+fn pe_certificates(table: &[u8]) -> Vec<(u16, u16, usize)> {
+    let mut certificates = Vec::new();
+    let mut rest = table;
+    while rest.len() >= 8 {
+        let length = u32::from_le_bytes(rest[0..4].try_into().unwrap()) as usize;
+        let revision = u16::from_le_bytes(rest[4..6].try_into().unwrap());
+        let certificate_type = u16::from_le_bytes(rest[6..8].try_into().unwrap());
+        if length < 8 {
+            break;
+        }
+        certificates.push((revision, certificate_type, length));
+        // Entries are padded to 8 bytes.
+        rest = rest.get((length + 7) & !7..).unwrap_or_default();
+    }
+    certificates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn debug_entry(pointer_to_raw_data: u32) -> Vec<u8> {
+        let mut entry = vec![0u8; std::mem::size_of::<pe::ImageDebugDirectory>()];
+        // Type: CodeView, SizeOfData, AddressOfRawData, PointerToRawData
+        entry[12..16].copy_from_slice(&pe::IMAGE_DEBUG_TYPE_CODEVIEW.to_le_bytes());
+        entry[16..20].copy_from_slice(&0x20u32.to_le_bytes());
+        entry[20..24].copy_from_slice(&0x1200u32.to_le_bytes());
+        entry[24..28].copy_from_slice(&pointer_to_raw_data.to_le_bytes());
+        entry
+    }
+
+    fn moved_rdata() -> MovedSection {
+        MovedSection {
+            virtual_address: 0x1000,
+            virtual_size: 0x800,
+            old_file_offset: 0x400,
+            new_file_offset: 0x600,
+            file_size: 0x800,
         }
+    }
 
-        let mut updated_config = *embedded_config;
-        updated_config.data_offset = (config_data_offset as i32 - embedded_config_offset as i32)
-            - (config_data_offset as i32 - config_section_offset as i32)
-            + (config_data_rva - config_section_rva);
+    #[test]
+    fn debug_directory_follows_moved_sections() {
+        let mut data = vec![0u8; 0x1000];
+        // The directory is at RVA 0x1100, now file offset 0x700
+        data[0x700..0x71c].copy_from_slice(&debug_entry(0x600));
+        relocate_debug_directory(&mut data, (0x1100, 0x1c), &[moved_rdata()]).unwrap();
+        assert_eq!(&data[0x700 + 24..0x700 + 28], &0x800u32.to_le_bytes());
+    }
 
-        let config_bytes = updated_config.as_bytes();
-        let mut final_out_data = out_data;
-        final_out_data[embedded_config_offset..embedded_config_offset + config_bytes.len()]
-            .copy_from_slice(&config_bytes);
+    #[test]
+    fn debug_directory_unchanged_without_moves() {
+        let mut data = vec![0u8; 0x1000];
+        data[0x500..0x51c].copy_from_slice(&debug_entry(0x600));
+        let section = MovedSection {
+            new_file_offset: 0x400,
+            ..moved_rdata()
+        };
+        relocate_debug_directory(&mut data, (0x1100, 0x1c), &[section]).unwrap();
+        assert_eq!(&data[0x500..0x51c], &debug_entry(0x600)[..]);
+    }
 
-        Ok(final_out_data)
+    #[test]
+    fn debug_data_outside_sections_is_rejected() {
+        let mut data = vec![0u8; 0x1000];
+        data[0x700..0x71c].copy_from_slice(&debug_entry(0xf00));
+        assert!(relocate_debug_directory(&mut data, (0x1100, 0x1c), &[moved_rdata()]).is_err());
+    }
+
+    /// DOS header, stub and Rich header of an MSVC-linked x64 DLL, up to
+    /// `e_lfanew`. The linker masked the Rich header with key 0xfeb2f9f4.
+    const MSVC_DOS_HEADER: &str = concat!(
+        "4d5a90000300000004000000ffff0000b8000000000000004000000000000000",
+        "00000000000000000000000000000000000000000000000000000000f0000000",
+        "0e1fba0e00b409cd21b8014ccd21546869732070726f6772616d2063616e6e6f",
+        "742062652072756e20696e20444f53206d6f64652e0d0d0a2400000000000000",
+        "b098dcadf4f9b2fef4f9b2fef4f9b2fe67b72afef5f9b2feef6419fed5f9b2fe",
+        "ef6418fe87f9b2feef642cfefdf9b2fefd8121fef3f9b2fef4f9b3fe92f9b2fe",
+        "ef641cfef5f9b2feef6428fef5f9b2feef642ffef5f9b2fe52696368f4f9b2fe",
+        "00000000000000000000000000000000",
+    );
+
+    /// The same header with the Rich entries masked with key 0x12345678.
+    const WRONG_KEY_DOS_HEADER: &str = concat!(
+        "4d5a90000300000004000000ffff0000b8000000000000004000000000000000",
+        "00000000000000000000000000000000000000000000000000000000f0000000",
+        "0e1fba0e00b409cd21b8014ccd21546869732070726f6772616d2063616e6e6f",
+        "742062652072756e20696e20444f53206d6f64652e0d0d0a2400000000000000",
+        "3c375a41785634127856341278563412eb18ac127956341263cb9f1259563412",
+        "63cb9e120b56341263cbaa1271563412712ea7127f563412785635121e563412",
+        "63cb9a127956341263cbae127956341263cba912795634125269636878563412",
+        "00000000000000000000000000000000",
+    );
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|at| u8::from_str_radix(&hex[at..at + 2], 16).unwrap())
+            .collect()
     }
 
-    fn compress_xz(&self, data: &[u8]) -> Result<Vec<u8>> {
-        use std::io::Write;
-        use xz2::write::XzEncoder;
+    #[test]
+    fn rich_header_key_matches_linker() {
+        let mut data = from_hex(WRONG_KEY_DOS_HEADER);
+        update_rich_header_key(&mut data).unwrap();
+        assert_eq!(data, from_hex(MSVC_DOS_HEADER));
 
-        let mut encoder = XzEncoder::new(Vec::new(), 6);
-        encoder.write_all(data)?;
-        Ok(encoder.finish()?)
+        let mut data = from_hex(MSVC_DOS_HEADER);
+        update_rich_header_key(&mut data).unwrap();
+        assert_eq!(data, from_hex(MSVC_DOS_HEADER));
     }
 
-    pub fn into_data(self) -> Vec<u8> {
-        self.data
+    #[test]
+    fn pe_checksum_known_answers() {
+        // Expected values from the dword-based algorithm used by pefile
+        let mut data: Vec<u8> = (0..1001u32).map(|i| ((i * 31 + 7) % 251) as u8).collect();
+        assert_eq!(pe_checksum(&data, 0x40), 0x3420);
+        // The stored checksum itself is skipped
+        data[0x40..0x44].copy_from_slice(&0xdeadbeefu32.to_le_bytes());
+        assert_eq!(pe_checksum(&data, 0x40), 0x3420);
+
+        assert_eq!(pe_checksum(&[0xff; 4096], 0x40), 0x10fff);
+        assert_eq!(pe_checksum(&from_hex(MSVC_DOS_HEADER), 0x58), 0xc280);
     }
 }
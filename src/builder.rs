@@ -1,37 +1,104 @@
-use crate::binary::BinaryProcessor;
-use crate::config::{Platform, ResolvedConfig, ResolvedTarget, TargetConfig};
+use crate::apk::{self, ApkEntry, ResourceTable, XmlElement, XmlValue};
+use crate::authenticode::AuthenticodeSigner;
+use crate::binary::{AntiFridaOptions, BinaryProcessor, KeywordRule, PipelineReport};
+use crate::bundle::{self, Bundle};
+use crate::codesign;
+use crate::compression::{Codec, Compression};
+use crate::config::{
+    Arch, InjectMachoConfig, InjectPeConfig, Platform, PlatformConfig, ResolvedConfig,
+    ResolvedTarget, TargetConfig,
+};
+use crate::crypto::PayloadEncryption;
+use crate::dex::{self, FieldRef, Instruction, MethodRef, Proto};
 use crate::downloader::Downloader;
+use crate::pe_resource::VersionInfo;
+use crate::signing;
 use anyhow::Result;
 use log::{info, warn};
-use rand::Rng;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use sha2::{Digest, Sha256};
 use std::path::{Path, PathBuf};
 use tokio::{fs, process::Command};
 
 pub struct Builder {
     downloader: Downloader,
+    /// Source of every generated identifier; seeded per target when the
+    /// target sets `seed`.
+    rng: StdRng,
+    /// Only report the planned binary changes, write nothing.
+    dry_run: bool,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
-enum Mode {
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub enum Mode {
     EmbedJs = 1,
     WatchPath = 2,
 }
-#[derive(serde::Serialize, serde::Deserialize)]
-struct EmbeddedConfigData {
-    mode: Mode,
-    js_filepath: Option<String>,
-    js_content: Option<String>,
-    watch_path: Option<String>,
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct EmbeddedConfigData {
+    pub mode: Mode,
+    pub js_filepath: Option<String>,
+    pub js_content: Option<String>,
+    pub watch_path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bundle: Option<Bundle>,
 }
 
 impl Builder {
     pub fn new() -> Self {
         Self {
             downloader: Downloader::new(),
+            rng: StdRng::from_entropy(),
+            dry_run: false,
         }
     }
 
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
     pub async fn build_target(&mut self, target_name: &str, target: &ResolvedTarget) -> Result<Option<String>> {
+        self.rng = match &target.seed {
+            Some(seed) => {
+                info!("→ Using seed for reproducible build: {seed}");
+                let mut hasher = Sha256::new();
+                hasher.update(seed.as_bytes());
+                hasher.update([0]);
+                hasher.update(target_name.as_bytes());
+                StdRng::from_seed(hasher.finalize().into())
+            }
+            None => StdRng::from_entropy(),
+        };
+
+        if self.dry_run {
+            if target.target_type.is_none() {
+                warn!("Target type not specified for target: {target_name}, skipping...");
+                return Ok(None);
+            }
+            let mut processor = self.prepare_binary(target).await?;
+            if target.target_type.as_deref() == Some("shared") {
+                if let Some(version_info) = shared_version_info(target_name, target) {
+                    processor.set_version_info(version_info)?;
+                }
+                if let Some(platform) = &target.platform {
+                    let base_name = target.target_base_name.as_deref().unwrap_or(target_name);
+                    processor.set_adhoc_identifier(&format!("{base_name}-{platform}"));
+                }
+            }
+            let signer = authenticode_signer(target)?;
+            let mut report = processor.plan()?;
+            if let Some(signer) = &signer {
+                report.changes.push(format!(
+                    "Sign with Authenticode certificate: {}",
+                    signer.subject()
+                ));
+            }
+            info!("→ Planned changes (dry run):");
+            log_pipeline_report(&report, show_keyword_locations(target));
+            self.plan_host(target, &processor, signer.as_ref()).await?;
+            return Ok(None);
+        }
+
         // Run beforeBuild hook
         if let Some(cmd) = &target.before_build {
             self.run_hook(cmd).await?;
@@ -41,7 +108,11 @@ impl Builder {
             Some("shared") => Some(self.build_shared(target_name, target).await?),
             Some("xposed") => Some(self.build_xposed(target_name, target).await?),
             Some("inject-apk") => Some(self.build_inject_apk(target_name, target).await?),
+            Some("inject-ipa") => Some(self.build_inject_ipa(target_name, target).await?),
+            Some("inject-pe") => Some(self.build_inject_pe(target_name, target).await?),
+            Some("inject-macho") => Some(self.build_inject_macho(target_name, target).await?),
             Some("zygisk") => Some(self.build_zygisk(target_name, target).await?),
+            Some("tweak-deb") => Some(self.build_tweak_deb(target_name, target).await?),
             Some(other) => anyhow::bail!("Unsupported target type: {other}"),
             None => {
                 warn!("Target type not specified for target: {target_name}, skipping...");
@@ -59,6 +130,106 @@ impl Builder {
         Ok(build_result)
     }
 
+    /// Plan the changes an inject target makes to its host binary, which the
+    /// dry run shows next to the ones to the generated library.
+    async fn plan_host(
+        &mut self,
+        target: &ResolvedTarget,
+        generated: &BinaryProcessor,
+        signer: Option<&AuthenticodeSigner>,
+    ) -> Result<()> {
+        let platform = target
+            .platform
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Missing required field: platform"))?;
+
+        let (host_path, report) = match target.target_type.as_deref() {
+            Some("inject-apk") => {
+                let inject_config = target
+                    .inject_apk
+                    .as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("Missing required field: injectApk"))?;
+                let Some(source_apk_path) = &inject_config.source_apk_path else {
+                    info!("→ The host library is picked after pulling the APK from the device, not planning it");
+                    return Ok(());
+                };
+                let (lib_path, lib_data) = apk_target_library(
+                    &fs::read(source_apk_path).await?,
+                    &platform.android_abi()?,
+                    inject_config.target_lib.as_deref(),
+                )?;
+                info!("→ Selected target library: {lib_path}");
+                let (processor, _) = self.inject_apk_host(lib_data)?;
+                (format!("{source_apk_path}!{lib_path}"), processor.plan()?)
+            }
+            Some("inject-ipa") => {
+                let inject_config = target
+                    .inject_ipa
+                    .as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("Missing required field: injectIpa"))?;
+                let source_ipa_path = inject_config.source_ipa_path.as_ref().ok_or_else(|| {
+                    anyhow::anyhow!("Missing required field: injectIpa.sourceIpaPath")
+                })?;
+                let adhoc_sign = inject_config.adhoc_sign.unwrap_or(false);
+
+                let mut archive =
+                    zip::ZipArchive::new(std::io::Cursor::new(fs::read(source_ipa_path).await?))?;
+                let executable = ipa_main_executable(&mut archive)?;
+                let signed = codesign::identifier(&executable.data)?.is_some();
+                let (processor, _) = self.inject_ipa_host(executable.data, platform, adhoc_sign)?;
+                let mut report = processor.plan()?;
+                if adhoc_sign {
+                    if !signed {
+                        report.changes.push(format!(
+                            "Ad-hoc sign the executable ({})",
+                            executable.bundle_identifier
+                        ));
+                    }
+                    report.changes.push(format!(
+                        "Remove the bundle signature in {}_CodeSignature/",
+                        executable.app_dir
+                    ));
+                }
+                (format!("{source_ipa_path}!{}", executable.path), report)
+            }
+            Some("inject-pe") => {
+                let inject_config = target
+                    .inject_pe
+                    .as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("Missing required field: injectPe"))?;
+                let target_module_path = inject_pe_module_path(inject_config)?;
+                let import_symbol = generated.export_names()?.into_iter().next();
+                let (processor, _) = self
+                    .inject_pe_host(&target_module_path, platform, import_symbol.as_deref())
+                    .await?;
+                let mut report = processor.plan()?;
+                if let Some(signer) = signer {
+                    report.changes.push(format!(
+                        "Sign with Authenticode certificate: {}",
+                        signer.subject()
+                    ));
+                }
+                (target_module_path.display().to_string(), report)
+            }
+            Some("inject-macho") => {
+                let inject_config = target
+                    .inject_macho
+                    .as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("Missing required field: injectMacho"))?;
+                let target_binary_path = inject_macho_binary_path(inject_config)?;
+                let (processor, _) = self
+                    .inject_macho_host(&target_binary_path, platform, inject_config)
+                    .await?;
+                (target_binary_path.display().to_string(), processor.plan()?)
+            }
+            _ => return Ok(()),
+        };
+
+        info!("→ Planned changes to {host_path} (dry run):");
+        log_pipeline_report(&report, false);
+        Ok(())
+    }
+
     async fn run_hook(&self, cmd: &str) -> Result<()> {
         info!("→ Running build hook: {}", cmd);
         let output = if cfg!(target_os = "windows") {
@@ -78,6 +249,13 @@ impl Builder {
     }
 
     async fn generate_binary(&mut self, target: &ResolvedTarget) -> Result<Vec<u8>> {
+        let processor = self.prepare_binary(target).await?;
+        finish_binary(processor, target)
+    }
+
+    /// Queue every change to the prebuilt; nothing is rewritten until the
+    /// processor is finished.
+    async fn prepare_binary(&mut self, target: &ResolvedTarget) -> Result<BinaryProcessor> {
         // Get required fields
         let platform = target
             .platform
@@ -89,10 +267,11 @@ impl Builder {
             .ok_or_else(|| anyhow::anyhow!("Missing required field: fridaVersion"))?;
         let mode = if target.watch_mode {
             "watchpath"
+        } else if target.bundle.is_some() {
+            "bundle"
         } else {
             "embedjs"
         };
-        let use_xz = target.xz.unwrap_or(false);
 
         // Get prebuilt file data
         let prebuilt_data = if let Some(override_file) = &target.override_prebuild_file {
@@ -117,6 +296,7 @@ impl Builder {
         // Process the binary
         info!("→ Processing binary...");
         let mut processor = BinaryProcessor::new(prebuilt_data)?;
+        processor.set_page_size(target.page_size)?;
 
         let config_data = match mode {
             "embedjs" => {
@@ -134,6 +314,41 @@ impl Builder {
                     js_filepath: Some(entry.clone()),
                     js_content: Some(String::from_utf8_lossy(&entry_data).to_string()),
                     watch_path: None,
+                    bundle: None,
+                }
+            }
+            "bundle" => {
+                let bundle_config = target.bundle.as_ref().unwrap();
+                let root = Path::new(&bundle_config.root);
+                let entry = match &bundle_config.entry {
+                    Some(entry) => entry.clone(),
+                    None => {
+                        let entry = target.entry.as_ref().ok_or_else(|| {
+                            anyhow::anyhow!(
+                                "Missing required field: entry or bundle.entry for bundle mode"
+                            )
+                        })?;
+                        bundle::bundle_path(root, Path::new(entry))?
+                    }
+                };
+                let include = bundle_config
+                    .include
+                    .clone()
+                    .unwrap_or_else(|| vec!["**/*".to_string()]);
+
+                info!("→ Collecting bundle from: {}", root.display());
+                let bundle = Bundle::collect(root, &include, &entry)?;
+                let entry_file = bundle.entry_file().unwrap();
+
+                // Bundles stay in EmbedJs mode, which every runtime knows;
+                // those with bundle support look for the `bundle` object,
+                // the others run js_content as a plain script
+                EmbeddedConfigData {
+                    mode: Mode::EmbedJs,
+                    js_filepath: Some(entry_file.path.clone()),
+                    js_content: Some(String::from_utf8_lossy(&entry_file.data).to_string()),
+                    watch_path: None,
+                    bundle: Some(bundle),
                 }
             }
             "watchpath" => {
@@ -147,23 +362,200 @@ impl Builder {
                     js_filepath: None,
                     js_content: None,
                     watch_path: Some(push_path.clone()),
+                    bundle: None,
                 }
             }
             _ => anyhow::bail!("Unsupported mode: {mode}"),
         };
 
-        let config_data = serde_json::to_string(&config_data)?;
+        let mut config_data = serde_json::to_vec(&config_data)?;
+        if let Some(script_key) = target
+            .signing
+            .as_ref()
+            .and_then(|signing| signing.script_key.as_ref())
+        {
+            let key = signing::load_signing_key(Path::new(script_key))?;
+            let signature = signing::sign(&key, &config_data);
+            info!("→ Signed script with key {}", signature.key_fingerprint);
+            signing::append_signature(&mut config_data, &signature)?;
+        }
+
+        let (compression, compressed) = self.payload_compression(target, &config_data)?;
+        let encryption = self.payload_encryption(target)?;
 
         // Add embedded config section
-        processor
-            .add_embedded_config_data(config_data.as_bytes(), use_xz)
-            .unwrap();
+        processor.add_embedded_config_data(
+            &config_data,
+            compressed,
+            compression,
+            encryption.as_ref(),
+            target.checksum.unwrap_or(false),
+        )?;
+        if target.randomize_magic.unwrap_or(false) {
+            processor.randomize_magic(&mut self.rng)?;
+        }
 
-        processor.anti_anti_frida()?;
+        match self.anti_frida_options(target)? {
+            Some(options) => processor.anti_anti_frida(&options),
+            None => info!("→ Skipping anti-detection keyword rewriting"),
+        }
 
-        let output_data = processor.into_data();
+        Ok(processor)
+    }
 
-        Ok(output_data)
+    /// Resolve the payload compression and compress `data` with it. When a
+    /// `compression` block is configured, the size every codec would produce
+    /// is logged too, so the trade-off is visible in the build output.
+    fn payload_compression(
+        &self,
+        target: &ResolvedTarget,
+        data: &[u8],
+    ) -> Result<(Compression, Vec<u8>)> {
+        let compression = match &target.compression {
+            Some(config) => Compression::new(Codec::from_name(&config.codec)?, config.level)?,
+            None if target.xz.unwrap_or(false) => Compression::new(Codec::Xz, None)?,
+            None => Compression::NONE,
+        };
+        if target.compression.is_none() {
+            return Ok((compression, compression.compress(data)?));
+        }
+
+        info!("→ Payload size per codec:");
+        let mut selected = None;
+        for codec in Codec::ALL {
+            let candidate = if codec == compression.codec {
+                compression
+            } else {
+                Compression::new(codec, None)?
+            };
+            let start = std::time::Instant::now();
+            let compressed = candidate.compress(data)?;
+            info!(
+                "  {:<6} level {:>2}: {:>9} bytes ({:.1}%) in {:?}{}",
+                codec.name(),
+                candidate.level,
+                compressed.len(),
+                compressed.len() as f64 * 100.0 / data.len().max(1) as f64,
+                start.elapsed(),
+                if codec == compression.codec {
+                    "  ← selected"
+                } else {
+                    ""
+                }
+            );
+            if codec == compression.codec {
+                selected = Some(compressed);
+            }
+        }
+
+        let compressed = selected
+            .ok_or_else(|| anyhow::anyhow!("Selected codec missing from the codec list"))?;
+        Ok((compression, compressed))
+    }
+
+    fn anti_frida_options(&mut self, target: &ResolvedTarget) -> Result<Option<AntiFridaOptions>> {
+        let mut options = AntiFridaOptions::default();
+        let Some(anti_detection) = &target.anti_detection else {
+            self.fill_keyword_replacements(&mut options);
+            return Ok(Some(options));
+        };
+        if anti_detection.enabled == Some(false) {
+            return Ok(None);
+        }
+
+        if let Some(remove) = &anti_detection.remove_keywords {
+            options
+                .keywords
+                .retain(|rule| !remove.contains(&rule.keyword));
+        }
+        for keyword in anti_detection.extra_keywords.iter().flatten() {
+            if keyword.is_empty() {
+                anyhow::bail!("antiDetection.extraKeywords must not contain empty strings");
+            }
+            if !options.keywords.iter().any(|rule| &rule.keyword == keyword) {
+                options.keywords.push(KeywordRule {
+                    keyword: keyword.clone(),
+                    replacement: None,
+                });
+            }
+        }
+        if let Some(replacements) = &anti_detection.replacements {
+            let mut replacements: Vec<_> = replacements.iter().collect();
+            replacements.sort();
+            for (keyword, replacement) in replacements {
+                if keyword.len() != replacement.len() {
+                    anyhow::bail!(
+                        "antiDetection replacement for {keyword:?} must have the same length ({} bytes), got {replacement:?}",
+                        keyword.len()
+                    );
+                }
+                match options
+                    .keywords
+                    .iter_mut()
+                    .find(|rule| &rule.keyword == keyword)
+                {
+                    Some(rule) => rule.replacement = Some(replacement.clone()),
+                    None => options.keywords.push(KeywordRule {
+                        keyword: keyword.clone(),
+                        replacement: Some(replacement.clone()),
+                    }),
+                }
+            }
+        }
+        // Longer keywords first so e.g. "gum-js-loop" is not split by "gum"
+        options
+            .keywords
+            .sort_by_key(|rule| std::cmp::Reverse(rule.keyword.len()));
+
+        options.extra_sections = anti_detection.extra_sections.clone().unwrap_or_default();
+        self.fill_keyword_replacements(&mut options);
+
+        Ok(Some(options))
+    }
+
+    /// Pick the random replacements here so they follow the target seed.
+    fn fill_keyword_replacements(&mut self, options: &mut AntiFridaOptions) {
+        for rule in &mut options.keywords {
+            if rule.replacement.is_none() {
+                rule.replacement = Some(generate_random_string(&mut self.rng, rule.keyword.len()));
+            }
+        }
+    }
+
+    fn payload_encryption(&mut self, target: &ResolvedTarget) -> Result<Option<PayloadEncryption>> {
+        let Some(encrypt) = &target.encrypt else {
+            return Ok(None);
+        };
+
+        let encryption = match encrypt.key_source.as_deref().unwrap_or("random") {
+            "random" => PayloadEncryption::random(&mut self.rng),
+            "passphrase" => {
+                let passphrase = encrypt.passphrase.as_ref().ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Missing required field: encrypt.passphrase for passphrase key source"
+                    )
+                })?;
+                PayloadEncryption::from_passphrase(passphrase, &mut self.rng)
+            }
+            "packageName" => {
+                let package_name = encrypt
+                    .package_name
+                    .as_ref()
+                    .or_else(|| {
+                        target
+                            .inject_apk
+                            .as_ref()
+                            .and_then(|inject_apk| inject_apk.source_apk_package_name.as_ref())
+                    })
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("Missing required field: encrypt.packageName for packageName key source")
+                    })?;
+                PayloadEncryption::from_package_name(package_name, &mut self.rng)
+            }
+            other => anyhow::bail!("Unsupported encrypt.keySource: {other}"),
+        };
+
+        Ok(Some(encryption))
     }
 
     async fn build_shared(&mut self, target_name: &str, target: &ResolvedTarget) -> Result<String> {
@@ -172,11 +564,24 @@ impl Builder {
 
         let output_dir = target.output_dir.as_deref().unwrap_or("./fripack");
 
-        let output_data = self.generate_binary(target).await?;
         let platform = target
             .platform
             .as_ref()
             .ok_or_else(|| anyhow::anyhow!("Missing required field: platform"))?;
+        let mut processor = self.prepare_binary(target).await?;
+        if let Some(version_info) = shared_version_info(target_name, target) {
+            info!("→ Adding version resource: {}", version_info.version);
+            processor.set_version_info(version_info)?;
+        }
+        processor.set_adhoc_identifier(&format!("{base_name}-{platform}"));
+        let mut output_data = finish_binary(processor, target)?;
+        if let Some(signer) = authenticode_signer(target)? {
+            signer.sign(&mut output_data)?;
+            info!(
+                "✓ Signed with Authenticode certificate: {}",
+                signer.subject()
+            );
+        }
         let output_filename = format!("{base_name}-{platform}.{}", platform.platform.binary_ext());
         let output_file_path = std::path::Path::new(output_dir).join(&output_filename);
         std::fs::create_dir_all(output_dir)?;
@@ -216,145 +621,71 @@ impl Builder {
             anyhow::bail!("Xposed target only supports Android platform");
         }
 
-        let sign = target.sign.is_some();
+        let apk_keystore = match &target.sign {
+            Some(sign_config) => sign_config.apk_keystore()?,
+            None => None,
+        };
+        // Signing is the one step left that needs apksigner and a JDK
+        if apk_keystore.is_some() && !command_in_path("apksigner") {
+            warn!(
+                "apksigner was not found in PATH, so signing the APK will fail; install the Android build tools and a JDK, or drop sign.keystore to build an unsigned APK"
+            );
+        }
         let output_dir = target.output_dir.as_deref().unwrap_or("./fripack");
         let binary_data = self.generate_binary(target).await?;
 
-        let random_so_name = format!("lib{}.so", generate_random_string(8));
-
-        // 3. Create a temporary directory for the apktool project
-        let temp_dir = tempfile::tempdir()?;
-        let temp_path = temp_dir.path();
-        info!("→ Created temporary directory: {}", temp_path.display());
-
-        // Move the generated .so file to the temporary directory for now
-        let temp_so_path = temp_path.join(&random_so_name);
-        fs::write(&temp_so_path, &binary_data).await?;
+        let random_so_name = format!("lib{}.so", generate_random_string(&mut self.rng, 8));
+        let abi = platform.android_abi()?;
 
-        // 4. Create assets/native_init and assets/xposed_init files
-        let assets_dir = temp_path.join("assets");
-        fs::create_dir_all(&assets_dir).await?;
-
-        let native_init_path = assets_dir.join("native_init");
-        fs::write(&native_init_path, &random_so_name).await?;
-        info!("→ Created native_init: {}", native_init_path.display());
-
-        // 5. Generate a random class name for the smali file
-        let random_class_name =
-            format!("{}{}", generate_random_string(4), generate_random_string(4)); // e.g., "abcdABCD"
-
-        let xposed_init_path = assets_dir.join("xposed_init");
+        // 3. Generate a random class name for the Xposed entry class
+        let random_class_name = format!(
+            "{}{}",
+            generate_random_string(&mut self.rng, 4),
+            generate_random_string(&mut self.rng, 4)
+        ); // e.g., "abcdABCD"
         let xposed_init_content = format!("{package_name}.{random_class_name}");
-        fs::write(&xposed_init_path, &xposed_init_content).await?;
-        info!("→ Created xposed_init: {}", xposed_init_path.display());
-
-        // 6. Copy the generated .so file to lib/架构/libxxxx.so within the temporary directory.
-
-        let lib_dir = temp_path.join("lib").join(platform.android_abi()?);
-        fs::create_dir_all(&lib_dir).await?;
-        let dest_so_path = lib_dir.join(&random_so_name);
-        fs::copy(&temp_so_path, &dest_so_path).await?;
-        info!("→ Copied .so to: {}", dest_so_path.display());
-
-        info!("✓ Successfully built Xposed module: {target_name}");
-
-        // 7. Create the smali/com/xx/xx/xx/随机类名.smali file
-        let smali_dir_path = temp_path.join("smali").join(package_name.replace(".", "/"));
-        fs::create_dir_all(&smali_dir_path).await?;
-
-        let smali_file_path = smali_dir_path.join(format!("{random_class_name}.smali"));
-
-        let smali_content = format!(
-            r#".class public L{}/{};
-.super Ljava/lang/Object;
-.implements Lde/robv/android/xposed/IXposedHookLoadPackage;
-.implements Lde/robv/android/xposed/IXposedHookZygoteInit;
-
-.method public constructor <init>()V
-    .locals 0
-    invoke-direct {{p0}}, Ljava/lang/Object;-><init>()V
-    return-void
-.end method
-
-.method public initZygote(Lde/robv/android/xposed/IXposedHookZygoteInit$StartupParam;)V
-    .locals 3
-    iget-object v0, p1, Lde/robv/android/xposed/IXposedHookZygoteInit$StartupParam;->modulePath:Ljava/lang/String;
-    
-    new-instance v1, Ljava/io/File;
-    invoke-direct {{v1, v0}}, Ljava/io/File;-><init>(Ljava/lang/String;)V
-    invoke-virtual {{v1}}, Ljava/io/File;->getParent()Ljava/lang/String;
-    move-result-object v0
-
-    new-instance v1, Ljava/lang/StringBuilder;
-    invoke-direct {{v1}}, Ljava/lang/StringBuilder;-><init>()V
-    invoke-virtual {{v1, v0}}, Ljava/lang/StringBuilder;->append(Ljava/lang/String;)Ljava/lang/StringBuilder;
-    const-string v2, "/lib/{}/{}"
-    invoke-virtual {{v1, v2}}, Ljava/lang/StringBuilder;->append(Ljava/lang/String;)Ljava/lang/StringBuilder;
-    invoke-virtual {{v1}}, Ljava/lang/StringBuilder;->toString()Ljava/lang/String;
-    move-result-object v1
-    
-    invoke-static {{v1}}, Ljava/lang/System;->load(Ljava/lang/String;)V
-    return-void
-.end method
-
-.method public handleLoadPackage(Lde/robv/android/xposed/callbacks/XC_LoadPackage$LoadPackageParam;)V
-    .locals 0
-    return-void
-.end method
-"#,
-            package_name.replace(".", "/"),
-            random_class_name,
-            platform.frida_arch()?,
-            random_so_name
-        );
-
-        fs::write(&smali_file_path, smali_content.as_bytes()).await?;
-        info!("→ Created smali file: {}", smali_file_path.display());
-
-        // 8. Copy ic_launcher.webp and ic_launcher_round.webp if specified in the config.
+        info!("→ Xposed entry class: {xposed_init_content}");
+
+        // 4. Compile the entry class, which loads our library from the
+        // extracted native library directory in initZygote
+        let class_descriptor =
+            format!("L{}/{};", package_name.replace('.', "/"), random_class_name);
+        let library_path = format!("/lib/{}/{}", platform.frida_arch()?, random_so_name);
+        let classes_dex = dex::write_dex(&xposed_entry_class(&class_descriptor, &library_path))?;
+        info!("→ Compiled classes.dex ({} bytes)", classes_dex.len());
+
+        // 5. Collect ic_launcher.webp and ic_launcher_round.webp next to the configured icon
+        let mut resources = ResourceTable::new(package_name);
+        let label = resources.add("string", 0, "app_name", name)?;
+        let mut icons = Vec::new();
         if let Some(icon_path_str) = xposed_config.icon.as_ref() {
-            let icon_path = PathBuf::from(icon_path_str);
-            let res_mipmap_xxhdpi_dir = temp_path.join("res").join("mipmap-xxhdpi");
-            fs::create_dir_all(&res_mipmap_xxhdpi_dir).await?;
-
-            let launcher_icon_name = "ic_launcher.webp";
-            let launcher_round_icon_name = "ic_launcher_round.webp";
-
-            let src_launcher_path = icon_path
+            let icon_dir = Path::new(icon_path_str)
                 .parent()
                 .unwrap_or_else(|| Path::new(""))
-                .join(launcher_icon_name);
-            let src_launcher_round_path = icon_path
-                .parent()
-                .unwrap_or_else(|| Path::new(""))
-                .join(launcher_round_icon_name);
-
-            if src_launcher_path.exists() {
-                let dest_launcher_path = res_mipmap_xxhdpi_dir.join(launcher_icon_name);
-                fs::copy(&src_launcher_path, &dest_launcher_path).await?;
-                info!("→ Copied launcher icon: {}", dest_launcher_path.display());
-            }
-
-            if src_launcher_round_path.exists() {
-                let dest_launcher_round_path = res_mipmap_xxhdpi_dir.join(launcher_round_icon_name);
-                fs::copy(&src_launcher_round_path, &dest_launcher_round_path).await?;
-                info!(
-                    "→ Copied round launcher icon: {}",
-                    dest_launcher_round_path.display()
-                );
+                .to_path_buf();
+            for (resource_name, attribute, resource_id) in [
+                ("ic_launcher", "icon", apk::ATTR_ICON),
+                ("ic_launcher_round", "roundIcon", apk::ATTR_ROUND_ICON),
+            ] {
+                let src_path = icon_dir.join(format!("{resource_name}.webp"));
+                if !src_path.exists() {
+                    warn!("Launcher icon not found, skipping: {}", src_path.display());
+                    continue;
+                }
+                let apk_path = format!("res/mipmap-xxhdpi/{resource_name}.webp");
+                let id = resources.add("mipmap", apk::DENSITY_XXHDPI, resource_name, &apk_path)?;
+                icons.push((
+                    attribute,
+                    resource_id,
+                    id,
+                    apk_path,
+                    fs::read(&src_path).await?,
+                ));
+                info!("→ Added launcher icon: {}", src_path.display());
             }
         }
 
-        // 9. Modify AndroidManifest.xml based on the configuration.
-        let manifest_path = temp_path.join("AndroidManifest.xml");
-
-        let icon_attributes = if xposed_config.icon.is_some() {
-            r#"android:icon="@mipmap/ic_launcher" android:roundIcon="@mipmap/ic_launcher_round""#
-                .to_string()
-        } else {
-            "".to_string()
-        };
-
+        // 6. Compile AndroidManifest.xml
         let xposed_description = xposed_config
             .description
             .as_deref()
@@ -364,80 +695,111 @@ impl Builder {
             .as_deref()
             .unwrap_or("com.example.a;com.example.b");
 
-        let manifest_content = format!(
-            r#"<?xml version="1.0" encoding="utf-8" standalone="no"?>
-<manifest xmlns:android="http://schemas.android.com/apk/res/android" android:compileSdkVersion="36" android:compileSdkVersionCodename="16" package="{package_name}" platformBuildVersionCode="36" platformBuildVersionName="16">
-    <application android:debuggable="true" android:extractNativeLibs="true"
-                {icon_attributes} android:label="{name}">
-        <meta-data android:name="xposedmodule" android:value="true"/>
-        <meta-data android:name="xposeddescription" android:value="{xposed_description}"/>
-        <meta-data android:name="xposedminversion" android:value="53"/>
-        <meta-data android:name="xposedscope" android:value="{xposed_scope}"/>
-    </application>
-</manifest>"#
-        );
-
-        fs::write(&manifest_path, manifest_content.as_bytes()).await?;
-        info!("→ Created AndroidManifest.xml: {}", manifest_path.display());
-
-        // 10. Create apktool.yml with the specified content.
-        let apktool_yml_path = temp_path.join("apktool.yml");
-        let apktool_yml_content = r#"apkFileName: app-debug.apk
-isFrameworkApk: false
-usesFramework:
-  ids:
-  - 1
-  tag: null
-sdkInfo:
-  minSdkVersion: 24
-  targetSdkVersion: 26
-packageInfo:
-  forcedPackageId: 127
-  renameManifestPackage: null
-versionInfo:
-  versionCode: 1
-  versionName: 1.0
-resourcesAreCompressed: false
-sharedLibrary: false
-sparseResources: true
-unknownFiles:
-doNotCompress:
-- resources.arsc
-- webp"#;
-
-        fs::write(&apktool_yml_path, apktool_yml_content.as_bytes()).await?;
-        info!("→ Created apktool.yml: {}", apktool_yml_path.display());
-
-        // 11. Build the APK using apktool b.
-        info!("→ Building APK with apktool b...");
-        let output = tokio::process::Command::new("apktool")
-            .arg("b")
-            .arg(temp_path.to_str().unwrap())
-            .arg("-o")
-            .arg(temp_path.join("dist").join("app-debug.apk"))
-            .output()
-            .await?;
-
-        if !output.status.success() {
-            anyhow::bail!(
-                "apktool build failed: {}",
-                String::from_utf8_lossy(&output.stderr)
+        let meta_data = |name: &str, value: XmlValue| {
+            XmlElement::new("meta-data")
+                .android_attribute("name", apk::ATTR_NAME, XmlValue::String(name.to_string()))
+                .android_attribute("value", apk::ATTR_VALUE, value)
+        };
+        let mut application = XmlElement::new("application")
+            .android_attribute("label", apk::ATTR_LABEL, XmlValue::Reference(label))
+            .android_attribute("debuggable", apk::ATTR_DEBUGGABLE, XmlValue::Bool(true))
+            .android_attribute(
+                "extractNativeLibs",
+                apk::ATTR_EXTRACT_NATIVE_LIBS,
+                XmlValue::Bool(true),
             );
+        for (attribute, resource_id, id, _, _) in &icons {
+            application =
+                application.android_attribute(attribute, *resource_id, XmlValue::Reference(*id));
+        }
+        let application = application
+            .child(meta_data("xposedmodule", XmlValue::Bool(true)))
+            .child(meta_data(
+                "xposeddescription",
+                XmlValue::String(xposed_description.to_string()),
+            ))
+            .child(meta_data("xposedminversion", XmlValue::Int(53)))
+            .child(meta_data(
+                "xposedscope",
+                XmlValue::String(xposed_scope.to_string()),
+            ));
+        let manifest = XmlElement::new("manifest")
+            .android_attribute("versionCode", apk::ATTR_VERSION_CODE, XmlValue::Int(1))
+            .android_attribute(
+                "versionName",
+                apk::ATTR_VERSION_NAME,
+                XmlValue::String("1.0".to_string()),
+            )
+            .attribute("package", XmlValue::String(package_name.clone()))
+            .child(
+                XmlElement::new("uses-sdk")
+                    .android_attribute(
+                        "minSdkVersion",
+                        apk::ATTR_MIN_SDK_VERSION,
+                        XmlValue::Int(24),
+                    )
+                    .android_attribute(
+                        "targetSdkVersion",
+                        apk::ATTR_TARGET_SDK_VERSION,
+                        XmlValue::Int(26),
+                    ),
+            )
+            .child(application);
+
+        // 7. Assemble the APK. resources.arsc and the icons are stored
+        // uncompressed and aligned.
+        let mut entries = vec![
+            ApkEntry {
+                name: "AndroidManifest.xml".to_string(),
+                data: apk::compile_xml(&manifest),
+                compress: true,
+            },
+            ApkEntry {
+                name: "classes.dex".to_string(),
+                data: classes_dex,
+                compress: true,
+            },
+            ApkEntry {
+                name: "resources.arsc".to_string(),
+                data: resources.to_bytes(),
+                compress: false,
+            },
+        ];
+        for (_, _, _, apk_path, data) in icons {
+            entries.push(ApkEntry {
+                name: apk_path,
+                data,
+                compress: false,
+            });
         }
-        info!("✓ APK built successfully with apktool b.");
+        entries.push(ApkEntry {
+            name: "assets/native_init".to_string(),
+            data: random_so_name.clone().into_bytes(),
+            compress: true,
+        });
+        entries.push(ApkEntry {
+            name: "assets/xposed_init".to_string(),
+            data: xposed_init_content.into_bytes(),
+            compress: true,
+        });
+        entries.push(ApkEntry {
+            name: format!("lib/{abi}/{random_so_name}"),
+            data: binary_data,
+            compress: true,
+        });
+        let apk_data = apk::write_apk(&entries)?;
+        info!("✓ Successfully built Xposed module: {target_name}");
 
-        // 12. Sign the APK using apksigner.
-        if sign {
-            info!("→ Signing APK with apksigner...");
-            let unsigned_apk_path = temp_path.join("dist").join("app-debug.apk");
-            let signed_apk_path = temp_path
-                .join("dist")
-                .join(format!("{base_name}-{platform}-signed.apk"));
+        let final_apk_name = format!("{base_name}-{platform}.apk");
+        let final_apk_path = std::path::Path::new(&output_dir).join(&final_apk_name);
+        std::fs::create_dir_all(output_dir)?;
 
-            let sign_config = target.sign.as_ref().unwrap();
-            let keystore = &sign_config.keystore;
-            let keystore_pass = &sign_config.keystore_pass;
-            let keystore_alias = &sign_config.keystore_alias;
+        // 8. Sign the APK using apksigner.
+        if let Some((keystore, keystore_pass, keystore_alias)) = apk_keystore {
+            info!("→ Signing APK with apksigner...");
+            let temp_dir = tempfile::tempdir()?;
+            let unsigned_apk_path = temp_dir.path().join("app-unsigned.apk");
+            fs::write(&unsigned_apk_path, &apk_data).await?;
 
             let mut command = if cfg!(target_os = "windows") {
                 let mut cmd = Command::new("cmd");
@@ -458,7 +820,7 @@ doNotCompress:
 
             let output = command
                 .arg("--out")
-                .arg(&signed_apk_path)
+                .arg(&final_apk_path)
                 .arg(&unsigned_apk_path)
                 .output()
                 .await?;
@@ -469,26 +831,15 @@ doNotCompress:
                     String::from_utf8_lossy(&output.stderr)
                 );
             }
-            info!("✓ APK signed successfully with apksigner.");
-
-            // 13. Copy the signed APK back to the desired location.
-            let final_apk_name = format!("{base_name}-{platform}.apk");
-            let final_apk_path = std::path::Path::new(&output_dir).join(&final_apk_name);
-            std::fs::create_dir_all(output_dir)?;
-            fs::copy(&signed_apk_path, &final_apk_path).await?;
-            info!("✓ Copied signed APK to: {}", final_apk_path.display());
-            Ok(final_apk_path.to_string_lossy().to_string())
+            info!(
+                "✓ APK signed successfully with apksigner: {}",
+                final_apk_path.display()
+            );
         } else {
-            // If not signing, just copy the unsigned APK
-            let unsigned_apk_path = temp_path.join("dist").join("app-debug.apk");
-            let final_apk_name = format!("{base_name}-{platform}.apk");
-            let final_apk_path = std::path::Path::new(&output_dir).join(&final_apk_name);
-            std::fs::create_dir_all(output_dir)?;
-            fs::copy(&unsigned_apk_path, &final_apk_path).await?;
-            info!("✓ Copied APK to: {}", final_apk_path.display());
-            Ok(final_apk_path.to_string_lossy().to_string())
+            fs::write(&final_apk_path, &apk_data).await?;
+            info!("✓ Wrote APK to: {}", final_apk_path.display());
         }
-
+        Ok(final_apk_path.to_string_lossy().to_string())
     }
 
     async fn build_inject_apk(&mut self, target_name: &str, target: &ResolvedTarget) -> Result<String> {
@@ -565,15 +916,11 @@ doNotCompress:
 
         info!("→ Selected target library: {}", target_lib_path.display());
 
-        // Read the target library
-        let mut target_lib_data = fs::read(&target_lib_path).await?;
-
         // Inject our library using ELF manipulation
-        let inject_lib_name = format!("lib{}.so", generate_random_string(8));
-        info!("→ Injecting library as: {}", inject_lib_name);
-        let mut processor = BinaryProcessor::new(target_lib_data.clone())?;
-        processor.add_needed_library(&inject_lib_name)?;
-        target_lib_data = processor.into_data();
+        let (processor, inject_lib_name) =
+            self.inject_apk_host(fs::read(&target_lib_path).await?)?;
+        let (target_lib_data, report) = processor.finish()?;
+        log_pipeline_report(&report, false);
 
         // Write the modified library back
         fs::write(&target_lib_path, &target_lib_data).await?;
@@ -625,6 +972,9 @@ doNotCompress:
             );
         }
         info!("✓ APK rebuilt successfully with apktool");
+        if target.seed.is_some() {
+            normalize_zip(&rebuilt_apk_path)?;
+        }
 
         // Run zipalign on the rebuilt APK
         info!("→ Aligning APK with zipalign...");
@@ -654,7 +1004,11 @@ doNotCompress:
         std::fs::create_dir_all(output_dir)?;
 
         // Sign the APK if signing configuration is provided
-        if let Some(sign_config) = &target.sign {
+        let apk_keystore = match &target.sign {
+            Some(sign_config) => sign_config.apk_keystore()?,
+            None => None,
+        };
+        if let Some((keystore, keystore_pass, keystore_alias)) = apk_keystore {
             info!("→ Signing APK...");
             let signed_apk_path = temp_path.join(format!("{base_name}-{platform}-signed.apk"));
 
@@ -670,11 +1024,11 @@ doNotCompress:
             let output = command
                 .arg("sign")
                 .arg("--ks")
-                .arg(&sign_config.keystore)
+                .arg(keystore)
                 .arg("--ks-key-alias")
-                .arg(&sign_config.keystore_alias)
+                .arg(keystore_alias)
                 .arg("--ks-pass")
-                .arg(format!("pass:{}", sign_config.keystore_pass))
+                .arg(format!("pass:{keystore_pass}"))
                 .arg("--out")
                 .arg(&signed_apk_path)
                 .arg(&rebuilt_apk_path)
@@ -702,6 +1056,247 @@ doNotCompress:
         Ok(final_apk_path.to_string_lossy().to_string())
     }
 
+    async fn build_inject_ipa(
+        &mut self,
+        target_name: &str,
+        target: &ResolvedTarget,
+    ) -> Result<String> {
+        use std::io::Write;
+
+        let base_name = target.target_base_name.as_deref().unwrap_or(target_name);
+        info!("→ Building Inject IPA target: {target_name} (base name: {base_name})");
+
+        // Get required fields
+        let platform = target
+            .platform
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Missing required field: platform"))?;
+
+        if platform.platform != Platform::IOS {
+            anyhow::bail!("Inject IPA target only supports iOS platform");
+        }
+
+        let inject_config = target
+            .inject_ipa
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Missing required field: injectIpa"))?;
+        let source_ipa_path = inject_config
+            .source_ipa_path
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Missing required field: injectIpa.sourceIpaPath"))?;
+        let adhoc_sign = inject_config.adhoc_sign.unwrap_or(false);
+
+        let output_dir = target.output_dir.as_deref().unwrap_or("./fripack");
+        let mut injected_binary_data = self.generate_binary(target).await?;
+
+        // Unpack the IPA
+        info!("→ Using source IPA path: {source_ipa_path}");
+        let mut archive =
+            zip::ZipArchive::new(std::io::Cursor::new(fs::read(source_ipa_path).await?))?;
+        let IpaExecutable {
+            app_dir,
+            path: executable_path,
+            bundle_identifier,
+            data: executable_data,
+        } = ipa_main_executable(&mut archive)?;
+        info!("→ Selected main executable: {executable_path}");
+
+        // Inject our library with a load command
+        let (processor, inject_lib_name) =
+            self.inject_ipa_host(executable_data, platform, adhoc_sign)?;
+        let (mut executable_data, report) = processor.finish()?;
+        log_pipeline_report(&report, false);
+
+        if adhoc_sign {
+            info!("→ Ad-hoc signing the executable and the injected library...");
+            if codesign::identifier(&executable_data)?.is_none() {
+                codesign::adhoc_sign(&mut executable_data, &bundle_identifier)?;
+            }
+            if codesign::identifier(&injected_binary_data)?.is_none() {
+                let identifier = inject_lib_name.trim_end_matches(".dylib");
+                codesign::adhoc_sign(&mut injected_binary_data, identifier)?;
+            }
+        }
+
+        // Repackage the IPA with the patched executable and our library
+        info!("→ Repackaging IPA...");
+        let code_signature_dir = format!("{app_dir}_CodeSignature/");
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for index in 0..archive.len() {
+            let entry = archive.by_index(index)?;
+            let name = entry.name().to_string();
+            if adhoc_sign && name.starts_with(&code_signature_dir) {
+                continue;
+            }
+            if name != executable_path {
+                writer.raw_copy_file(entry)?;
+                continue;
+            }
+            let options = zip::write::FileOptions::default()
+                .compression_method(zip::CompressionMethod::Deflated)
+                .last_modified_time(entry.last_modified())
+                .unix_permissions(entry.unix_mode().unwrap_or(0o755));
+            writer.start_file(name, options)?;
+            writer.write_all(&executable_data)?;
+        }
+        let options = zip::write::FileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated)
+            .last_modified_time(zip::DateTime::default())
+            .unix_permissions(0o755);
+        writer.start_file(format!("{app_dir}Frameworks/{inject_lib_name}"), options)?;
+        writer.write_all(&injected_binary_data)?;
+        if adhoc_sign {
+            info!("→ Removed the bundle signature in {code_signature_dir}");
+        }
+
+        let final_ipa_name = format!("{base_name}-{platform}-injected.ipa");
+        let final_ipa_path = Path::new(output_dir).join(&final_ipa_name);
+        std::fs::create_dir_all(output_dir)?;
+        fs::write(&final_ipa_path, writer.finish()?.into_inner()).await?;
+        if target.seed.is_some() {
+            normalize_zip(&final_ipa_path)?;
+        }
+        if !adhoc_sign {
+            warn!("The IPA keeps its original bundle signature, which no longer matches; re-sign it before installing");
+        }
+
+        info!(
+            "✓ Successfully built inject IPA: {}",
+            final_ipa_path.display()
+        );
+        Ok(final_ipa_path.to_string_lossy().to_string())
+    }
+
+    async fn build_inject_pe(
+        &mut self,
+        target_name: &str,
+        target: &ResolvedTarget,
+    ) -> Result<String> {
+        let base_name = target.target_base_name.as_deref().unwrap_or(target_name);
+        info!("→ Building Inject PE target: {target_name} (base name: {base_name})");
+
+        // Get required fields
+        let platform = target
+            .platform
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Missing required field: platform"))?;
+
+        if platform.platform != Platform::Windows {
+            anyhow::bail!("Inject PE target only supports Windows platform");
+        }
+
+        let inject_config = target
+            .inject_pe
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Missing required field: injectPe"))?;
+        let target_module_path = inject_pe_module_path(inject_config)?;
+
+        let output_dir = target.output_dir.as_deref().unwrap_or("./fripack");
+        let mut injected_binary_data = self.generate_binary(target).await?;
+
+        // PE imports need a symbol, use the first export of our library
+        let import_symbol = BinaryProcessor::new(injected_binary_data.clone())?
+            .export_names()?
+            .into_iter()
+            .next();
+        let (processor, inject_lib_name) = self
+            .inject_pe_host(&target_module_path, platform, import_symbol.as_deref())
+            .await?;
+        let (mut target_module_data, report) = processor.finish()?;
+        log_pipeline_report(&report, false);
+
+        if let Some(signer) = authenticode_signer(target)? {
+            signer.sign(&mut target_module_data)?;
+            signer.sign(&mut injected_binary_data)?;
+            info!(
+                "✓ Signed with Authenticode certificate: {}",
+                signer.subject()
+            );
+        }
+
+        // Write the patched module and our library next to each other
+        let module_file_name = target_module_path
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("Invalid target module path"))?;
+        let patched_module_path = Path::new(output_dir).join(module_file_name);
+        let inject_lib_path = Path::new(output_dir).join(&inject_lib_name);
+        std::fs::create_dir_all(output_dir)?;
+        fs::write(&patched_module_path, &target_module_data).await?;
+        fs::write(&inject_lib_path, &injected_binary_data).await?;
+
+        info!(
+            "→ Added import {}!{} to {}",
+            inject_lib_name,
+            import_symbol.as_deref().unwrap_or("#1"),
+            module_file_name.to_string_lossy()
+        );
+        info!("→ Wrote injected library: {}", inject_lib_path.display());
+        info!(
+            "✓ Successfully built inject PE: {}",
+            patched_module_path.display()
+        );
+        Ok(patched_module_path.to_string_lossy().to_string())
+    }
+
+    async fn build_inject_macho(
+        &mut self,
+        target_name: &str,
+        target: &ResolvedTarget,
+    ) -> Result<String> {
+        let base_name = target.target_base_name.as_deref().unwrap_or(target_name);
+        info!("→ Building Inject Mach-O target: {target_name} (base name: {base_name})");
+
+        // Get required fields
+        let platform = target
+            .platform
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Missing required field: platform"))?;
+
+        if platform.platform != Platform::MacOS {
+            anyhow::bail!("Inject Mach-O target only supports macOS platform");
+        }
+
+        let inject_config = target
+            .inject_macho
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Missing required field: injectMacho"))?;
+        let target_binary_path = inject_macho_binary_path(inject_config)?;
+
+        let output_dir = target.output_dir.as_deref().unwrap_or("./fripack");
+        let mut injected_processor = self.prepare_binary(target).await?;
+
+        let (processor, inject_lib_name) = self
+            .inject_macho_host(&target_binary_path, platform, inject_config)
+            .await?;
+        // An unsigned prebuilt is signed under the random name of its file
+        injected_processor.set_adhoc_identifier(inject_lib_name.trim_end_matches(".dylib"));
+        let injected_binary_data = finish_binary(injected_processor, target)?;
+        let (target_binary_data, report) = processor.finish()?;
+        log_pipeline_report(&report, false);
+
+        // Write the patched binary and our library next to each other
+        let binary_file_name = target_binary_path
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("Invalid target binary path"))?;
+        let patched_binary_path = Path::new(output_dir).join(binary_file_name);
+        let inject_lib_path = Path::new(output_dir).join(&inject_lib_name);
+        std::fs::create_dir_all(output_dir)?;
+        fs::write(&patched_binary_path, &target_binary_data).await?;
+        fs::write(&inject_lib_path, &injected_binary_data).await?;
+        // Keep the executable bit of the original
+        std::fs::set_permissions(
+            &patched_binary_path,
+            std::fs::metadata(&target_binary_path)?.permissions(),
+        )?;
+
+        info!("→ Wrote injected library: {}", inject_lib_path.display());
+        info!(
+            "✓ Successfully built inject Mach-O: {}",
+            patched_binary_path.display()
+        );
+        Ok(patched_binary_path.to_string_lossy().to_string())
+    }
+
     async fn build_zygisk(&mut self, target_name: &str, target: &ResolvedTarget) -> Result<String> {
         let base_name = target.target_base_name.as_deref().unwrap_or(target_name);
         info!("→ Building Zygisk target: {target_name} (base name: {base_name})");
@@ -749,6 +1344,7 @@ doNotCompress:
         let mut zip = zip::ZipWriter::new(zip_file);
         let options = zip::write::FileOptions::default()
             .compression_method(zip::CompressionMethod::Deflated)
+            .last_modified_time(zip::DateTime::default())
             .unix_permissions(0o755);
 
         use std::io::Write;
@@ -798,6 +1394,158 @@ doNotCompress:
         Ok(zip_path.to_string_lossy().to_string())
     }
 
+    async fn build_tweak_deb(
+        &mut self,
+        target_name: &str,
+        target: &ResolvedTarget,
+    ) -> Result<String> {
+        let base_name = target.target_base_name.as_deref().unwrap_or(target_name);
+        info!("→ Building tweak deb target: {target_name} (base name: {base_name})");
+
+        // Get required fields
+        let platform = target
+            .platform
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Missing required field: platform"))?;
+        let tweak_config = target
+            .tweak_deb
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Missing required field: tweakDeb"))?;
+
+        let scope = tweak_config
+            .scope
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Missing required field: tweakDeb.scope"))?;
+
+        let id = tweak_config
+            .id
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Missing required field: tweakDeb.id"))?;
+
+        let name = tweak_config
+            .name
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Missing required field: tweakDeb.name"))?;
+
+        if platform.platform != Platform::IOS {
+            anyhow::bail!("Tweak deb target only supports iOS platform");
+        }
+
+        // dpkg rejects versions that do not start with a digit
+        let version = tweak_config.version.as_deref().unwrap_or("1.0");
+        if !version.starts_with(|c: char| c.is_ascii_digit()) {
+            anyhow::bail!("tweakDeb.version must start with a digit: {version}");
+        }
+        // Debian package names: [a-z0-9][a-z0-9+.-]+
+        let valid_id = id.len() >= 2
+            && id.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+            && id
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "+.-".contains(c));
+        if !valid_id {
+            anyhow::bail!(
+                "tweakDeb.id must be a valid Debian package name (lowercase letters, digits, '+', '.' and '-', at least two characters, starting with a letter or digit): {id}"
+            );
+        }
+        let author = tweak_config.author.as_deref().unwrap_or("FriPack");
+        let description = tweak_config
+            .description
+            .as_deref()
+            .unwrap_or("A tweak created by FriPack");
+        // A line break would start a new control field
+        for (field, value) in [
+            ("name", name.as_str()),
+            ("version", version),
+            ("author", author),
+            ("description", description),
+        ] {
+            if value.contains(['\n', '\r']) {
+                anyhow::bail!("tweakDeb.{field} must be a single line: {value:?}");
+            }
+        }
+
+        let output_dir = target.output_dir.as_deref().unwrap_or("./fripack");
+        let mut binary_data = self.generate_binary(target).await?;
+        // Jailbroken devices still refuse to load unsigned code
+        if codesign::identifier(&binary_data)?.is_none() {
+            info!("→ Ad-hoc signing the tweak dylib...");
+            codesign::adhoc_sign(&mut binary_data, id)?;
+        }
+
+        // Substrate loads the dylib into processes matching the filter plist
+        let bundles = scope
+            .split(';')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|s| plist::Value::String(s.to_string()))
+            .collect::<Vec<_>>();
+        let mut filter = plist::Dictionary::new();
+        filter.insert("Bundles".to_string(), plist::Value::Array(bundles));
+        let mut filter_plist = plist::Dictionary::new();
+        filter_plist.insert("Filter".to_string(), plist::Value::Dictionary(filter));
+        let mut filter_plist_content = Vec::new();
+        plist::Value::Dictionary(filter_plist).to_writer_xml(&mut filter_plist_content)?;
+
+        // Rootless jailbreaks mount everything under /var/jb
+        let rootless = tweak_config.rootless.unwrap_or(false);
+        let (prefix, architecture) = if rootless {
+            ("./var/jb/", "iphoneos-arm64")
+        } else {
+            ("./", "iphoneos-arm")
+        };
+        let library_dir = format!("{prefix}Library/MobileSubstrate/DynamicLibraries/");
+        let dylib_path = format!("{library_dir}{id}.dylib");
+        let filter_plist_path = format!("{library_dir}{id}.plist");
+
+        let mut data_entries: Vec<(String, u32, Option<&[u8]>)> = Vec::new();
+        let mut dir = String::new();
+        for component in library_dir.split_inclusive('/') {
+            dir.push_str(component);
+            data_entries.push((dir.clone(), 0o755, None));
+        }
+        data_entries.push((dylib_path, 0o755, Some(&binary_data)));
+        data_entries.push((filter_plist_path, 0o644, Some(&filter_plist_content)));
+
+        let control_content = format!(
+            "Package: {}\nName: {}\nVersion: {}\nArchitecture: {}\nDescription: {}\nMaintainer: {}\nAuthor: {}\nSection: Tweaks\nDepends: mobilesubstrate\nInstalled-Size: {}\n",
+            id,
+            name,
+            version,
+            architecture,
+            description,
+            author,
+            author,
+            (binary_data.len() + filter_plist_content.len()).div_ceil(1024)
+        );
+        let control_entries: Vec<(String, u32, Option<&[u8]>)> = vec![
+            ("./".to_string(), 0o755, None),
+            (
+                "./control".to_string(),
+                0o644,
+                Some(control_content.as_bytes()),
+            ),
+        ];
+
+        let deb_filename = format!("{}-tweak-{}.deb", base_name, platform);
+        let deb_path = Path::new(output_dir).join(&deb_filename);
+        std::fs::create_dir_all(output_dir)?;
+
+        info!("→ Creating tweak package: {}", deb_path.display());
+        let xz = Compression::new(Codec::Xz, None)?;
+        let control_tar = xz.compress(&tar_archive(&control_entries)?)?;
+        let data_tar = xz.compress(&tar_archive(&data_entries)?)?;
+        let deb = ar_archive(&[
+            ("debian-binary", b"2.0\n"),
+            ("control.tar.xz", &control_tar),
+            ("data.tar.xz", &data_tar),
+        ]);
+        fs::write(&deb_path, deb).await?;
+
+        info!("✓ Successfully built tweak deb: {}", deb_path.display());
+
+        Ok(deb_path.to_string_lossy().to_string())
+    }
+
     async fn extract_apk_from_device(&self, package_name: &str) -> Result<PathBuf> {
         let cache_dir = Path::new("./fripack_cache").join("apks");
         std::fs::create_dir_all(&cache_dir)?;
@@ -857,6 +1605,108 @@ doNotCompress:
         Ok(cached_apk_path)
     }
 
+    /// Queue a DT_NEEDED entry for a randomly named copy of our library in
+    /// the host library of an inject-apk target.
+    fn inject_apk_host(&mut self, target_lib_data: Vec<u8>) -> Result<(BinaryProcessor, String)> {
+        let inject_lib_name = format!("lib{}.so", generate_random_string(&mut self.rng, 8));
+        info!("→ Injecting library as: {}", inject_lib_name);
+        let mut processor = BinaryProcessor::new(target_lib_data)?;
+        processor.add_needed_library(&inject_lib_name, None)?;
+        Ok((processor, inject_lib_name))
+    }
+
+    /// Check the main executable of an inject-ipa target and queue a load
+    /// command for a randomly named copy of our library.
+    fn inject_ipa_host(
+        &mut self,
+        executable_data: Vec<u8>,
+        platform: &PlatformConfig,
+        adhoc_sign: bool,
+    ) -> Result<(BinaryProcessor, String)> {
+        let mut processor = BinaryProcessor::new(executable_data)?;
+        if processor.architecture()? != object::Architecture::Aarch64 {
+            anyhow::bail!(
+                "Main executable architecture {:?} does not match platform {platform}",
+                processor.architecture()?
+            );
+        }
+
+        let inject_lib_name = format!("{}.dylib", generate_random_string(&mut self.rng, 8));
+        let install_name = format!("@executable_path/Frameworks/{inject_lib_name}");
+        info!("→ Injecting library as: {}", install_name);
+        processor.add_needed_library(&install_name, None)?;
+        // A signed executable is re-signed by `finish` with its own
+        // identifier; without adhocSign it keeps the original signature.
+        processor.set_resign(adhoc_sign);
+        Ok((processor, inject_lib_name))
+    }
+
+    /// Check the module of an inject-pe target and queue an import of
+    /// `import_symbol` from a randomly named copy of our library.
+    async fn inject_pe_host(
+        &mut self,
+        target_module_path: &Path,
+        platform: &PlatformConfig,
+        import_symbol: Option<&str>,
+    ) -> Result<(BinaryProcessor, String)> {
+        let mut processor = BinaryProcessor::new(fs::read(target_module_path).await?)?;
+        let expected_arch = match platform.arch {
+            Arch::X86 => object::Architecture::I386,
+            Arch::X86_64 => object::Architecture::X86_64,
+            _ => anyhow::bail!("Inject PE target only supports x86 and x86_64"),
+        };
+        if processor.architecture()? != expected_arch {
+            anyhow::bail!(
+                "Target module architecture {:?} does not match platform {platform}",
+                processor.architecture()?
+            );
+        }
+        if import_symbol.is_none() {
+            warn!("Generated library has no exports, importing it by ordinal 1");
+        }
+
+        let inject_lib_name = format!("{}.dll", generate_random_string(&mut self.rng, 8));
+        info!("→ Injecting library as: {}", inject_lib_name);
+        processor.add_needed_library(&inject_lib_name, import_symbol)?;
+        Ok((processor, inject_lib_name))
+    }
+
+    /// Check the binary of an inject-macho target and queue a (weak) load
+    /// command for a randomly named copy of our library.
+    async fn inject_macho_host(
+        &mut self,
+        target_binary_path: &Path,
+        platform: &PlatformConfig,
+        inject_config: &InjectMachoConfig,
+    ) -> Result<(BinaryProcessor, String)> {
+        let mut processor = BinaryProcessor::new(fs::read(target_binary_path).await?)?;
+        if let Some(file_name) = target_binary_path.file_name() {
+            processor.set_adhoc_identifier(&file_name.to_string_lossy());
+        }
+        let expected_arch = match platform.arch {
+            Arch::Arm64 => object::Architecture::Aarch64,
+            Arch::X86_64 => object::Architecture::X86_64,
+            _ => anyhow::bail!("Inject Mach-O target only supports arm64 and x86_64"),
+        };
+        if processor.architecture()? != expected_arch {
+            anyhow::bail!(
+                "Target binary architecture {:?} does not match platform {platform}",
+                processor.architecture()?
+            );
+        }
+
+        let inject_lib_name = format!("{}.dylib", generate_random_string(&mut self.rng, 8));
+        let load_path = inject_config.load_path.as_deref().unwrap_or("@loader_path");
+        let install_name = format!("{}/{inject_lib_name}", load_path.trim_end_matches('/'));
+        info!("→ Injecting library as: {}", install_name);
+        if inject_config.weak.unwrap_or(false) {
+            processor.add_weak_needed_library(&install_name)?;
+        } else {
+            processor.add_needed_library(&install_name, None)?;
+        }
+        Ok((processor, inject_lib_name))
+    }
+
     async fn find_target_library(
         &self,
         lib_dir: &Path,
@@ -866,63 +1716,625 @@ doNotCompress:
             anyhow::bail!("Library directory does not exist: {}", lib_dir.display());
         }
 
-        // If target_lib is specified, try to find it
-        if let Some(target_name) = target_lib {
-            let target_path = lib_dir.join(target_name);
-            if target_path.exists() {
-                return Ok(target_path);
-            }
-            anyhow::bail!("Target library not found: {}", target_path.display());
+        let mut libraries = Vec::new();
+        let mut entries = tokio::fs::read_dir(lib_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            libraries.push((name, entry.metadata().await?.len()));
         }
+        Ok(lib_dir.join(select_target_library(&libraries, target_lib.as_deref())?))
+    }
+}
 
-        // Search for libraries in whitelist
-        let whitelist = ["libCrashSight.so", "libBugly.so", "libmmkv.so"];
-        for lib_name in &whitelist {
-            let lib_path = lib_dir.join(lib_name);
-            if lib_path.exists() {
-                info!("→ Found whitelist library: {}", lib_name);
-                return Ok(lib_path);
-            }
+/// Pick the host library from `(file name, size)` pairs: `target_lib` if
+/// set, else a whitelisted library, else the smallest `.so`.
+fn select_target_library(libraries: &[(String, u64)], target_lib: Option<&str>) -> Result<String> {
+    // If target_lib is specified, try to find it
+    if let Some(target_name) = target_lib {
+        if libraries.iter().any(|(name, _)| name == target_name) {
+            return Ok(target_name.to_string());
         }
+        anyhow::bail!("Target library not found: {target_name}");
+    }
 
-        // If no whitelist library found, find the smallest .so file
-        warn!("No whitelist library found, searching for smallest .so file");
-        let mut entries = tokio::fs::read_dir(lib_dir).await?;
-        let mut smallest_lib: Option<(PathBuf, u64)> = None;
+    // Search for libraries in whitelist
+    let whitelist = ["libCrashSight.so", "libBugly.so", "libmmkv.so"];
+    for lib_name in &whitelist {
+        if libraries.iter().any(|(name, _)| name == lib_name) {
+            info!("→ Found whitelist library: {}", lib_name);
+            return Ok(lib_name.to_string());
+        }
+    }
 
-        while let Some(entry) = entries.next_entry().await? {
-            let path = entry.path();
-            if path.extension().and_then(|s| s.to_str()) == Some("so") {
-                let metadata = entry.metadata().await?;
-                let size = metadata.len();
-
-                if let Some((_, smallest_size)) = &smallest_lib {
-                    if size < *smallest_size {
-                        smallest_lib = Some((path, size));
-                    }
-                } else {
-                    smallest_lib = Some((path, size));
-                }
+    // If no whitelist library found, find the smallest .so file
+    warn!("No whitelist library found, searching for smallest .so file");
+    // Break ties by name, directory order is not stable
+    let smallest_lib = libraries
+        .iter()
+        .filter(|(name, _)| name.ends_with(".so"))
+        .min_by(|(a_name, a_size), (b_name, b_size)| (a_size, a_name).cmp(&(b_size, b_name)));
+    if let Some((lib_name, size)) = smallest_lib {
+        warn!("→ Selected smallest library: {lib_name} ({size} bytes)");
+        Ok(lib_name.clone())
+    } else {
+        anyhow::bail!("No .so files found in library directory");
+    }
+}
+
+/// Read the host library of an inject-apk target straight from the APK,
+/// picked like `find_target_library` does from the apktool checkout.
+/// Returns its path inside the APK and its contents.
+fn apk_target_library(
+    apk: &[u8],
+    abi: &str,
+    target_lib: Option<&str>,
+) -> Result<(String, Vec<u8>)> {
+    use std::io::Read;
+
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(apk))?;
+    let lib_dir = format!("lib/{abi}/");
+    let mut libraries = Vec::new();
+    for index in 0..archive.len() {
+        let entry = archive.by_index(index)?;
+        if let Some(name) = entry.name().strip_prefix(&lib_dir) {
+            if !name.contains('/') {
+                libraries.push((name.to_string(), entry.size()));
             }
         }
+    }
+    if libraries.is_empty() {
+        anyhow::bail!("Library directory does not exist: {lib_dir}");
+    }
 
-        if let Some((lib_path, size)) = smallest_lib {
-            warn!(
-                "→ Selected smallest library: {} ({} bytes)",
-                lib_path.display(),
-                size
-            );
-            Ok(lib_path)
-        } else {
-            anyhow::bail!("No .so files found in library directory");
-        }
+    let lib_path = format!(
+        "{lib_dir}{}",
+        select_target_library(&libraries, target_lib)?
+    );
+    let mut data = Vec::new();
+    archive.by_name(&lib_path)?.read_to_end(&mut data)?;
+    Ok((lib_path, data))
+}
+
+/// The module an inject-pe target patches: `sourcePath` itself, or
+/// `targetModule` inside it.
+fn inject_pe_module_path(inject_config: &InjectPeConfig) -> Result<PathBuf> {
+    let source_path = inject_config
+        .source_path
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Missing required field: injectPe.sourcePath"))?;
+
+    // sourcePath is either the module itself or a directory containing targetModule
+    let source_path = PathBuf::from(source_path);
+    let target_module_path = if source_path.is_dir() {
+        let target_module = inject_config.target_module.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("injectPe.targetModule is required when sourcePath is a directory")
+        })?;
+        source_path.join(target_module)
+    } else {
+        source_path
+    };
+    if !target_module_path.exists() {
+        anyhow::bail!("Target module not found: {}", target_module_path.display());
+    }
+    info!("→ Selected target module: {}", target_module_path.display());
+    Ok(target_module_path)
+}
+
+/// The binary an inject-macho target patches: `sourcePath` itself, or
+/// `targetBinary` inside it.
+fn inject_macho_binary_path(inject_config: &InjectMachoConfig) -> Result<PathBuf> {
+    let source_path = inject_config
+        .source_path
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Missing required field: injectMacho.sourcePath"))?;
+
+    // sourcePath is either the binary itself or a directory (e.g. an .app
+    // bundle) containing targetBinary
+    let source_path = PathBuf::from(source_path);
+    let target_binary_path = if source_path.is_dir() {
+        let target_binary = inject_config.target_binary.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("injectMacho.targetBinary is required when sourcePath is a directory")
+        })?;
+        source_path.join(target_binary)
+    } else {
+        source_path
+    };
+    if !target_binary_path.exists() {
+        anyhow::bail!("Target binary not found: {}", target_binary_path.display());
     }
+    info!("→ Selected target binary: {}", target_binary_path.display());
+    Ok(target_binary_path)
+}
+
+/// The main executable of the app bundle in an IPA.
+struct IpaExecutable {
+    /// `Payload/<name>.app/`
+    app_dir: String,
+    path: String,
+    bundle_identifier: String,
+    data: Vec<u8>,
 }
 
-fn generate_random_string(len: usize) -> String {
-    rand::thread_rng()
-        .sample_iter(&rand::distributions::Alphanumeric)
+/// Find the app bundle in an IPA and read its main executable.
+fn ipa_main_executable<R: std::io::Read + std::io::Seek>(
+    archive: &mut zip::ZipArchive<R>,
+) -> Result<IpaExecutable> {
+    use std::io::Read;
+
+    let app_dir = archive
+        .file_names()
+        .find_map(|name| {
+            let app_name = name.strip_prefix("Payload/")?.split('/').next()?;
+            app_name
+                .ends_with(".app")
+                .then(|| format!("Payload/{app_name}/"))
+        })
+        .ok_or_else(|| anyhow::anyhow!("No Payload/*.app bundle found in IPA"))?;
+
+    let mut info_plist = Vec::new();
+    archive
+        .by_name(&format!("{app_dir}Info.plist"))?
+        .read_to_end(&mut info_plist)?;
+    let info_plist: plist::Dictionary = plist::from_bytes(&info_plist)?;
+    let executable_name = info_plist
+        .get("CFBundleExecutable")
+        .and_then(plist::Value::as_string)
+        .ok_or_else(|| anyhow::anyhow!("Info.plist has no CFBundleExecutable"))?;
+    let bundle_identifier = info_plist
+        .get("CFBundleIdentifier")
+        .and_then(plist::Value::as_string)
+        .unwrap_or(executable_name)
+        .to_string();
+    let path = format!("{app_dir}{executable_name}");
+
+    let mut data = Vec::new();
+    archive.by_name(&path)?.read_to_end(&mut data)?;
+    Ok(IpaExecutable {
+        app_dir,
+        path,
+        bundle_identifier,
+        data,
+    })
+}
+
+/// Whether `program` is in `PATH`, as a `.bat`, `.cmd` or `.exe` on Windows.
+fn command_in_path(program: &str) -> bool {
+    let Some(path) = std::env::var_os("PATH") else {
+        return false;
+    };
+    let file_names = if cfg!(target_os = "windows") {
+        ["bat", "cmd", "exe"]
+            .iter()
+            .map(|ext| format!("{program}.{ext}"))
+            .collect()
+    } else {
+        vec![program.to_string()]
+    };
+    std::env::split_paths(&path).any(|dir| {
+        file_names
+            .iter()
+            .any(|file_name| dir.join(file_name).is_file())
+    })
+}
+
+fn generate_random_string(rng: &mut impl Rng, len: usize) -> String {
+    rng.sample_iter(&rand::distributions::Alphanumeric)
         .take(len)
         .map(char::from)
         .collect()
 }
+
+/// The Xposed entry class, equivalent to this smali:
+///
+/// ```smali
+/// .class public L<package>/<Name>;
+/// .super Ljava/lang/Object;
+/// .implements Lde/robv/android/xposed/IXposedHookLoadPackage;
+/// .implements Lde/robv/android/xposed/IXposedHookZygoteInit;
+///
+/// .method public initZygote(Lde/robv/android/xposed/IXposedHookZygoteInit$StartupParam;)V
+///     # System.load(new File(param.modulePath).getParent() + "<library_path>")
+/// .end method
+///
+/// .method public handleLoadPackage(Lde/robv/android/xposed/callbacks/XC_LoadPackage$LoadPackageParam;)V
+///     return-void
+/// .end method
+/// ```
+fn xposed_entry_class(class_descriptor: &str, library_path: &str) -> dex::Class {
+    const OBJECT: &str = "Ljava/lang/Object;";
+    const STRING: &str = "Ljava/lang/String;";
+    const FILE: &str = "Ljava/io/File;";
+    const STRING_BUILDER: &str = "Ljava/lang/StringBuilder;";
+    const STARTUP_PARAM: &str = "Lde/robv/android/xposed/IXposedHookZygoteInit$StartupParam;";
+    const LOAD_PACKAGE_PARAM: &str =
+        "Lde/robv/android/xposed/callbacks/XC_LoadPackage$LoadPackageParam;";
+
+    let append = MethodRef::new(
+        STRING_BUILDER,
+        "append",
+        Proto::new(STRING_BUILDER, &[STRING]),
+    );
+    dex::Class {
+        descriptor: class_descriptor.to_string(),
+        superclass: OBJECT.to_string(),
+        interfaces: vec![
+            "Lde/robv/android/xposed/IXposedHookLoadPackage;".to_string(),
+            "Lde/robv/android/xposed/IXposedHookZygoteInit;".to_string(),
+        ],
+        access_flags: dex::ACC_PUBLIC,
+        direct_methods: vec![dex::Method {
+            name: "<init>".to_string(),
+            proto: Proto::new("V", &[]),
+            access_flags: dex::ACC_PUBLIC | dex::ACC_CONSTRUCTOR,
+            registers: 1,
+            code: vec![
+                Instruction::InvokeDirect(
+                    vec![0],
+                    MethodRef::new(OBJECT, "<init>", Proto::new("V", &[])),
+                ),
+                Instruction::ReturnVoid,
+            ],
+        }],
+        virtual_methods: vec![
+            // v0-v2 are locals, p0 = v3, p1 = v4
+            dex::Method {
+                name: "initZygote".to_string(),
+                proto: Proto::new("V", &[STARTUP_PARAM]),
+                access_flags: dex::ACC_PUBLIC,
+                registers: 5,
+                code: vec![
+                    Instruction::IgetObject(
+                        0,
+                        4,
+                        FieldRef::new(STARTUP_PARAM, "modulePath", STRING),
+                    ),
+                    Instruction::NewInstance(1, FILE.to_string()),
+                    Instruction::InvokeDirect(
+                        vec![1, 0],
+                        MethodRef::new(FILE, "<init>", Proto::new("V", &[STRING])),
+                    ),
+                    Instruction::InvokeVirtual(
+                        vec![1],
+                        MethodRef::new(FILE, "getParent", Proto::new(STRING, &[])),
+                    ),
+                    Instruction::MoveResultObject(0),
+                    Instruction::NewInstance(1, STRING_BUILDER.to_string()),
+                    Instruction::InvokeDirect(
+                        vec![1],
+                        MethodRef::new(STRING_BUILDER, "<init>", Proto::new("V", &[])),
+                    ),
+                    Instruction::InvokeVirtual(vec![1, 0], append.clone()),
+                    Instruction::ConstString(2, library_path.to_string()),
+                    Instruction::InvokeVirtual(vec![1, 2], append),
+                    Instruction::InvokeVirtual(
+                        vec![1],
+                        MethodRef::new(STRING_BUILDER, "toString", Proto::new(STRING, &[])),
+                    ),
+                    Instruction::MoveResultObject(1),
+                    Instruction::InvokeStatic(
+                        vec![1],
+                        MethodRef::new("Ljava/lang/System;", "load", Proto::new("V", &[STRING])),
+                    ),
+                    Instruction::ReturnVoid,
+                ],
+            },
+            dex::Method {
+                name: "handleLoadPackage".to_string(),
+                proto: Proto::new("V", &[LOAD_PACKAGE_PARAM]),
+                access_flags: dex::ACC_PUBLIC,
+                registers: 2,
+                code: vec![Instruction::ReturnVoid],
+            },
+        ],
+    }
+}
+
+/// The signer for `sign.authenticode`, if set and the target builds for
+/// Windows.
+fn authenticode_signer(target: &ResolvedTarget) -> Result<Option<AuthenticodeSigner>> {
+    let Some(authenticode) = target
+        .sign
+        .as_ref()
+        .and_then(|sign| sign.authenticode.as_ref())
+    else {
+        return Ok(None);
+    };
+    if target
+        .platform
+        .as_ref()
+        .is_none_or(|platform| platform.platform != Platform::Windows)
+    {
+        warn!("sign.authenticode only applies to Windows targets, skipping");
+        return Ok(None);
+    }
+
+    info!("→ Loading Authenticode certificate: {}", authenticode.pfx);
+    let password = authenticode.resolve_password()?;
+    Ok(Some(AuthenticodeSigner::from_pfx(
+        Path::new(&authenticode.pfx),
+        &password,
+    )?))
+}
+
+fn finish_binary(processor: BinaryProcessor, target: &ResolvedTarget) -> Result<Vec<u8>> {
+    let (output_data, report) = processor.finish()?;
+    log_pipeline_report(&report, show_keyword_locations(target));
+    Ok(output_data)
+}
+
+/// The version resource of a Windows shared library, named like the file
+/// `build_shared` writes.
+fn shared_version_info(target_name: &str, target: &ResolvedTarget) -> Option<VersionInfo> {
+    let Some(platform) = target
+        .platform
+        .as_ref()
+        .filter(|platform| platform.platform == Platform::Windows)
+    else {
+        return None;
+    };
+    let base_name = target.target_base_name.as_deref().unwrap_or(target_name);
+    let version = target
+        .version
+        .clone()
+        .unwrap_or_else(|| "0.0.0".to_string());
+
+    let mut strings = vec![
+        ("FileVersion".to_string(), version.clone()),
+        ("InternalName".to_string(), base_name.to_string()),
+        (
+            "OriginalFilename".to_string(),
+            format!("{base_name}-{platform}.{}", platform.platform.binary_ext()),
+        ),
+        ("ProductName".to_string(), base_name.to_string()),
+        ("ProductVersion".to_string(), version.clone()),
+    ];
+    for (key, value) in [
+        ("CompanyName", &target.company_name),
+        ("FileDescription", &target.file_description),
+        ("LegalCopyright", &target.copyright),
+    ] {
+        if let Some(value) = value {
+            strings.push((key.to_string(), value.clone()));
+        }
+    }
+
+    Some(VersionInfo {
+        version,
+        file_type_dll: true,
+        strings,
+    })
+}
+
+fn show_keyword_locations(target: &ResolvedTarget) -> bool {
+    target
+        .anti_detection
+        .as_ref()
+        .and_then(|anti_detection| anti_detection.report)
+        .unwrap_or(false)
+}
+
+fn log_pipeline_report(report: &PipelineReport, show_locations: bool) {
+    for change in &report.changes {
+        info!("  {change}");
+    }
+    for keyword in report.keywords.iter().filter(|r| !r.occurrences.is_empty()) {
+        info!(
+            "  {} → {}: {} occurrence(s)",
+            keyword.keyword,
+            keyword.replacement,
+            keyword.occurrences.len()
+        );
+        if show_locations {
+            for (section, offset) in &keyword.occurrences {
+                info!("    {section}+{offset:#x}");
+            }
+        }
+    }
+}
+
+/// A ustar archive of `(path, mode, contents)` entries, where entries
+/// without contents are directories. Owners and timestamps are fixed so
+/// the output is reproducible.
+fn tar_archive(entries: &[(String, u32, Option<&[u8]>)]) -> Result<Vec<u8>> {
+    const BLOCK_SIZE: usize = 512;
+
+    fn put(header: &mut [u8], offset: usize, value: &[u8]) {
+        header[offset..offset + value.len()].copy_from_slice(value);
+    }
+
+    let mut archive = Vec::new();
+    for (path, mode, contents) in entries {
+        if path.len() > 100 {
+            anyhow::bail!("Path is too long for a tar entry: {path}");
+        }
+        let size = contents.map_or(0, |contents| contents.len());
+        let mut header = [0u8; BLOCK_SIZE];
+        put(&mut header, 0, path.as_bytes());
+        put(&mut header, 100, format!("{mode:07o}\0").as_bytes());
+        put(&mut header, 108, b"0000000\0");
+        put(&mut header, 116, b"0000000\0");
+        put(&mut header, 124, format!("{size:011o}\0").as_bytes());
+        put(&mut header, 136, b"00000000000\0");
+        put(&mut header, 148, b"        ");
+        header[156] = if contents.is_some() { b'0' } else { b'5' };
+        put(&mut header, 257, b"ustar\0");
+        put(&mut header, 263, b"00");
+        put(&mut header, 265, b"root");
+        put(&mut header, 297, b"wheel");
+        let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+        put(&mut header, 148, format!("{checksum:06o}\0 ").as_bytes());
+
+        archive.extend_from_slice(&header);
+        if let Some(contents) = contents {
+            archive.extend_from_slice(contents);
+            archive.resize(archive.len().next_multiple_of(BLOCK_SIZE), 0);
+        }
+    }
+    // Two zero blocks mark the end of the archive
+    archive.resize(archive.len() + 2 * BLOCK_SIZE, 0);
+    Ok(archive)
+}
+
+/// A `.deb` style ar archive of `(name, contents)` members.
+fn ar_archive(members: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut archive = b"!<arch>\n".to_vec();
+    for (name, contents) in members {
+        let header = format!(
+            "{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+            name,
+            0,
+            0,
+            0,
+            100644,
+            contents.len()
+        );
+        archive.extend_from_slice(header.as_bytes());
+        archive.extend_from_slice(contents);
+        // Members are aligned to 2 bytes
+        if contents.len() % 2 == 1 {
+            archive.push(b'\n');
+        }
+    }
+    archive
+}
+
+/// Rewrite a zip with entries sorted by name and a fixed timestamp, keeping
+/// each entry's compression method.
+fn normalize_zip(path: &Path) -> Result<()> {
+    use std::io::{Read, Write};
+
+    info!("→ Normalizing zip entries: {}", path.display());
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(std::fs::read(path)?))?;
+    let mut names: Vec<String> = archive.file_names().map(str::to_string).collect();
+    names.sort();
+
+    let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    for name in names {
+        let mut entry = archive.by_name(&name)?;
+        let mut options = zip::write::FileOptions::default()
+            .compression_method(entry.compression())
+            .last_modified_time(zip::DateTime::default());
+        if let Some(mode) = entry.unix_mode() {
+            options = options.unix_permissions(mode);
+        }
+        if entry.is_dir() {
+            writer.add_directory(name, options)?;
+            continue;
+        }
+        let mut data = Vec::with_capacity(entry.size() as usize);
+        entry.read_to_end(&mut data)?;
+        writer.start_file(name, options)?;
+        writer.write_all(&data)?;
+    }
+
+    std::fs::write(path, writer.finish()?.into_inner())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{read_uleb128, u16_at, u32_at};
+    use sha1::{Digest, Sha1};
+
+    #[test]
+    fn xposed_entry_class_dex_parses_back() {
+        let library_path = "/lib/arm64/libfripack.so";
+        let dex = dex::write_dex(&xposed_entry_class("Lcom/example/Entry;", library_path)).unwrap();
+
+        assert_eq!(&dex[..8], b"dex\n035\0");
+        assert_eq!(u32_at(&dex, 32) as usize, dex.len());
+        assert_eq!(u32_at(&dex, 36), 0x70);
+        assert_eq!(u32_at(&dex, 40), 0x12345678);
+        let data_size = u32_at(&dex, 104) as usize;
+        let data_off = u32_at(&dex, 108) as usize;
+        assert_eq!(data_off + data_size, dex.len());
+
+        // Adler-32 over everything after the checksum, SHA-1 after the signature
+        let (mut a, mut b) = (1u32, 0u32);
+        for &byte in &dex[12..] {
+            a = (a + byte as u32) % 65521;
+            b = (b + a) % 65521;
+        }
+        assert_eq!(u32_at(&dex, 8), b << 16 | a);
+        assert_eq!(&dex[12..32], Sha1::digest(&dex[32..]).as_slice());
+
+        // The map list is sorted by offset, covers the header and agrees with
+        // the ID section counts and offsets in the header
+        let map_off = u32_at(&dex, 52) as usize;
+        let map: Vec<(u16, u32, u32)> = (0..u32_at(&dex, map_off) as usize)
+            .map(|index| {
+                let item = map_off + 4 + index * 12;
+                (
+                    u16_at(&dex, item),
+                    u32_at(&dex, item + 4),
+                    u32_at(&dex, item + 8),
+                )
+            })
+            .collect();
+        assert_eq!(map[0], (0x0000, 1, 0));
+        assert!(map.windows(2).all(|pair| pair[0].2 < pair[1].2), "{map:x?}");
+        assert!(map
+            .iter()
+            .all(|&(_, _, offset)| (offset as usize) < dex.len()));
+        assert!(map.contains(&(0x1000, 1, map_off as u32)));
+        for (item_type, header_offset) in [
+            (0x0001, 56),
+            (0x0002, 64),
+            (0x0003, 72),
+            (0x0004, 80),
+            (0x0005, 88),
+            (0x0006, 96),
+        ] {
+            let count = u32_at(&dex, header_offset);
+            let offset = u32_at(&dex, header_offset + 4);
+            assert!(map.contains(&(item_type, count, offset)), "{item_type:#x}");
+        }
+
+        // Strings are sorted and hold the library path
+        let string_ids_off = u32_at(&dex, 60) as usize;
+        let strings: Vec<String> = (0..u32_at(&dex, 56) as usize)
+            .map(|index| {
+                let mut at = u32_at(&dex, string_ids_off + index * 4) as usize;
+                let len = read_uleb128(&dex, &mut at) as usize;
+                let end = at + dex[at..].iter().position(|&byte| byte == 0).unwrap();
+                let string = String::from_utf8(dex[at..end].to_vec()).unwrap();
+                assert_eq!(string.encode_utf16().count(), len);
+                string
+            })
+            .collect();
+        assert!(strings.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(strings.iter().any(|string| string == library_path));
+
+        // One public class extending Object, with <init> and two virtual methods
+        let type_ids_off = u32_at(&dex, 68) as usize;
+        let type_name =
+            |index: u32| &strings[u32_at(&dex, type_ids_off + index as usize * 4) as usize];
+        let class_def = u32_at(&dex, 100) as usize;
+        assert_eq!(u32_at(&dex, 96), 1);
+        assert_eq!(type_name(u32_at(&dex, class_def)), "Lcom/example/Entry;");
+        assert_eq!(u32_at(&dex, class_def + 4), dex::ACC_PUBLIC);
+        assert_eq!(type_name(u32_at(&dex, class_def + 8)), "Ljava/lang/Object;");
+        let interfaces_off = u32_at(&dex, class_def + 12) as usize;
+        assert_eq!(u32_at(&dex, interfaces_off), 2);
+
+        let mut at = u32_at(&dex, class_def + 24) as usize;
+        let counts: Vec<u32> = (0..4).map(|_| read_uleb128(&dex, &mut at)).collect();
+        assert_eq!(counts, [0, 0, 1, 2]);
+        let method_ids_off = u32_at(&dex, 92) as usize;
+        let mut names = Vec::new();
+        for methods in [1, 2] {
+            let mut method_index = 0;
+            for _ in 0..methods {
+                method_index += read_uleb128(&dex, &mut at);
+                read_uleb128(&dex, &mut at);
+                let code_off = read_uleb128(&dex, &mut at) as usize;
+                assert_eq!(code_off % 4, 0);
+                assert!(code_off >= data_off && code_off < map_off);
+                let method_id = method_ids_off + method_index as usize * 8;
+                names.push(strings[u32_at(&dex, method_id + 4) as usize].as_str());
+            }
+        }
+        assert_eq!(names, ["<init>", "handleLoadPackage", "initZygote"]);
+    }
+}
@@ -0,0 +1,126 @@
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::info;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::path::Path;
+
+/// Version of the bundle layout, bumped on incompatible changes.
+pub const BUNDLE_VERSION: u32 = 1;
+
+/// A set of modules and assets embedded in the payload. Paths are relative to
+/// the bundle root and always use `/`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bundle {
+    pub version: u32,
+    /// Path of the module to run first.
+    pub entry: String,
+    pub files: Vec<BundleFile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BundleFile {
+    pub path: String,
+    #[serde(
+        serialize_with = "serialize_base64",
+        deserialize_with = "deserialize_base64"
+    )]
+    pub data: Vec<u8>,
+}
+
+impl Bundle {
+    /// Collect all files under `root` matching one of `patterns`.
+    pub fn collect(root: &Path, patterns: &[String], entry: &str) -> Result<Self> {
+        let mut files = Vec::new();
+        for dir_entry in walkdir::WalkDir::new(root).sort_by_file_name() {
+            let dir_entry = dir_entry?;
+            if !dir_entry.file_type().is_file() {
+                continue;
+            }
+            let path = bundle_path(root, dir_entry.path())?;
+            if !patterns.iter().any(|pattern| glob_match(pattern, &path)) {
+                continue;
+            }
+            let data = std::fs::read(dir_entry.path())
+                .with_context(|| format!("Failed to read {}", dir_entry.path().display()))?;
+            files.push(BundleFile { path, data });
+        }
+
+        let entry = entry.trim_start_matches("./").replace('\\', "/");
+        if !files.iter().any(|file| file.path == entry) {
+            anyhow::bail!(
+                "Bundle entry {entry} is not part of the bundle rooted at {}",
+                root.display()
+            );
+        }
+
+        let total: usize = files.iter().map(|file| file.data.len()).sum();
+        info!(
+            "→ Bundled {} files ({} bytes), entry: {}",
+            files.len(),
+            total,
+            entry
+        );
+
+        Ok(Self {
+            version: BUNDLE_VERSION,
+            entry,
+            files,
+        })
+    }
+
+    pub fn entry_file(&self) -> Option<&BundleFile> {
+        self.files.iter().find(|file| file.path == self.entry)
+    }
+}
+
+/// `path` relative to `root`, with `/` separators.
+pub fn bundle_path(root: &Path, path: &Path) -> Result<String> {
+    let relative = path
+        .strip_prefix(root)
+        .with_context(|| format!("{} is outside {}", path.display(), root.display()))?;
+    Ok(relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/"))
+}
+
+/// Match `path` against a glob where `*` and `?` stay within one path
+/// segment and `**` spans any number of segments.
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<&str> = pattern.trim_start_matches("./").split('/').collect();
+    let path: Vec<&str> = path.split('/').collect();
+    match_segments(&pattern, &path)
+}
+
+fn match_segments(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|skip| match_segments(rest, &path[skip..])),
+        Some((segment, rest)) => match path.split_first() {
+            Some((name, path_rest)) => {
+                match_segment(segment.as_bytes(), name.as_bytes())
+                    && match_segments(rest, path_rest)
+            }
+            None => false,
+        },
+    }
+}
+
+fn match_segment(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| match_segment(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && match_segment(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && match_segment(rest, &name[1..]),
+    }
+}
+
+fn serialize_base64<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&STANDARD.encode(data))
+}
+
+fn deserialize_base64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let encoded = String::deserialize(deserializer)?;
+    STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+}
@@ -0,0 +1,300 @@
+use anyhow::{Context, Result};
+use object::{
+    macho::{LinkeditDataCommand, SegmentCommand64, LC_CODE_SIGNATURE, MH_EXECUTE},
+    pod, LittleEndian as LE,
+};
+use sha2::{Digest, Sha256};
+
+use crate::macho;
+
+const CSMAGIC_EMBEDDED_SIGNATURE: u32 = 0xfade0cc0;
+const CSMAGIC_CODEDIRECTORY: u32 = 0xfade0c02;
+const CSMAGIC_REQUIREMENTS: u32 = 0xfade0c01;
+const CSMAGIC_BLOBWRAPPER: u32 = 0xfade0b01;
+
+const CSSLOT_CODEDIRECTORY: u32 = 0;
+const CSSLOT_REQUIREMENTS: u32 = 2;
+const CSSLOT_ENTITLEMENTS: u32 = 5;
+const CSSLOT_DER_ENTITLEMENTS: u32 = 7;
+const CSSLOT_SIGNATURESLOT: u32 = 0x10000;
+
+const CS_ADHOC: u32 = 0x2;
+const CS_HASHTYPE_SHA256: u8 = 2;
+const CS_EXECSEG_MAIN_BINARY: u64 = 0x1;
+/// Version with the executable segment fields.
+const CODEDIRECTORY_VERSION: u32 = 0x20400;
+const CODEDIRECTORY_HEADER_SIZE: usize = 88;
+
+/// Code pages are hashed in 4K chunks on every architecture.
+const PAGE_SIZE_LOG2: u8 = 12;
+const PAGE_SIZE: usize = 1 << PAGE_SIZE_LOG2;
+const HASH_SIZE: usize = 32;
+
+/// File offset and size of the signature blob `LC_CODE_SIGNATURE` points
+/// to, and the offset of the command itself.
+fn code_signature(data: &[u8]) -> Result<Option<(usize, usize, usize)>> {
+    let Some(command) = macho::load_commands(data)?
+        .into_iter()
+        .find(|command| command.cmd == LC_CODE_SIGNATURE)
+    else {
+        return Ok(None);
+    };
+    let (signature, _) = pod::from_bytes::<LinkeditDataCommand<LE>>(&data[command.offset..])
+        .map_err(|_| anyhow::anyhow!("LC_CODE_SIGNATURE is truncated"))?;
+    let dataoff = signature.dataoff.get(LE) as usize;
+    let datasize = signature.datasize.get(LE) as usize;
+    if dataoff + datasize > data.len() {
+        anyhow::bail!("Code signature is out of range");
+    }
+    Ok(Some((command.offset, dataoff, datasize)))
+}
+
+fn read_u32_be(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Slot and offset of every blob in the index of an embedded signature.
+fn blob_index(blob: &[u8]) -> Result<Vec<(u32, usize)>> {
+    if read_u32_be(blob, 0) != Some(CSMAGIC_EMBEDDED_SIGNATURE) {
+        anyhow::bail!("Code signature has an unknown format");
+    }
+    let count = read_u32_be(blob, 8).context("Code signature is truncated")?;
+    (0..count as usize)
+        .map(|index| {
+            let slot = read_u32_be(blob, 12 + index * 8).context("Code signature is truncated")?;
+            let offset =
+                read_u32_be(blob, 16 + index * 8).context("Code signature is truncated")?;
+            Ok((slot, offset as usize))
+        })
+        .collect()
+}
+
+/// Identifier of the existing code signature, if the binary has one.
+pub fn identifier(data: &[u8]) -> Result<Option<String>> {
+    let Some((_, dataoff, datasize)) = code_signature(data)? else {
+        return Ok(None);
+    };
+    let blob = &data[dataoff..dataoff + datasize];
+    for (slot, offset) in blob_index(blob)? {
+        if slot != CSSLOT_CODEDIRECTORY {
+            continue;
+        }
+        let code_directory = blob
+            .get(offset..)
+            .context("Code directory is out of range")?;
+        let ident_offset =
+            read_u32_be(code_directory, 20).context("Code directory is truncated")? as usize;
+        let ident = code_directory
+            .get(ident_offset..)
+            .context("Code directory identifier is out of range")?;
+        let len = ident.iter().position(|&b| b == 0).unwrap_or(ident.len());
+        return Ok(Some(String::from_utf8_lossy(&ident[..len]).to_string()));
+    }
+    anyhow::bail!("Code signature has no code directory")
+}
+
+/// Entitlement blobs (XML and DER) of the existing code signature with
+/// their slots, so that a new signature grants the same entitlements.
+fn entitlement_blobs(data: &[u8]) -> Result<Vec<(u32, Vec<u8>)>> {
+    let Some((_, dataoff, datasize)) = code_signature(data)? else {
+        return Ok(Vec::new());
+    };
+    let blob = &data[dataoff..dataoff + datasize];
+    let mut entitlements = Vec::new();
+    for (slot, offset) in blob_index(blob)? {
+        if slot != CSSLOT_ENTITLEMENTS && slot != CSSLOT_DER_ENTITLEMENTS {
+            continue;
+        }
+        let length = read_u32_be(blob, offset + 4).context("Entitlements blob is truncated")?;
+        let contents = blob
+            .get(offset..offset + length as usize)
+            .context("Entitlements blob is out of range")?;
+        entitlements.push((slot, contents.to_vec()));
+    }
+    entitlements.sort();
+    Ok(entitlements)
+}
+
+/// Add an empty `LC_CODE_SIGNATURE` pointing past the end of `__LINKEDIT`.
+fn add_code_signature_command(data: &mut Vec<u8>) -> Result<()> {
+    let linkedit = macho::segments(data)?
+        .into_iter()
+        .find(|segment| segment.name == "__LINKEDIT")
+        .context("Binary has no __LINKEDIT segment")?;
+    let dataoff = (linkedit.fileoff + linkedit.filesize).next_multiple_of(16);
+
+    let command_size = std::mem::size_of::<LinkeditDataCommand<LE>>();
+    let mut command = vec![0u8; command_size];
+    {
+        let (signature, _) = pod::from_bytes_mut::<LinkeditDataCommand<LE>>(&mut command)
+            .map_err(|_| anyhow::anyhow!("Failed to build LC_CODE_SIGNATURE"))?;
+        signature.cmd.set(LE, LC_CODE_SIGNATURE);
+        signature.cmdsize.set(LE, command_size as u32);
+        signature.dataoff.set(LE, dataoff as u32);
+    }
+    macho::insert_load_command(data, &command, None).context("Failed to add LC_CODE_SIGNATURE")?;
+
+    let (segment, _) =
+        pod::from_bytes_mut::<SegmentCommand64<LE>>(&mut data[linkedit.cmd_offset..])
+            .map_err(|_| anyhow::anyhow!("Mach-O segment command is truncated"))?;
+    segment.filesize.set(LE, dataoff - linkedit.fileoff);
+    data.resize(dataoff as usize, 0);
+    Ok(())
+}
+
+/// Replace the code signature with an ad-hoc one for `identifier`: a code
+/// directory of SHA-256 page hashes, an empty requirement set and an empty
+/// CMS blob, as `codesign -s -` writes. Entitlements of the old signature
+/// are carried over. The signature has to be the last thing in
+/// `__LINKEDIT`. Unsigned binaries get an `LC_CODE_SIGNATURE` command,
+/// which needs 16 bytes of header padding.
+pub fn adhoc_sign(data: &mut Vec<u8>, identifier: &str) -> Result<()> {
+    let entitlements = entitlement_blobs(data)?;
+    if code_signature(data)?.is_none() {
+        add_code_signature_command(data)?;
+    }
+    let (command_offset, dataoff, datasize) =
+        code_signature(data)?.context("Binary has no LC_CODE_SIGNATURE")?;
+    let segments = macho::segments(data)?;
+    let linkedit = segments
+        .iter()
+        .find(|segment| segment.name == "__LINKEDIT")
+        .context("Binary has no __LINKEDIT segment")?;
+    let linkedit_end = (linkedit.fileoff + linkedit.filesize) as usize;
+    if dataoff < linkedit.fileoff as usize || dataoff + datasize > linkedit_end {
+        anyhow::bail!("Code signature is not at the end of __LINKEDIT");
+    }
+    let (exec_seg_base, exec_seg_limit) = segments
+        .iter()
+        .find(|segment| segment.name == "__TEXT")
+        .map(|segment| (segment.fileoff, segment.filesize))
+        .unwrap_or_default();
+    let exec_seg_flags = if macho::header(data)?.filetype.get(LE) == MH_EXECUTE {
+        CS_EXECSEG_MAIN_BINARY
+    } else {
+        0
+    };
+
+    // The load commands are covered by the page hashes, so the final size
+    // goes in first.
+    let code_limit = dataoff;
+    let page_count = code_limit.div_ceil(PAGE_SIZE);
+    let requirements = blob(CSMAGIC_REQUIREMENTS, &0u32.to_be_bytes());
+    let cms = blob(CSMAGIC_BLOBWRAPPER, &[]);
+    let mut blobs = vec![
+        (CSSLOT_CODEDIRECTORY, Vec::new()),
+        (CSSLOT_REQUIREMENTS, requirements),
+    ];
+    blobs.extend(entitlements);
+    blobs.push((CSSLOT_SIGNATURESLOT, cms));
+
+    // Special slots run down to the highest one in use; Info.plist (-1) and
+    // the others without a blob are left empty.
+    let special_slots = blobs
+        .iter()
+        .map(|(slot, _)| *slot)
+        .filter(|&slot| slot < CSSLOT_SIGNATURESLOT)
+        .max()
+        .unwrap_or(CSSLOT_REQUIREMENTS) as usize;
+    let ident_offset = CODEDIRECTORY_HEADER_SIZE;
+    let hash_offset = ident_offset + identifier.len() + 1 + special_slots * HASH_SIZE;
+    let code_directory_size = hash_offset + page_count * HASH_SIZE;
+    let blobs_offset = 12 + blobs.len() * 8;
+    let signature_size = blobs_offset
+        + code_directory_size
+        + blobs
+            .iter()
+            .map(|(_, contents)| contents.len())
+            .sum::<usize>();
+    let new_datasize = signature_size.next_multiple_of(16);
+
+    {
+        let (command, _) =
+            pod::from_bytes_mut::<LinkeditDataCommand<LE>>(&mut data[command_offset..])
+                .map_err(|_| anyhow::anyhow!("LC_CODE_SIGNATURE is truncated"))?;
+        command.datasize.set(LE, new_datasize as u32);
+    }
+    let filesize = (dataoff + new_datasize) as u64 - linkedit.fileoff;
+    let (segment, _) =
+        pod::from_bytes_mut::<SegmentCommand64<LE>>(&mut data[linkedit.cmd_offset..])
+            .map_err(|_| anyhow::anyhow!("Mach-O segment command is truncated"))?;
+    segment.filesize.set(LE, filesize);
+    segment.vmsize.set(
+        LE,
+        linkedit
+            .vmsize
+            .max(filesize.next_multiple_of(macho::SEGMENT_ALIGN)),
+    );
+    data.resize(dataoff, 0);
+
+    let mut code_directory = Vec::with_capacity(code_directory_size);
+    for value in [
+        CSMAGIC_CODEDIRECTORY,
+        code_directory_size as u32,
+        CODEDIRECTORY_VERSION,
+        CS_ADHOC,
+        hash_offset as u32,
+        ident_offset as u32,
+        special_slots as u32,
+        page_count as u32,
+        code_limit as u32,
+    ] {
+        code_directory.extend_from_slice(&value.to_be_bytes());
+    }
+    // hashSize, hashType, platform, pageSize
+    code_directory.extend_from_slice(&[HASH_SIZE as u8, CS_HASHTYPE_SHA256, 0, PAGE_SIZE_LOG2]);
+    // spare2, scatterOffset, teamOffset, spare3
+    code_directory.extend_from_slice(&[0; 16]);
+    for value in [0, exec_seg_base, exec_seg_limit, exec_seg_flags] {
+        code_directory.extend_from_slice(&value.to_be_bytes());
+    }
+    debug_assert_eq!(code_directory.len(), CODEDIRECTORY_HEADER_SIZE);
+    code_directory.extend_from_slice(identifier.as_bytes());
+    code_directory.push(0);
+    // Special slots are stored in reverse, ending right before slot 0.
+    for special_slot in (1..=special_slots as u32).rev() {
+        match blobs.iter().find(|(slot, _)| *slot == special_slot) {
+            Some((_, contents)) => code_directory.extend_from_slice(&Sha256::digest(contents)),
+            None => code_directory.extend_from_slice(&[0; HASH_SIZE]),
+        }
+    }
+    for page in data[..code_limit].chunks(PAGE_SIZE) {
+        code_directory.extend_from_slice(&Sha256::digest(page));
+    }
+    debug_assert_eq!(code_directory.len(), code_directory_size);
+
+    blobs[0].1 = code_directory;
+
+    let mut signature = Vec::with_capacity(new_datasize);
+    for value in [
+        CSMAGIC_EMBEDDED_SIGNATURE,
+        signature_size as u32,
+        blobs.len() as u32,
+    ] {
+        signature.extend_from_slice(&value.to_be_bytes());
+    }
+    let mut offset = blobs_offset;
+    for (slot, contents) in &blobs {
+        signature.extend_from_slice(&slot.to_be_bytes());
+        signature.extend_from_slice(&(offset as u32).to_be_bytes());
+        offset += contents.len();
+    }
+    for (_, contents) in &blobs {
+        signature.extend_from_slice(contents);
+    }
+    signature.resize(new_datasize, 0);
+
+    data.extend_from_slice(&signature);
+    Ok(())
+}
+
+/// A generic blob: magic, total length, then `contents`.
+fn blob(magic: u32, contents: &[u8]) -> Vec<u8> {
+    let mut blob = Vec::with_capacity(8 + contents.len());
+    blob.extend_from_slice(&magic.to_be_bytes());
+    blob.extend_from_slice(&(8 + contents.len() as u32).to_be_bytes());
+    blob.extend_from_slice(contents);
+    blob
+}
@@ -0,0 +1,126 @@
+use anyhow::Result;
+use std::io::{Read, Write};
+
+/// Codec id stored in the `codec` byte of `EmbeddedConfig`. `None` and `Xz`
+/// keep the values of the old `data_xz` flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Codec {
+    None = 0,
+    Xz = 1,
+    Zstd = 2,
+    Brotli = 3,
+}
+
+impl Codec {
+    pub const ALL: [Codec; 4] = [Codec::None, Codec::Xz, Codec::Zstd, Codec::Brotli];
+
+    pub fn from_id(id: u8) -> Result<Self> {
+        match id {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Xz),
+            2 => Ok(Codec::Zstd),
+            3 => Ok(Codec::Brotli),
+            _ => anyhow::bail!("Unknown payload codec: {id}"),
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "none" => Ok(Codec::None),
+            "xz" => Ok(Codec::Xz),
+            "zstd" => Ok(Codec::Zstd),
+            "brotli" => Ok(Codec::Brotli),
+            _ => anyhow::bail!("Unsupported compression codec: {name}"),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::None => "none",
+            Codec::Xz => "xz",
+            Codec::Zstd => "zstd",
+            Codec::Brotli => "brotli",
+        }
+    }
+
+    pub fn default_level(&self) -> u32 {
+        match self {
+            Codec::None => 0,
+            Codec::Xz => 6,
+            Codec::Zstd => 19,
+            Codec::Brotli => 11,
+        }
+    }
+
+    fn max_level(&self) -> u32 {
+        match self {
+            Codec::None => 0,
+            Codec::Xz => 9,
+            Codec::Zstd => 22,
+            Codec::Brotli => 11,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Compression {
+    pub codec: Codec,
+    pub level: u32,
+}
+
+impl Compression {
+    pub const NONE: Compression = Compression {
+        codec: Codec::None,
+        level: 0,
+    };
+
+    pub fn new(codec: Codec, level: Option<u32>) -> Result<Self> {
+        let level = level.unwrap_or(codec.default_level());
+        if level > codec.max_level() {
+            anyhow::bail!(
+                "Compression level {level} is out of range for {} (max {})",
+                codec.name(),
+                codec.max_level()
+            );
+        }
+        Ok(Self { codec, level })
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self.codec {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Xz => {
+                let mut encoder = xz2::write::XzEncoder::new(Vec::new(), self.level);
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+            Codec::Zstd => Ok(zstd::bulk::compress(data, self.level as i32)?),
+            Codec::Brotli => {
+                let mut out = Vec::new();
+                {
+                    let mut encoder = brotli::CompressorWriter::new(&mut out, 4096, self.level, 22);
+                    encoder.write_all(data)?;
+                }
+                Ok(out)
+            }
+        }
+    }
+}
+
+pub fn decompress(codec: Codec, data: &[u8]) -> Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    match codec {
+        Codec::None => decoded.extend_from_slice(data),
+        Codec::Xz => {
+            xz2::read::XzDecoder::new(data).read_to_end(&mut decoded)?;
+        }
+        Codec::Zstd => {
+            zstd::stream::read::Decoder::new(data)?.read_to_end(&mut decoded)?;
+        }
+        Codec::Brotli => {
+            brotli::Decompressor::new(data, 4096).read_to_end(&mut decoded)?;
+        }
+    }
+    Ok(decoded)
+}
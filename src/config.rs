@@ -14,11 +14,111 @@ macro_rules! merge_fields {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SignConfig {
-    pub keystore: String,
+    /// APK signing keystore for apksigner
+    pub keystore: Option<String>,
     #[serde(rename = "keystorePass")]
-    pub keystore_pass: String,
+    pub keystore_pass: Option<String>,
     #[serde(rename = "keystoreAlias")]
-    pub keystore_alias: String,
+    pub keystore_alias: Option<String>,
+    /// Authenticode signing of Windows outputs
+    pub authenticode: Option<AuthenticodeConfig>,
+}
+
+impl SignConfig {
+    /// Keystore, password and alias for apksigner, if a keystore is set.
+    pub fn apk_keystore(&self) -> Result<Option<(&str, &str, &str)>> {
+        let Some(keystore) = &self.keystore else {
+            return Ok(None);
+        };
+        let keystore_pass = self
+            .keystore_pass
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("Missing required field: sign.keystorePass"))?;
+        let keystore_alias = self
+            .keystore_alias
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("Missing required field: sign.keystoreAlias"))?;
+        Ok(Some((keystore, keystore_pass, keystore_alias)))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthenticodeConfig {
+    /// PKCS#12 file holding the RSA code signing key and its certificate chain
+    pub pfx: String,
+    /// Password of `pfx`
+    pub password: Option<String>,
+    /// Environment variable holding the password of `pfx`
+    #[serde(rename = "passwordEnv")]
+    pub password_env: Option<String>,
+}
+
+impl AuthenticodeConfig {
+    pub fn resolve_password(&self) -> Result<String> {
+        match (&self.password, &self.password_env) {
+            (Some(_), Some(_)) => {
+                anyhow::bail!("Set only one of sign.authenticode.password and passwordEnv")
+            }
+            (Some(password), None) => Ok(password.clone()),
+            (None, Some(variable)) => std::env::var(variable).map_err(|_| {
+                anyhow::anyhow!("Environment variable {variable} for the PFX password is not set")
+            }),
+            (None, None) => Ok(String::new()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SigningConfig {
+    /// Ed25519 private key (PKCS#8 PEM) used to sign the embedded script
+    #[serde(rename = "scriptKey")]
+    pub script_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptConfig {
+    /// `random` (default), `passphrase` or `packageName`
+    #[serde(rename = "keySource")]
+    pub key_source: Option<String>,
+    pub passphrase: Option<String>,
+    #[serde(rename = "packageName")]
+    pub package_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AntiDetectionConfig {
+    /// Set to `false` to skip keyword rewriting entirely
+    pub enabled: Option<bool>,
+    /// Keywords replaced in addition to the built-in table
+    #[serde(rename = "extraKeywords")]
+    pub extra_keywords: Option<Vec<String>>,
+    /// Built-in keywords that should be left untouched
+    #[serde(rename = "removeKeywords")]
+    pub remove_keywords: Option<Vec<String>>,
+    /// Fixed replacement per keyword instead of a random string
+    pub replacements: Option<HashMap<String, String>>,
+    /// Sections scanned in addition to `.rodata`/`.dynstr` (ELF) or `.rdata` (PE)
+    #[serde(rename = "extraSections")]
+    pub extra_sections: Option<Vec<String>>,
+    /// Log the location of every replaced occurrence
+    pub report: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompressionConfig {
+    /// `none`, `xz`, `zstd` or `brotli`
+    pub codec: String,
+    pub level: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BundleConfig {
+    /// Directory packed into the bundle
+    pub root: String,
+    /// Globs relative to `root`, defaults to every file
+    pub include: Option<Vec<String>>,
+    /// Entry module relative to `root`, defaults to the target `entry`
+    pub entry: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -39,6 +139,38 @@ pub struct InjectApkConfig {
     pub target_lib: Option<String>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InjectPeConfig {
+    #[serde(rename = "sourcePath")]
+    pub source_path: Option<String>,
+    #[serde(rename = "targetModule")]
+    pub target_module: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InjectMachoConfig {
+    #[serde(rename = "sourcePath")]
+    pub source_path: Option<String>,
+    /// Path of the binary inside `sourcePath`, e.g. `Contents/MacOS/App`
+    #[serde(rename = "targetBinary")]
+    pub target_binary: Option<String>,
+    /// Use `LC_LOAD_WEAK_DYLIB` so the binary still starts without the dylib
+    pub weak: Option<bool>,
+    /// Directory the dylib is loaded from, `@loader_path` by default
+    #[serde(rename = "loadPath")]
+    pub load_path: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InjectIpaConfig {
+    #[serde(rename = "sourceIpaPath")]
+    pub source_ipa_path: Option<String>,
+    /// Ad-hoc sign the patched executable and the injected dylib, and drop
+    /// the bundle signature they no longer match
+    #[serde(rename = "adhocSign")]
+    pub adhoc_sign: Option<bool>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct XposedConfig {
     #[serde(rename = "packageName")]
@@ -61,6 +193,18 @@ pub struct ZygiskConfig {
     pub scope: Option<String>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TweakDebConfig {
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub author: Option<String>,
+    pub description: Option<String>,
+    pub scope: Option<String>,
+    /// Install under `/var/jb` for rootless jailbreaks
+    pub rootless: Option<bool>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FripackConfig {
     #[serde(flatten)]
@@ -82,15 +226,31 @@ impl FripackConfig {
                 frida_version: Some("17.5.1".to_string()),
                 entry: Some("main.js".to_string()),
                 xz: Some(false),
+                encrypt: None,
+                anti_detection: None,
+                seed: None,
+                bundle: None,
+                compression: None,
+                checksum: None,
+                randomize_magic: None,
+                page_size: None,
                 override_prebuild_file: None,
                 sign: None,
+                signing: None,
                 output_dir: None,
                 target_base_name: None,
+                company_name: None,
+                file_description: None,
+                copyright: None,
                 before_build: None,
                 after_build: None,
                 inject_apk: None,
+                inject_pe: None,
+                inject_macho: None,
+                inject_ipa: None,
                 xposed: None,
                 zygisk: None,
+                tweak_deb: None,
                 watch_path: None,
                 push_path: None,
             },
@@ -107,17 +267,33 @@ impl FripackConfig {
                 frida_version: None,
                 entry: None,
                 xz: None,
+                encrypt: None,
+                anti_detection: None,
+                seed: None,
+                bundle: None,
+                compression: None,
+                checksum: None,
+                randomize_magic: None,
+                page_size: None,
                 override_prebuild_file: None,
                 sign: Some(SignConfig {
-                    keystore: "C:\\Users\\YourUser\\.android\\debug.keystore".to_string(),
-                    keystore_pass: "android".to_string(),
-                    keystore_alias: "androiddebugkey".to_string(),
+                    keystore: Some("C:\\Users\\YourUser\\.android\\debug.keystore".to_string()),
+                    keystore_pass: Some("android".to_string()),
+                    keystore_alias: Some("androiddebugkey".to_string()),
+                    authenticode: None,
                 }),
+                signing: None,
                 output_dir: None,
                 target_base_name: None,
+                company_name: None,
+                file_description: None,
+                copyright: None,
                 before_build: None,
                 after_build: None,
                 inject_apk: None,
+                inject_pe: None,
+                inject_macho: None,
+                inject_ipa: None,
                 xposed: Some(XposedConfig {
                     package_name: Some("com.example.myxposedmodule".to_string()),
                     name: Some("My Xposed Module".to_string()),
@@ -129,6 +305,7 @@ impl FripackConfig {
                     ),
                 }),
                 zygisk: None,
+                tweak_deb: None,
                 watch_path: None,
                 push_path: None,
             },
@@ -145,15 +322,31 @@ impl FripackConfig {
                 frida_version: None,
                 entry: None,
                 xz: None,
+                encrypt: None,
+                anti_detection: None,
+                seed: None,
+                bundle: None,
+                compression: None,
+                checksum: None,
+                randomize_magic: None,
+                page_size: None,
                 override_prebuild_file: Some("./libfripack-inject.so".to_string()),
                 sign: None,
+                signing: None,
                 output_dir: None,
                 target_base_name: None,
+                company_name: None,
+                file_description: None,
+                copyright: None,
                 before_build: None,
                 after_build: None,
                 inject_apk: None,
+                inject_pe: None,
+                inject_macho: None,
+                inject_ipa: None,
                 xposed: None,
                 zygisk: None,
+                tweak_deb: None,
                 watch_path: None,
                 push_path: None,
             },
@@ -170,9 +363,20 @@ impl FripackConfig {
                 frida_version: Some("17.5.1".to_string()),
                 entry: Some("main.js".to_string()),
                 xz: Some(false),
+                encrypt: None,
+                anti_detection: None,
+                seed: None,
+                bundle: None,
+                compression: None,
+                checksum: None,
+                randomize_magic: None,
+                page_size: None,
                 override_prebuild_file: None,
                 output_dir: None,
                 target_base_name: None,
+                company_name: None,
+                file_description: None,
+                copyright: None,
                 before_build: None,
                 after_build: None,
                 inject_apk: Some(InjectApkConfig {
@@ -181,13 +385,63 @@ impl FripackConfig {
                     inject_mode: InjectMode::NativeAddNeeded,
                     target_lib: Some("libnative-lib.so".to_string()),
                 }),
+                inject_pe: None,
+                inject_macho: None,
+                inject_ipa: None,
                 xposed: None,
                 zygisk: None,
+                tweak_deb: None,
                 sign: Some(SignConfig {
-                    keystore: "C:\\Users\\YourUser\\.android\\debug.keystore".to_string(),
-                    keystore_pass: "android".to_string(),
-                    keystore_alias: "androiddebugkey".to_string(),
+                    keystore: Some("C:\\Users\\YourUser\\.android\\debug.keystore".to_string()),
+                    keystore_pass: Some("android".to_string()),
+                    keystore_alias: Some("androiddebugkey".to_string()),
+                    authenticode: None,
                 }),
+                signing: None,
+                watch_path: None,
+                push_path: None,
+            },
+        );
+
+        // Example Inject PE
+        targets.insert(
+            "example-inject-pe".to_string(),
+            TargetConfig {
+                inherit: None,
+                target_type: Some("inject-pe".to_string()),
+                platform: Some("windows-x64".to_string()),
+                version: Some("1.0.0".to_string()),
+                frida_version: Some("17.5.1".to_string()),
+                entry: Some("main.js".to_string()),
+                xz: Some(false),
+                encrypt: None,
+                anti_detection: None,
+                seed: None,
+                bundle: None,
+                compression: None,
+                checksum: None,
+                randomize_magic: None,
+                page_size: None,
+                override_prebuild_file: None,
+                output_dir: None,
+                target_base_name: None,
+                company_name: None,
+                file_description: None,
+                copyright: None,
+                before_build: None,
+                after_build: None,
+                inject_apk: None,
+                inject_pe: Some(InjectPeConfig {
+                    source_path: Some("C:\\Program Files\\Example".to_string()),
+                    target_module: Some("example.exe".to_string()),
+                }),
+                inject_macho: None,
+                inject_ipa: None,
+                xposed: None,
+                zygisk: None,
+                tweak_deb: None,
+                sign: None,
+                signing: None,
                 watch_path: None,
                 push_path: None,
             },
@@ -204,12 +458,26 @@ impl FripackConfig {
                 frida_version: Some("17.5.1".to_string()),
                 entry: Some("main.js".to_string()),
                 xz: Some(false),
+                encrypt: None,
+                anti_detection: None,
+                seed: None,
+                bundle: None,
+                compression: None,
+                checksum: None,
+                randomize_magic: None,
+                page_size: None,
                 override_prebuild_file: None,
                 output_dir: None,
                 target_base_name: None,
+                company_name: None,
+                file_description: None,
+                copyright: None,
                 before_build: None,
                 after_build: None,
                 inject_apk: None,
+                inject_pe: None,
+                inject_macho: None,
+                inject_ipa: None,
                 xposed: None,
                 zygisk: Some(ZygiskConfig {
                     id: Some("myzygiskmodule".to_string()),
@@ -220,7 +488,9 @@ impl FripackConfig {
                     description: Some("A minimal Zygisk module".to_string()),
                     scope: Some("com.example.app1;com.example.app2".to_string()),
                 }),
+                tweak_deb: None,
                 sign: None,
+                signing: None,
                 watch_path: None,
                 push_path: None,
             },
@@ -298,22 +568,54 @@ pub struct TargetConfig {
     #[serde(rename = "fridaVersion")]
     pub frida_version: Option<String>,
     pub entry: Option<String>,
+    pub bundle: Option<BundleConfig>,
     pub xz: Option<bool>,
+    pub compression: Option<CompressionConfig>,
+    /// Store CRC-32 checksums of the payload (header version 4)
+    pub checksum: Option<bool>,
+    /// Replace the fixed `EmbeddedConfig` magic values with per-build ones.
+    /// The pair still follows the public `randomized_magic2` relation.
+    #[serde(rename = "randomizeMagic")]
+    pub randomize_magic: Option<bool>,
+    pub encrypt: Option<EncryptConfig>,
+    #[serde(rename = "antiDetection")]
+    pub anti_detection: Option<AntiDetectionConfig>,
+    /// Seeds generated names and keys so identical inputs give identical outputs
+    pub seed: Option<String>,
+    #[serde(rename = "pageSize")]
+    pub page_size: Option<u64>,
     #[serde(rename = "overridePrebuildFile")]
     pub override_prebuild_file: Option<String>,
     pub sign: Option<SignConfig>,
+    pub signing: Option<SigningConfig>,
     #[serde(rename = "outputDir")]
     pub output_dir: Option<String>,
     #[serde(rename = "targetBaseName")]
     pub target_base_name: Option<String>,
+    /// `CompanyName` of the Windows version resource
+    #[serde(rename = "companyName")]
+    pub company_name: Option<String>,
+    /// `FileDescription` of the Windows version resource
+    #[serde(rename = "fileDescription")]
+    pub file_description: Option<String>,
+    /// `LegalCopyright` of the Windows version resource
+    pub copyright: Option<String>,
     #[serde(rename = "beforeBuild")]
     pub before_build: Option<String>,
     #[serde(rename = "afterBuild")]
     pub after_build: Option<String>,
     #[serde(rename = "injectApk")]
     pub inject_apk: Option<InjectApkConfig>,
+    #[serde(rename = "injectPe")]
+    pub inject_pe: Option<InjectPeConfig>,
+    #[serde(rename = "injectMacho")]
+    pub inject_macho: Option<InjectMachoConfig>,
+    #[serde(rename = "injectIpa")]
+    pub inject_ipa: Option<InjectIpaConfig>,
     pub xposed: Option<XposedConfig>,
     pub zygisk: Option<ZygiskConfig>,
+    #[serde(rename = "tweakDeb")]
+    pub tweak_deb: Option<TweakDebConfig>,
     #[serde(rename = "watchPath")]
     pub watch_path: Option<String>,
     #[serde(rename = "pushPath")]
@@ -333,6 +635,7 @@ pub enum Platform {
     Windows,
     Linux,
     MacOS,
+    IOS,
 }
 
 impl Platform {
@@ -342,6 +645,7 @@ impl Platform {
             Platform::Windows => "dll",
             Platform::Linux => "so",
             Platform::MacOS => "dylib",
+            Platform::IOS => "dylib",
         }
     }
 }
@@ -381,6 +685,7 @@ impl PlatformConfig {
             ["linux", "x64"] => (Platform::Linux, Arch::X86_64),
             ["macos", "x86_64"] => (Platform::MacOS, Arch::X86_64),
             ["macos", "arm64"] => (Platform::MacOS, Arch::Arm64),
+            ["ios", "arm64"] => (Platform::IOS, Arch::Arm64),
             _ => anyhow::bail!("Unsupported platform description: {platform_desc}"),
         };
         Ok(PlatformConfig { arch, platform })
@@ -410,6 +715,7 @@ impl PlatformConfig {
             Platform::Windows => Ok("windows".to_string()),
             Platform::Linux => Ok("linux".to_string()),
             Platform::MacOS => Ok("macos".to_string()),
+            Platform::IOS => Ok("ios".to_string()),
         }
     }
 }
@@ -421,16 +727,32 @@ pub struct ResolvedTarget {
     pub version: Option<String>,
     pub frida_version: Option<String>,
     pub entry: Option<String>,
+    pub bundle: Option<BundleConfig>,
     pub xz: Option<bool>,
+    pub compression: Option<CompressionConfig>,
+    pub checksum: Option<bool>,
+    pub randomize_magic: Option<bool>,
+    pub encrypt: Option<EncryptConfig>,
+    pub anti_detection: Option<AntiDetectionConfig>,
+    pub seed: Option<String>,
+    pub page_size: Option<u64>,
     pub override_prebuild_file: Option<String>,
     pub sign: Option<SignConfig>,
+    pub signing: Option<SigningConfig>,
     pub output_dir: Option<String>,
     pub target_base_name: Option<String>,
+    pub company_name: Option<String>,
+    pub file_description: Option<String>,
+    pub copyright: Option<String>,
     pub before_build: Option<String>,
     pub after_build: Option<String>,
     pub inject_apk: Option<InjectApkConfig>,
+    pub inject_pe: Option<InjectPeConfig>,
+    pub inject_macho: Option<InjectMachoConfig>,
+    pub inject_ipa: Option<InjectIpaConfig>,
     pub xposed: Option<XposedConfig>,
     pub zygisk: Option<ZygiskConfig>,
+    pub tweak_deb: Option<TweakDebConfig>,
     pub watch_path: Option<String>,
     pub push_path: Option<String>,
     pub watch_mode: bool,
@@ -445,16 +767,32 @@ impl ResolvedTarget {
             version,
             frida_version,
             entry,
+            bundle,
             xz,
+            compression,
+            checksum,
+            randomize_magic,
+            encrypt,
+            anti_detection,
+            seed,
+            page_size,
             override_prebuild_file,
             sign,
+            signing,
             output_dir,
             target_base_name,
+            company_name,
+            file_description,
+            copyright,
             before_build,
             after_build,
             inject_apk,
+            inject_pe,
+            inject_macho,
+            inject_ipa,
             xposed,
             zygisk,
+            tweak_deb,
             watch_path,
             push_path
         );
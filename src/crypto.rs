@@ -0,0 +1,214 @@
+use anyhow::{Context, Result};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305,
+};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Cipher {
+    ChaCha20Poly1305 = 1,
+}
+
+/// Where the runtime gets the payload key from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum KeySource {
+    /// Random per-build key stored in the header.
+    Random = 1,
+    /// PBKDF2-HMAC-SHA256 of a passphrase supplied to the runtime.
+    Passphrase = 2,
+    /// PBKDF2-HMAC-SHA256 of the host package name.
+    PackageName = 3,
+}
+
+impl KeySource {
+    fn from_u8(value: u8) -> Result<Self> {
+        match value {
+            1 => Ok(KeySource::Random),
+            2 => Ok(KeySource::Passphrase),
+            3 => Ok(KeySource::PackageName),
+            _ => anyhow::bail!("Unknown key source: {value}"),
+        }
+    }
+}
+
+/// Placed in front of the encrypted data for `EmbeddedConfig` version 2.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct EncryptionHeader {
+    pub cipher: u8,
+    pub key_source: u8,
+    pub reserved: u16,
+    pub kdf_iterations: u32,
+    pub salt: [u8; 16],
+    pub nonce: [u8; 12],
+    /// Only set for `KeySource::Random`.
+    pub key: [u8; 32],
+}
+
+impl EncryptionHeader {
+    pub const SIZE: usize = std::mem::size_of::<EncryptionHeader>();
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::SIZE);
+        bytes.push(self.cipher);
+        bytes.push(self.key_source);
+        bytes.extend_from_slice(&{ self.reserved }.to_le_bytes());
+        bytes.extend_from_slice(&{ self.kdf_iterations }.to_le_bytes());
+        bytes.extend_from_slice(&self.salt);
+        bytes.extend_from_slice(&self.nonce);
+        bytes.extend_from_slice(&self.key);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(..Self::SIZE)?;
+        Some(Self {
+            cipher: bytes[0],
+            key_source: bytes[1],
+            reserved: u16::from_le_bytes(bytes[2..4].try_into().ok()?),
+            kdf_iterations: u32::from_le_bytes(bytes[4..8].try_into().ok()?),
+            salt: bytes[8..24].try_into().ok()?,
+            nonce: bytes[24..36].try_into().ok()?,
+            key: bytes[36..68].try_into().ok()?,
+        })
+    }
+
+    /// The header is authenticated along with the data, minus the key.
+    fn associated_data(&self) -> Vec<u8> {
+        let mut header = *self;
+        header.key = [0; 32];
+        header.as_bytes()
+    }
+}
+
+pub struct PayloadEncryption {
+    key_source: KeySource,
+    key: [u8; 32],
+    salt: [u8; 16],
+    /// Hashed with the plaintext to get the nonce, so seeded builds stay
+    /// reproducible without reusing a nonce for different payloads.
+    nonce_seed: [u8; 32],
+}
+
+impl PayloadEncryption {
+    pub fn random(rng: &mut impl RngCore) -> Self {
+        let mut key = [0u8; 32];
+        rng.fill_bytes(&mut key);
+        let mut nonce_seed = [0u8; 32];
+        rng.fill_bytes(&mut nonce_seed);
+        Self {
+            key_source: KeySource::Random,
+            key,
+            salt: [0; 16],
+            nonce_seed,
+        }
+    }
+
+    pub fn from_passphrase(passphrase: &str, rng: &mut impl RngCore) -> Self {
+        Self::derived(KeySource::Passphrase, passphrase, rng)
+    }
+
+    pub fn from_package_name(package_name: &str, rng: &mut impl RngCore) -> Self {
+        Self::derived(KeySource::PackageName, package_name, rng)
+    }
+
+    fn derived(key_source: KeySource, secret: &str, rng: &mut impl RngCore) -> Self {
+        let mut salt = [0u8; 16];
+        rng.fill_bytes(&mut salt);
+        let mut nonce_seed = [0u8; 32];
+        rng.fill_bytes(&mut nonce_seed);
+        Self {
+            key_source,
+            key: derive_key(secret, &salt, PBKDF2_ITERATIONS),
+            salt,
+            nonce_seed,
+        }
+    }
+
+    pub fn key_source(&self) -> KeySource {
+        self.key_source
+    }
+
+    /// Encrypt `data`, returning the `EncryptionHeader` followed by the
+    /// ciphertext and tag.
+    pub fn seal(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let digest = Sha256::new()
+            .chain_update(self.nonce_seed)
+            .chain_update(data)
+            .finalize();
+        let nonce: [u8; 12] = digest[..12].try_into().unwrap();
+
+        let header = EncryptionHeader {
+            cipher: Cipher::ChaCha20Poly1305 as u8,
+            key_source: self.key_source as u8,
+            reserved: 0,
+            kdf_iterations: match self.key_source {
+                KeySource::Random => 0,
+                _ => PBKDF2_ITERATIONS,
+            },
+            salt: self.salt,
+            nonce,
+            key: match self.key_source {
+                KeySource::Random => self.key,
+                _ => [0; 32],
+            },
+        };
+
+        let cipher = ChaCha20Poly1305::new(&self.key.into());
+        let ciphertext = cipher
+            .encrypt(
+                &nonce.into(),
+                Payload {
+                    msg: data,
+                    aad: &header.associated_data(),
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt payload"))?;
+
+        let mut out = header.as_bytes();
+        out.extend_from_slice(&ciphertext);
+        Ok(out)
+    }
+}
+
+fn derive_key(secret: &str, salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(secret.as_bytes(), salt, iterations, &mut key);
+    key
+}
+
+/// Decrypt data produced by `PayloadEncryption::seal`. `secret` is the
+/// passphrase or package name for derived keys.
+pub fn open(data: &[u8], secret: Option<&str>) -> Result<Vec<u8>> {
+    let header = EncryptionHeader::from_bytes(data).context("Encryption header is truncated")?;
+    if header.cipher != Cipher::ChaCha20Poly1305 as u8 {
+        anyhow::bail!("Unsupported payload cipher: {}", header.cipher);
+    }
+
+    let key = match KeySource::from_u8(header.key_source)? {
+        KeySource::Random => header.key,
+        key_source => {
+            let secret = secret.with_context(|| {
+                format!("Payload key is derived from {key_source:?}, a key must be provided")
+            })?;
+            derive_key(secret, &header.salt, header.kdf_iterations)
+        }
+    };
+
+    let cipher = ChaCha20Poly1305::new(&key.into());
+    cipher
+        .decrypt(
+            &header.nonce.into(),
+            Payload {
+                msg: &data[EncryptionHeader::SIZE..],
+                aad: &header.associated_data(),
+            },
+        )
+        .map_err(|_| anyhow::anyhow!("Failed to decrypt payload (wrong key or corrupted data)"))
+}
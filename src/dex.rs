@@ -0,0 +1,742 @@
+use anyhow::{Context, Result};
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, BTreeSet};
+
+const DEX_MAGIC: &[u8; 8] = b"dex\n035\0";
+const HEADER_SIZE: usize = 0x70;
+const ENDIAN_CONSTANT: u32 = 0x12345678;
+const NO_INDEX: u32 = 0xffffffff;
+
+pub const ACC_PUBLIC: u32 = 0x1;
+pub const ACC_CONSTRUCTOR: u32 = 0x10000;
+
+const TYPE_HEADER_ITEM: u16 = 0x0000;
+const TYPE_STRING_ID_ITEM: u16 = 0x0001;
+const TYPE_TYPE_ID_ITEM: u16 = 0x0002;
+const TYPE_PROTO_ID_ITEM: u16 = 0x0003;
+const TYPE_FIELD_ID_ITEM: u16 = 0x0004;
+const TYPE_METHOD_ID_ITEM: u16 = 0x0005;
+const TYPE_CLASS_DEF_ITEM: u16 = 0x0006;
+const TYPE_MAP_LIST: u16 = 0x1000;
+const TYPE_TYPE_LIST: u16 = 0x1001;
+const TYPE_CLASS_DATA_ITEM: u16 = 0x2000;
+const TYPE_CODE_ITEM: u16 = 0x2001;
+const TYPE_STRING_DATA_ITEM: u16 = 0x2002;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Proto {
+    pub return_type: String,
+    pub parameters: Vec<String>,
+}
+
+impl Proto {
+    pub fn new(return_type: &str, parameters: &[&str]) -> Self {
+        Self {
+            return_type: return_type.to_string(),
+            parameters: parameters.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    /// Short form descriptor: every reference type collapses to `L`.
+    fn shorty(&self) -> String {
+        std::iter::once(&self.return_type)
+            .chain(&self.parameters)
+            .map(|descriptor| match descriptor.as_bytes()[0] {
+                b'[' | b'L' => 'L',
+                other => other as char,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FieldRef {
+    pub class: String,
+    pub name: String,
+    pub field_type: String,
+}
+
+impl FieldRef {
+    pub fn new(class: &str, name: &str, field_type: &str) -> Self {
+        Self {
+            class: class.to_string(),
+            name: name.to_string(),
+            field_type: field_type.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MethodRef {
+    pub class: String,
+    pub name: String,
+    pub proto: Proto,
+}
+
+impl MethodRef {
+    pub fn new(class: &str, name: &str, proto: Proto) -> Self {
+        Self {
+            class: class.to_string(),
+            name: name.to_string(),
+            proto,
+        }
+    }
+}
+
+/// The handful of Dalvik instructions the generated classes need, named
+/// after their smali mnemonics. Registers are `vN` numbers; arguments live
+/// in the last registers of the frame as usual.
+#[derive(Debug, Clone)]
+pub enum Instruction {
+    IgetObject(u8, u8, FieldRef),
+    NewInstance(u8, String),
+    InvokeDirect(Vec<u8>, MethodRef),
+    InvokeVirtual(Vec<u8>, MethodRef),
+    InvokeStatic(Vec<u8>, MethodRef),
+    MoveResultObject(u8),
+    ConstString(u8, String),
+    ReturnVoid,
+}
+
+/// An instance method. Parameters are expected to take one register each,
+/// i.e. no `long` or `double`.
+#[derive(Debug, Clone)]
+pub struct Method {
+    pub name: String,
+    pub proto: Proto,
+    pub access_flags: u32,
+    /// Locals plus the `this` and parameter registers.
+    pub registers: u16,
+    pub code: Vec<Instruction>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Class {
+    pub descriptor: String,
+    pub superclass: String,
+    pub interfaces: Vec<String>,
+    pub access_flags: u32,
+    pub direct_methods: Vec<Method>,
+    pub virtual_methods: Vec<Method>,
+}
+
+impl Class {
+    fn method_refs(&self) -> impl Iterator<Item = (MethodRef, &Method)> {
+        self.direct_methods
+            .iter()
+            .chain(&self.virtual_methods)
+            .map(|method| {
+                (
+                    MethodRef::new(&self.descriptor, &method.name, method.proto.clone()),
+                    method,
+                )
+            })
+    }
+}
+
+/// Every string, type, proto, field and method the class refers to. The
+/// ID sections have to be sorted, so indices are only known once
+/// everything is collected.
+#[derive(Default)]
+struct Pools {
+    strings: BTreeSet<Vec<u16>>,
+    types: BTreeSet<String>,
+    protos: BTreeSet<Proto>,
+    fields: BTreeSet<FieldRef>,
+    methods: BTreeSet<MethodRef>,
+}
+
+impl Pools {
+    fn add_type(&mut self, descriptor: &str) {
+        self.strings.insert(descriptor.encode_utf16().collect());
+        self.types.insert(descriptor.to_string());
+    }
+
+    fn add_proto(&mut self, proto: &Proto) {
+        self.strings.insert(proto.shorty().encode_utf16().collect());
+        self.add_type(&proto.return_type);
+        for parameter in &proto.parameters {
+            self.add_type(parameter);
+        }
+        self.protos.insert(proto.clone());
+    }
+
+    fn add_method(&mut self, method: &MethodRef) {
+        self.add_type(&method.class);
+        self.strings.insert(method.name.encode_utf16().collect());
+        self.add_proto(&method.proto);
+        self.methods.insert(method.clone());
+    }
+
+    fn add_field(&mut self, field: &FieldRef) {
+        self.add_type(&field.class);
+        self.add_type(&field.field_type);
+        self.strings.insert(field.name.encode_utf16().collect());
+        self.fields.insert(field.clone());
+    }
+}
+
+/// Final indices of the sorted ID sections.
+struct Indices {
+    strings: BTreeMap<Vec<u16>, u32>,
+    types: BTreeMap<String, u16>,
+    protos: BTreeMap<Proto, u16>,
+    fields: BTreeMap<FieldRef, u16>,
+    methods: BTreeMap<MethodRef, u16>,
+}
+
+impl Indices {
+    fn new(pools: &Pools) -> Result<Self> {
+        let strings = (0u32..)
+            .zip(&pools.strings)
+            .map(|(i, s)| (s.clone(), i))
+            .collect();
+        let types = index_map(&pools.types, "types")?;
+        let protos = index_map(&pools.protos, "prototypes")?;
+        let fields = index_map(&pools.fields, "fields")?;
+        let methods = index_map(&pools.methods, "methods")?;
+        let indices = Self {
+            strings,
+            types,
+            protos,
+            fields,
+            methods,
+        };
+        // Types sort by descriptor, which matches their string order only
+        // as long as both compare the same way; check rather than assume.
+        let mut previous = None;
+        for descriptor in &pools.types {
+            let index = indices.string(descriptor);
+            if previous.is_some_and(|previous| previous >= index) {
+                anyhow::bail!("Type descriptors do not sort like their strings");
+            }
+            previous = Some(index);
+        }
+        Ok(indices)
+    }
+
+    fn string(&self, value: &str) -> u32 {
+        self.strings[&value.encode_utf16().collect::<Vec<_>>()]
+    }
+}
+
+fn index_map<T: Ord + Clone>(items: &BTreeSet<T>, what: &str) -> Result<BTreeMap<T, u16>> {
+    if items.len() > u16::MAX as usize {
+        anyhow::bail!("Too many {what} for a DEX file");
+    }
+    Ok((0u16..)
+        .zip(items)
+        .map(|(i, item)| (item.clone(), i))
+        .collect())
+}
+
+/// Write a DEX file holding the single class `class`.
+pub fn write_dex(class: &Class) -> Result<Vec<u8>> {
+    let mut pools = Pools::default();
+    pools.add_type(&class.descriptor);
+    pools.add_type(&class.superclass);
+    for interface in &class.interfaces {
+        pools.add_type(interface);
+    }
+    for (method_ref, method) in class.method_refs() {
+        pools.add_method(&method_ref);
+        for instruction in &method.code {
+            match instruction {
+                Instruction::IgetObject(_, _, field) => pools.add_field(field),
+                Instruction::NewInstance(_, descriptor) => pools.add_type(descriptor),
+                Instruction::InvokeDirect(_, method)
+                | Instruction::InvokeVirtual(_, method)
+                | Instruction::InvokeStatic(_, method) => pools.add_method(method),
+                Instruction::ConstString(_, value) => {
+                    pools.strings.insert(value.encode_utf16().collect());
+                }
+                Instruction::MoveResultObject(_) | Instruction::ReturnVoid => {}
+            }
+        }
+    }
+    let indices = Indices::new(&pools)?;
+
+    let string_ids_off = HEADER_SIZE;
+    let type_ids_off = string_ids_off + pools.strings.len() * 4;
+    let proto_ids_off = type_ids_off + pools.types.len() * 4;
+    let field_ids_off = proto_ids_off + pools.protos.len() * 12;
+    let method_ids_off = field_ids_off + pools.fields.len() * 8;
+    let class_defs_off = method_ids_off + pools.methods.len() * 8;
+    let data_off = class_defs_off + 32;
+
+    // The data section, laid out first so the ID sections can point into it
+    let mut data = Vec::new();
+    let mut map = vec![(TYPE_HEADER_ITEM, 1, 0)];
+    for (item_type, count, offset) in [
+        (TYPE_STRING_ID_ITEM, pools.strings.len(), string_ids_off),
+        (TYPE_TYPE_ID_ITEM, pools.types.len(), type_ids_off),
+        (TYPE_PROTO_ID_ITEM, pools.protos.len(), proto_ids_off),
+        (TYPE_FIELD_ID_ITEM, pools.fields.len(), field_ids_off),
+        (TYPE_METHOD_ID_ITEM, pools.methods.len(), method_ids_off),
+        (TYPE_CLASS_DEF_ITEM, 1, class_defs_off),
+    ] {
+        if count > 0 {
+            map.push((item_type, count as u32, offset as u32));
+        }
+    }
+
+    let mut code_offsets = BTreeMap::new();
+    for (method_ref, method) in class.method_refs() {
+        align(&mut data, 4);
+        code_offsets.insert(method_ref.clone(), (data_off + data.len()) as u32);
+        write_code_item(&mut data, method, &indices)?;
+    }
+    map.push((TYPE_CODE_ITEM, code_offsets.len() as u32, data_off as u32));
+
+    let mut type_lists = BTreeMap::new();
+    let type_lists_off = data_off + data.len().next_multiple_of(4);
+    for list in pools
+        .protos
+        .iter()
+        .map(|proto| &proto.parameters)
+        .chain([&class.interfaces])
+        .filter(|list| !list.is_empty())
+    {
+        if type_lists.contains_key(list) {
+            continue;
+        }
+        align(&mut data, 4);
+        type_lists.insert(list.clone(), (data_off + data.len()) as u32);
+        data.extend_from_slice(&(list.len() as u32).to_le_bytes());
+        for descriptor in list {
+            data.extend_from_slice(&indices.types[descriptor].to_le_bytes());
+        }
+    }
+    if !type_lists.is_empty() {
+        map.push((
+            TYPE_TYPE_LIST,
+            type_lists.len() as u32,
+            type_lists_off as u32,
+        ));
+    }
+
+    let mut string_data_offsets = Vec::with_capacity(pools.strings.len());
+    map.push((
+        TYPE_STRING_DATA_ITEM,
+        pools.strings.len() as u32,
+        (data_off + data.len()) as u32,
+    ));
+    for string in &pools.strings {
+        string_data_offsets.push((data_off + data.len()) as u32);
+        write_uleb128(&mut data, string.len() as u32);
+        write_mutf8(&mut data, string);
+        data.push(0);
+    }
+
+    let class_data_off = (data_off + data.len()) as u32;
+    map.push((TYPE_CLASS_DATA_ITEM, 1, class_data_off));
+    write_uleb128(&mut data, 0);
+    write_uleb128(&mut data, 0);
+    write_uleb128(&mut data, class.direct_methods.len() as u32);
+    write_uleb128(&mut data, class.virtual_methods.len() as u32);
+    for methods in [&class.direct_methods, &class.virtual_methods] {
+        let mut encoded = methods
+            .iter()
+            .map(|method| {
+                let method_ref =
+                    MethodRef::new(&class.descriptor, &method.name, method.proto.clone());
+                (
+                    indices.methods[&method_ref] as u32,
+                    method.access_flags,
+                    code_offsets[&method_ref],
+                )
+            })
+            .collect::<Vec<_>>();
+        encoded.sort();
+        let mut previous = 0;
+        for (index, access_flags, code_off) in encoded {
+            write_uleb128(&mut data, index - previous);
+            write_uleb128(&mut data, access_flags);
+            write_uleb128(&mut data, code_off);
+            previous = index;
+        }
+    }
+
+    align(&mut data, 4);
+    let map_off = data_off + data.len();
+    map.push((TYPE_MAP_LIST, 1, map_off as u32));
+    data.extend_from_slice(&(map.len() as u32).to_le_bytes());
+    for (item_type, count, offset) in &map {
+        data.extend_from_slice(&item_type.to_le_bytes());
+        data.extend_from_slice(&0u16.to_le_bytes());
+        data.extend_from_slice(&count.to_le_bytes());
+        data.extend_from_slice(&offset.to_le_bytes());
+    }
+
+    let mut dex = Vec::with_capacity(data_off + data.len());
+    dex.extend_from_slice(DEX_MAGIC);
+    // Checksum and signature, filled in last
+    dex.resize(32, 0);
+    for value in [
+        (data_off + data.len()) as u32,
+        HEADER_SIZE as u32,
+        ENDIAN_CONSTANT,
+        0,
+        0,
+        map_off as u32,
+    ] {
+        dex.extend_from_slice(&value.to_le_bytes());
+    }
+    for (count, offset) in [
+        (pools.strings.len(), string_ids_off),
+        (pools.types.len(), type_ids_off),
+        (pools.protos.len(), proto_ids_off),
+        (pools.fields.len(), field_ids_off),
+        (pools.methods.len(), method_ids_off),
+        (1, class_defs_off),
+        (data.len(), data_off),
+    ] {
+        let offset = if count == 0 { 0 } else { offset };
+        dex.extend_from_slice(&(count as u32).to_le_bytes());
+        dex.extend_from_slice(&(offset as u32).to_le_bytes());
+    }
+    debug_assert_eq!(dex.len(), HEADER_SIZE);
+
+    for offset in string_data_offsets {
+        dex.extend_from_slice(&offset.to_le_bytes());
+    }
+    for descriptor in &pools.types {
+        dex.extend_from_slice(&indices.string(descriptor).to_le_bytes());
+    }
+    for proto in &pools.protos {
+        dex.extend_from_slice(&indices.string(&proto.shorty()).to_le_bytes());
+        dex.extend_from_slice(&(indices.types[&proto.return_type] as u32).to_le_bytes());
+        let parameters_off = type_lists.get(&proto.parameters).copied().unwrap_or(0);
+        dex.extend_from_slice(&parameters_off.to_le_bytes());
+    }
+    for field in &pools.fields {
+        dex.extend_from_slice(&indices.types[&field.class].to_le_bytes());
+        dex.extend_from_slice(&indices.types[&field.field_type].to_le_bytes());
+        dex.extend_from_slice(&indices.string(&field.name).to_le_bytes());
+    }
+    for method in &pools.methods {
+        dex.extend_from_slice(&indices.types[&method.class].to_le_bytes());
+        dex.extend_from_slice(&indices.protos[&method.proto].to_le_bytes());
+        dex.extend_from_slice(&indices.string(&method.name).to_le_bytes());
+    }
+    for value in [
+        indices.types[&class.descriptor] as u32,
+        class.access_flags,
+        indices.types[&class.superclass] as u32,
+        type_lists.get(&class.interfaces).copied().unwrap_or(0),
+        NO_INDEX,
+        0,
+        class_data_off,
+        0,
+    ] {
+        dex.extend_from_slice(&value.to_le_bytes());
+    }
+    debug_assert_eq!(dex.len(), data_off);
+    dex.extend_from_slice(&data);
+
+    let signature = Sha1::digest(&dex[32..]);
+    dex[12..32].copy_from_slice(&signature);
+    let checksum = adler32(&dex[12..]);
+    dex[8..12].copy_from_slice(&checksum.to_le_bytes());
+    Ok(dex)
+}
+
+fn write_code_item(data: &mut Vec<u8>, method: &Method, indices: &Indices) -> Result<()> {
+    let ins_size = method.proto.parameters.len() as u16 + 1;
+    if method.registers < ins_size {
+        anyhow::bail!("Method {} has fewer registers than arguments", method.name);
+    }
+    let mut outs_size = 0;
+    let mut insns: Vec<u16> = Vec::new();
+    for instruction in &method.code {
+        match instruction {
+            Instruction::IgetObject(a, b, field) => {
+                insns.push(0x54 | (nibbles(&[*a, *b])? << 8));
+                insns.push(indices.fields[field]);
+            }
+            Instruction::NewInstance(a, descriptor) => {
+                insns.push(0x22 | (*a as u16) << 8);
+                insns.push(indices.types[descriptor]);
+            }
+            Instruction::InvokeDirect(args, method)
+            | Instruction::InvokeVirtual(args, method)
+            | Instruction::InvokeStatic(args, method) => {
+                let opcode = match instruction {
+                    Instruction::InvokeDirect(..) => 0x70,
+                    Instruction::InvokeVirtual(..) => 0x6e,
+                    _ => 0x71,
+                };
+                if args.len() > 4 {
+                    anyhow::bail!("invoke with more than 4 arguments is not supported");
+                }
+                outs_size = outs_size.max(args.len() as u16);
+                insns.push(opcode | (args.len() as u16) << 12);
+                insns.push(indices.methods[method]);
+                insns.push(nibbles(args)?);
+            }
+            Instruction::MoveResultObject(a) => insns.push(0x0c | (*a as u16) << 8),
+            Instruction::ConstString(a, value) => {
+                let index = indices.string(value);
+                let index = u16::try_from(index).context("const-string index out of range")?;
+                insns.push(0x1a | (*a as u16) << 8);
+                insns.push(index);
+            }
+            Instruction::ReturnVoid => insns.push(0x0e),
+        }
+    }
+
+    for value in [method.registers, ins_size, outs_size, 0] {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    // debug_info_off, insns_size
+    data.extend_from_slice(&0u32.to_le_bytes());
+    data.extend_from_slice(&(insns.len() as u32).to_le_bytes());
+    for insn in insns {
+        data.extend_from_slice(&insn.to_le_bytes());
+    }
+    Ok(())
+}
+
+/// Pack registers into 4-bit fields, lowest first.
+fn nibbles(registers: &[u8]) -> Result<u16> {
+    let mut packed = 0;
+    for (i, &register) in registers.iter().enumerate() {
+        if register > 0xf {
+            anyhow::bail!("Register v{register} does not fit in 4 bits");
+        }
+        packed |= (register as u16) << (i * 4);
+    }
+    Ok(packed)
+}
+
+fn align(data: &mut Vec<u8>, alignment: usize) {
+    data.resize(data.len().next_multiple_of(alignment), 0);
+}
+
+fn write_uleb128(data: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            data.push(byte);
+            return;
+        }
+        data.push(byte | 0x80);
+    }
+}
+
+/// Modified UTF-8: each UTF-16 unit on its own, NUL as two bytes.
+fn write_mutf8(data: &mut Vec<u8>, string: &[u16]) {
+    for &unit in string {
+        match unit {
+            0x01..=0x7f => data.push(unit as u8),
+            0x00 | 0x80..=0x7ff => {
+                data.push(0xc0 | (unit >> 6) as u8);
+                data.push(0x80 | (unit & 0x3f) as u8);
+            }
+            _ => {
+                data.push(0xe0 | (unit >> 12) as u8);
+                data.push(0x80 | ((unit >> 6) & 0x3f) as u8);
+                data.push(0x80 | (unit & 0x3f) as u8);
+            }
+        }
+    }
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MOD_ADLER: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= MOD_ADLER;
+        b %= MOD_ADLER;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{u16_at, u32_at};
+
+    const OBJECT: &str = "Ljava/lang/Object;";
+
+    fn constructor() -> Method {
+        Method {
+            name: "<init>".to_string(),
+            proto: Proto::new("V", &[]),
+            access_flags: ACC_PUBLIC | ACC_CONSTRUCTOR,
+            registers: 1,
+            code: vec![
+                Instruction::InvokeDirect(
+                    vec![0],
+                    MethodRef::new(OBJECT, "<init>", Proto::new("V", &[])),
+                ),
+                Instruction::ReturnVoid,
+            ],
+        }
+    }
+
+    /// A class using every ID section: an interface, a field and a
+    /// virtual method with a parameter.
+    fn test_class() -> Class {
+        const STRING: &str = "Ljava/lang/String;";
+        const HOLDER: &str = "Lcom/example/Holder;";
+        Class {
+            descriptor: "Lcom/example/Test;".to_string(),
+            superclass: OBJECT.to_string(),
+            interfaces: vec!["Ljava/lang/Runnable;".to_string()],
+            access_flags: ACC_PUBLIC,
+            direct_methods: vec![constructor()],
+            virtual_methods: vec![Method {
+                name: "load".to_string(),
+                proto: Proto::new("V", &[HOLDER]),
+                access_flags: ACC_PUBLIC,
+                registers: 3,
+                code: vec![
+                    Instruction::IgetObject(0, 2, FieldRef::new(HOLDER, "path", STRING)),
+                    Instruction::InvokeStatic(
+                        vec![0],
+                        MethodRef::new("Ljava/lang/System;", "load", Proto::new("V", &[STRING])),
+                    ),
+                    Instruction::ReturnVoid,
+                ],
+            }],
+        }
+    }
+
+    /// `(type, count, offset)` entries of the map list.
+    fn map_list(dex: &[u8]) -> Vec<(u16, u32, u32)> {
+        let map_off = u32_at(dex, 52) as usize;
+        (0..u32_at(dex, map_off) as usize)
+            .map(|index| {
+                let item = map_off + 4 + index * 12;
+                assert_eq!(u16_at(dex, item + 2), 0);
+                (
+                    u16_at(dex, item),
+                    u32_at(dex, item + 4),
+                    u32_at(dex, item + 8),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn adler32_known_answers() {
+        let naive = |data: &[u8]| {
+            let (mut a, mut b) = (1u32, 0u32);
+            for &byte in data {
+                a = (a + byte as u32) % 65521;
+                b = (b + a) % 65521;
+            }
+            (b << 16) | a
+        };
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+        // Longer than the 5552-byte chunks the sums are reduced after, with
+        // all-0xff bytes as the case closest to overflowing
+        let data: Vec<u8> = (0..20000u32).map(|i| (i * 7 % 256) as u8).collect();
+        assert_eq!(adler32(&data), naive(&data));
+        assert_eq!(adler32(&[0xff; 20000]), naive(&[0xff; 20000]));
+    }
+
+    #[test]
+    fn header_checksum_and_signature() {
+        let dex = write_dex(&test_class()).unwrap();
+
+        assert_eq!(&dex[..8], DEX_MAGIC);
+        assert_eq!(u32_at(&dex, 32) as usize, dex.len());
+        assert_eq!(u32_at(&dex, 36) as usize, HEADER_SIZE);
+        assert_eq!(u32_at(&dex, 40), ENDIAN_CONSTANT);
+        // No link section
+        assert_eq!(u32_at(&dex, 44), 0);
+        assert_eq!(u32_at(&dex, 48), 0);
+
+        // SHA-1 covers everything after the signature, Adler-32 everything
+        // after the checksum, so the signature is part of the checksum
+        assert_eq!(&dex[12..32], Sha1::digest(&dex[32..]).as_slice());
+        assert_eq!(u32_at(&dex, 8), adler32(&dex[12..]));
+
+        let mut tampered = dex.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_ne!(&tampered[12..32], Sha1::digest(&tampered[32..]).as_slice());
+        assert_ne!(u32_at(&tampered, 8), adler32(&tampered[12..]));
+    }
+
+    #[test]
+    fn map_list_matches_header() {
+        let dex = write_dex(&test_class()).unwrap();
+        let map = map_list(&dex);
+
+        // Sorted by offset, starting with the header, each type listed once
+        assert_eq!(map[0], (TYPE_HEADER_ITEM, 1, 0));
+        assert!(map.windows(2).all(|pair| pair[0].2 < pair[1].2), "{map:x?}");
+        let mut types: Vec<u16> = map.iter().map(|&(item_type, _, _)| item_type).collect();
+        types.sort();
+        types.dedup();
+        assert_eq!(types.len(), map.len());
+
+        // ID sections agree with the header, data items live in the data
+        // section, which ends the file and holds the map list itself
+        for (item_type, header_offset) in [
+            (TYPE_STRING_ID_ITEM, 56),
+            (TYPE_TYPE_ID_ITEM, 64),
+            (TYPE_PROTO_ID_ITEM, 72),
+            (TYPE_FIELD_ID_ITEM, 80),
+            (TYPE_METHOD_ID_ITEM, 88),
+            (TYPE_CLASS_DEF_ITEM, 96),
+        ] {
+            let count = u32_at(&dex, header_offset);
+            let offset = u32_at(&dex, header_offset + 4);
+            assert!(count > 0, "{item_type:#x}");
+            assert!(map.contains(&(item_type, count, offset)), "{item_type:#x}");
+        }
+        let data_size = u32_at(&dex, 104) as usize;
+        let data_off = u32_at(&dex, 108) as usize;
+        assert_eq!(data_off + data_size, dex.len());
+        let map_off = u32_at(&dex, 52);
+        assert!(map.contains(&(TYPE_MAP_LIST, 1, map_off)));
+        for item_type in [
+            TYPE_TYPE_LIST,
+            TYPE_CLASS_DATA_ITEM,
+            TYPE_CODE_ITEM,
+            TYPE_STRING_DATA_ITEM,
+        ] {
+            let &(_, count, offset) = map
+                .iter()
+                .find(|&&(map_type, _, _)| map_type == item_type)
+                .unwrap_or_else(|| panic!("{item_type:#x} missing"));
+            assert!(count > 0);
+            assert!(offset as usize >= data_off && (offset as usize) < dex.len());
+        }
+        assert_eq!(
+            u32_at(&dex, map_off as usize) as usize * 12 + 4 + map_off as usize,
+            dex.len()
+        );
+    }
+
+    #[test]
+    fn empty_sections_are_left_out() {
+        let class = Class {
+            descriptor: "Lcom/example/Empty;".to_string(),
+            superclass: OBJECT.to_string(),
+            interfaces: Vec::new(),
+            access_flags: ACC_PUBLIC,
+            direct_methods: vec![constructor()],
+            virtual_methods: Vec::new(),
+        };
+        let dex = write_dex(&class).unwrap();
+
+        // No fields: count and offset are zero and the map skips the section
+        assert_eq!(u32_at(&dex, 80), 0);
+        assert_eq!(u32_at(&dex, 84), 0);
+        let map = map_list(&dex);
+        assert!(map
+            .iter()
+            .all(|&(item_type, _, _)| item_type != TYPE_FIELD_ID_ITEM));
+        assert_eq!(u32_at(&dex, 8), adler32(&dex[12..]));
+    }
+}
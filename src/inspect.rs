@@ -0,0 +1,168 @@
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::io::Read;
+use std::path::Path;
+
+use crate::binary::BinaryProcessor;
+use crate::builder::EmbeddedConfigData;
+use crate::compression::Codec;
+use crate::signing;
+
+const BINARY_EXTENSIONS: &[&str] = &[".so", ".dll", ".dylib"];
+
+pub async fn inspect_artifact(path: &Path, key: Option<&str>) -> Result<()> {
+    info!("Inspecting artifact: {}", path.display());
+
+    for_each_packed_binary(path, |name, data| inspect_binary(name, data, key)).await
+}
+
+/// Check the script signature of every packed library in the artifact
+/// against `public_key_path`.
+pub async fn verify_artifact(path: &Path, public_key_path: &Path, key: Option<&str>) -> Result<()> {
+    info!("Verifying script signature: {}", path.display());
+    let public_key = signing::load_verifying_key(public_key_path)?;
+
+    for_each_packed_binary(path, |name, data| {
+        let (_, payload) = read_payload(name, data, key)?;
+        let (config_json, signature) = signing::split_signature(&payload)?;
+        let signature = signature.with_context(|| format!("Script in {name} is not signed"))?;
+        signing::verify(&signature, &public_key, config_json)
+            .with_context(|| format!("Failed to verify script in {name}"))?;
+        info!("✓ {name}: signed by {}", signature.key_fingerprint);
+        Ok(())
+    })
+    .await
+}
+
+/// Call `f` with the name and contents of the artifact itself, or of each
+/// packed library inside an `.apk` or zygisk `.zip`.
+async fn for_each_packed_binary(
+    path: &Path,
+    mut f: impl FnMut(&str, Vec<u8>) -> Result<()>,
+) -> Result<()> {
+    let data = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+
+    let is_archive = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("apk") || ext.eq_ignore_ascii_case("zip"));
+
+    if is_archive {
+        for_each_archive_binary(data, f)
+    } else {
+        f(&path.display().to_string(), data)
+    }
+}
+
+/// Look for packed libraries inside `.apk` and zygisk `.zip` outputs.
+fn for_each_archive_binary(
+    data: Vec<u8>,
+    mut f: impl FnMut(&str, Vec<u8>) -> Result<()>,
+) -> Result<()> {
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(data))?;
+    let mut found = 0;
+
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+        let name = entry.name().to_string();
+        if !BINARY_EXTENSIONS.iter().any(|ext| name.ends_with(ext)) {
+            continue;
+        }
+
+        let mut entry_data = Vec::with_capacity(entry.size() as usize);
+        entry.read_to_end(&mut entry_data)?;
+
+        let has_payload = BinaryProcessor::new(entry_data.clone())
+            .map(|processor| processor.find_filled_embedded_config().is_some())
+            .unwrap_or(false);
+        if !has_payload {
+            continue;
+        }
+
+        f(&name, entry_data)?;
+        found += 1;
+    }
+
+    if found == 0 {
+        warn!("No library with an embedded payload found in archive");
+    }
+    Ok(())
+}
+
+fn read_payload(
+    name: &str,
+    data: Vec<u8>,
+    key: Option<&str>,
+) -> Result<(crate::binary::EmbeddedConfig, Vec<u8>)> {
+    let processor = BinaryProcessor::new(data)?;
+    processor
+        .read_embedded_payload(key)
+        .with_context(|| format!("Failed to read embedded payload from {name}"))
+}
+
+fn inspect_binary(name: &str, data: Vec<u8>, key: Option<&str>) -> Result<()> {
+    let (embedded_config, payload) = read_payload(name, data, key)?;
+    let payload_len = payload.len();
+    let (config_json, signature) = signing::split_signature(&payload)?;
+    let config_data: EmbeddedConfigData =
+        serde_json::from_slice(config_json).context("Failed to decode embedded config data")?;
+
+    let version = embedded_config.version;
+    let data_size = embedded_config.data_size;
+    info!("→ {name}");
+    info!("  Header version: {version}");
+    if embedded_config.is_encrypted() {
+        info!("  Encrypted: yes");
+    }
+    if embedded_config.has_checksums() {
+        info!("  Checksums: verified");
+    }
+    if embedded_config.has_randomized_magic() {
+        info!(
+            "  Magic: randomized ({:#010x} {:#010x})",
+            { embedded_config.magic1 } as u32,
+            { embedded_config.magic2 } as u32
+        );
+    }
+    let codec = embedded_config.codec()?;
+    if codec != Codec::None {
+        info!(
+            "  Payload: {} bytes ({}, {} bytes decompressed)",
+            data_size,
+            codec.name(),
+            payload_len
+        );
+    } else {
+        info!("  Payload: {data_size} bytes");
+    }
+    info!("  Mode: {:?}", config_data.mode);
+    if let Some(signature) = &signature {
+        info!(
+            "  Signed by: {} ({})",
+            signature.key_fingerprint, signature.algorithm
+        );
+    }
+    if let Some(js_filepath) = &config_data.js_filepath {
+        info!("  JS file path: {js_filepath}");
+    }
+    if let Some(watch_path) = &config_data.watch_path {
+        info!("  Watch path: {watch_path}");
+    }
+    if let Some(bundle) = &config_data.bundle {
+        info!(
+            "  Bundle (version {}, entry {}):",
+            bundle.version, bundle.entry
+        );
+        for file in &bundle.files {
+            info!("    {} ({} bytes)", file.path, file.data.len());
+        }
+    }
+    if let Some(js_content) = &config_data.js_content {
+        info!("  Script ({} bytes):", js_content.len());
+        println!("{js_content}");
+    }
+
+    Ok(())
+}
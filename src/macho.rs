@@ -0,0 +1,542 @@
+use anyhow::{Context, Result};
+use log::info;
+use object::{
+    macho::{
+        self, DyldInfoCommand, DylibCommand, DysymtabCommand, LinkeditDataCommand, MachHeader64,
+        SegmentCommand64, SymtabCommand,
+    },
+    pod, LittleEndian as LE, U32,
+};
+
+/// Segment alignment used for anything we add. 16K pages are required on
+/// arm64 and also valid on x86_64.
+pub const SEGMENT_ALIGN: u64 = 0x4000;
+
+const HEADER_SIZE: usize = std::mem::size_of::<MachHeader64<LE>>();
+const SEGMENT_COMMAND_SIZE: usize = std::mem::size_of::<SegmentCommand64<LE>>();
+const SECTION_SIZE: usize = std::mem::size_of::<macho::Section64<LE>>();
+const DYLIB_COMMAND_SIZE: usize = std::mem::size_of::<DylibCommand<LE>>();
+
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub name: String,
+    pub cmd_offset: usize,
+    pub vmaddr: u64,
+    pub vmsize: u64,
+    pub fileoff: u64,
+    pub filesize: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LoadCommandRef {
+    pub offset: usize,
+    pub cmd: u32,
+    pub cmdsize: u32,
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
+
+fn fixed_name(name: &str) -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    bytes[..name.len()].copy_from_slice(name.as_bytes());
+    bytes
+}
+
+fn read_name(name: &[u8; 16]) -> String {
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..len]).to_string()
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Universal binaries hold one Mach-O per architecture and are not
+/// patched; ask for a single slice instead.
+pub fn ensure_thin(data: &[u8]) -> Result<()> {
+    if matches!(
+        object::FileKind::parse(data),
+        Ok(object::FileKind::MachOFat32 | object::FileKind::MachOFat64)
+    ) {
+        anyhow::bail!(
+            "Universal (fat) Mach-O binaries are not supported, extract one architecture first with `lipo <file> -thin <arch> -output <thin file>`"
+        );
+    }
+    Ok(())
+}
+
+pub fn header(data: &[u8]) -> Result<&MachHeader64<LE>> {
+    ensure_thin(data)?;
+    let (header, _) = pod::from_bytes::<MachHeader64<LE>>(data)
+        .map_err(|_| anyhow::anyhow!("Mach-O header is truncated"))?;
+    // `magic` is always read big-endian, so a little-endian file shows up as CIGAM.
+    if header.magic.get(object::BigEndian) != macho::MH_CIGAM_64 {
+        anyhow::bail!("Only 64-bit little-endian Mach-O binaries are supported");
+    }
+    Ok(header)
+}
+
+fn header_mut(data: &mut [u8]) -> Result<&mut MachHeader64<LE>> {
+    header(data)?;
+    let (header, _) = pod::from_bytes_mut::<MachHeader64<LE>>(data)
+        .map_err(|_| anyhow::anyhow!("Mach-O header is truncated"))?;
+    Ok(header)
+}
+
+pub fn load_commands(data: &[u8]) -> Result<Vec<LoadCommandRef>> {
+    let header = header(data)?;
+    let ncmds = header.ncmds.get(LE);
+    let end = HEADER_SIZE + header.sizeofcmds.get(LE) as usize;
+    if end > data.len() {
+        anyhow::bail!("Mach-O load commands exceed file size");
+    }
+
+    let mut commands = Vec::with_capacity(ncmds as usize);
+    let mut offset = HEADER_SIZE;
+    for _ in 0..ncmds {
+        if offset + 8 > end {
+            anyhow::bail!("Mach-O load command at {offset:#x} is truncated");
+        }
+        let cmd = read_u32(data, offset);
+        let cmdsize = read_u32(data, offset + 4);
+        if cmdsize < 8 || offset + cmdsize as usize > end {
+            anyhow::bail!("Invalid Mach-O load command size {cmdsize} at {offset:#x}");
+        }
+        commands.push(LoadCommandRef {
+            offset,
+            cmd,
+            cmdsize,
+        });
+        offset += cmdsize as usize;
+    }
+    Ok(commands)
+}
+
+pub fn segments(data: &[u8]) -> Result<Vec<Segment>> {
+    let mut segments = Vec::new();
+    for command in load_commands(data)? {
+        if command.cmd != macho::LC_SEGMENT_64 {
+            continue;
+        }
+        let (segment, _) = pod::from_bytes::<SegmentCommand64<LE>>(&data[command.offset..])
+            .map_err(|_| anyhow::anyhow!("Mach-O segment command is truncated"))?;
+        segments.push(Segment {
+            name: read_name(&segment.segname),
+            cmd_offset: command.offset,
+            vmaddr: segment.vmaddr.get(LE),
+            vmsize: segment.vmsize.get(LE),
+            fileoff: segment.fileoff.get(LE),
+            filesize: segment.filesize.get(LE),
+        });
+    }
+    Ok(segments)
+}
+
+/// Number of unused bytes between the end of the load commands and the
+/// first section (or segment) contents.
+pub fn header_padding(data: &[u8]) -> Result<u64> {
+    let commands_end = (HEADER_SIZE + header(data)?.sizeofcmds.get(LE) as usize) as u64;
+    let mut first_content = data.len() as u64;
+
+    for command in load_commands(data)? {
+        if command.cmd != macho::LC_SEGMENT_64 {
+            continue;
+        }
+        let (segment, rest) = pod::from_bytes::<SegmentCommand64<LE>>(&data[command.offset..])
+            .map_err(|_| anyhow::anyhow!("Mach-O segment command is truncated"))?;
+        let fileoff = segment.fileoff.get(LE);
+        if fileoff != 0 && segment.filesize.get(LE) != 0 {
+            first_content = first_content.min(fileoff);
+        }
+        let (sections, _) =
+            pod::slice_from_bytes::<macho::Section64<LE>>(rest, segment.nsects.get(LE) as usize)
+                .map_err(|_| anyhow::anyhow!("Mach-O section headers are truncated"))?;
+        for section in sections {
+            let section_type = section.flags.get(LE) & macho::SECTION_TYPE;
+            let offset = section.offset.get(LE) as u64;
+            if offset != 0
+                && section_type != macho::S_ZEROFILL
+                && section_type != macho::S_GB_ZEROFILL
+                && section_type != macho::S_THREAD_LOCAL_ZEROFILL
+            {
+                first_content = first_content.min(offset);
+            }
+        }
+    }
+
+    Ok(first_content.saturating_sub(commands_end))
+}
+
+/// Insert a load command before the command at `before`, or at the end of
+/// the load commands if `before` is `None`.
+pub fn insert_load_command(data: &mut [u8], command: &[u8], before: Option<usize>) -> Result<()> {
+    let padding = header_padding(data)?;
+    if (command.len() as u64) > padding {
+        anyhow::bail!(
+            "Not enough Mach-O header padding to add a load command (need {} bytes, have {})",
+            command.len(),
+            padding
+        );
+    }
+
+    let header = header_mut(data)?;
+    let commands_end = HEADER_SIZE + header.sizeofcmds.get(LE) as usize;
+    header.ncmds.set(LE, header.ncmds.get(LE) + 1);
+    header
+        .sizeofcmds
+        .set(LE, header.sizeofcmds.get(LE) + command.len() as u32);
+
+    let at = before.unwrap_or(commands_end);
+    data.copy_within(at..commands_end, at + command.len());
+    data[at..at + command.len()].copy_from_slice(command);
+    Ok(())
+}
+
+/// Install names of the libraries loaded by `LC_LOAD_DYLIB`,
+/// `LC_LOAD_WEAK_DYLIB` and friends.
+pub fn dylibs(data: &[u8]) -> Result<Vec<String>> {
+    let mut dylibs = Vec::new();
+    for command in load_commands(data)? {
+        if !matches!(
+            command.cmd,
+            macho::LC_LOAD_DYLIB
+                | macho::LC_LOAD_WEAK_DYLIB
+                | macho::LC_REEXPORT_DYLIB
+                | macho::LC_LAZY_LOAD_DYLIB
+                | macho::LC_LOAD_UPWARD_DYLIB
+        ) {
+            continue;
+        }
+        let bytes = &data[command.offset..command.offset + command.cmdsize as usize];
+        let (dylib, _) = pod::from_bytes::<DylibCommand<LE>>(bytes)
+            .map_err(|_| anyhow::anyhow!("Mach-O dylib command is truncated"))?;
+        let name = bytes
+            .get(dylib.dylib.name.offset.get(LE) as usize..)
+            .context("Mach-O dylib name is out of range")?;
+        let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+        dylibs.push(String::from_utf8_lossy(&name[..len]).to_string());
+    }
+    Ok(dylibs)
+}
+
+/// Add an `LC_LOAD_DYLIB` (or `LC_LOAD_WEAK_DYLIB`) command for `path` after
+/// the existing load commands. dyld fails to launch the binary if a
+/// non-weak library is missing; a weak one is skipped.
+pub fn add_dylib(data: &mut [u8], path: &str, weak: bool) -> Result<()> {
+    let command_size = align_up((DYLIB_COMMAND_SIZE + path.len() + 1) as u64, 8) as usize;
+    let mut command = vec![0u8; command_size];
+    {
+        let (dylib, _) = pod::from_bytes_mut::<DylibCommand<LE>>(&mut command)
+            .map_err(|_| anyhow::anyhow!("Failed to build dylib command"))?;
+        let cmd = if weak {
+            macho::LC_LOAD_WEAK_DYLIB
+        } else {
+            macho::LC_LOAD_DYLIB
+        };
+        dylib.cmd.set(LE, cmd);
+        dylib.cmdsize.set(LE, command_size as u32);
+        dylib.dylib.name.offset.set(LE, DYLIB_COMMAND_SIZE as u32);
+        dylib.dylib.timestamp.set(LE, 2);
+        dylib.dylib.current_version.set(LE, 0x10000);
+        dylib.dylib.compatibility_version.set(LE, 0x10000);
+    }
+    command[DYLIB_COMMAND_SIZE..DYLIB_COMMAND_SIZE + path.len()].copy_from_slice(path.as_bytes());
+
+    insert_load_command(data, &command, None)
+        .with_context(|| format!("Failed to add load command for {path}"))
+}
+
+/// Shift every `__LINKEDIT` file offset referenced by a load command that
+/// satisfies `should_shift` by `delta` bytes.
+fn shift_linkedit_offsets(
+    data: &mut [u8],
+    should_shift: impl Fn(u32) -> bool,
+    delta: u32,
+) -> Result<()> {
+    let shift = |field: &mut U32<LE>| {
+        let value = field.get(LE);
+        if value != 0 && should_shift(value) {
+            field.set(LE, value + delta);
+        }
+    };
+
+    for command in load_commands(data)? {
+        let bytes = &mut data[command.offset..command.offset + command.cmdsize as usize];
+        match command.cmd {
+            macho::LC_SYMTAB => {
+                let (symtab, _) = pod::from_bytes_mut::<SymtabCommand<LE>>(bytes)
+                    .map_err(|_| anyhow::anyhow!("LC_SYMTAB is truncated"))?;
+                shift(&mut symtab.symoff);
+                shift(&mut symtab.stroff);
+            }
+            macho::LC_DYSYMTAB => {
+                let (dysymtab, _) = pod::from_bytes_mut::<DysymtabCommand<LE>>(bytes)
+                    .map_err(|_| anyhow::anyhow!("LC_DYSYMTAB is truncated"))?;
+                shift(&mut dysymtab.tocoff);
+                shift(&mut dysymtab.modtaboff);
+                shift(&mut dysymtab.extrefsymoff);
+                shift(&mut dysymtab.indirectsymoff);
+                shift(&mut dysymtab.extreloff);
+                shift(&mut dysymtab.locreloff);
+            }
+            macho::LC_DYLD_INFO | macho::LC_DYLD_INFO_ONLY => {
+                let (dyld_info, _) = pod::from_bytes_mut::<DyldInfoCommand<LE>>(bytes)
+                    .map_err(|_| anyhow::anyhow!("LC_DYLD_INFO is truncated"))?;
+                shift(&mut dyld_info.rebase_off);
+                shift(&mut dyld_info.bind_off);
+                shift(&mut dyld_info.weak_bind_off);
+                shift(&mut dyld_info.lazy_bind_off);
+                shift(&mut dyld_info.export_off);
+            }
+            macho::LC_CODE_SIGNATURE
+            | macho::LC_SEGMENT_SPLIT_INFO
+            | macho::LC_FUNCTION_STARTS
+            | macho::LC_DATA_IN_CODE
+            | macho::LC_DYLIB_CODE_SIGN_DRS
+            | macho::LC_LINKER_OPTIMIZATION_HINT
+            | macho::LC_DYLD_EXPORTS_TRIE
+            | macho::LC_DYLD_CHAINED_FIXUPS => {
+                let (linkedit_data, _) = pod::from_bytes_mut::<LinkeditDataCommand<LE>>(bytes)
+                    .map_err(|_| anyhow::anyhow!("Linkedit data command is truncated"))?;
+                shift(&mut linkedit_data.dataoff);
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// dyld checks that `dyld_chained_starts_in_image.seg_count` matches the
+/// number of segments, so adding a segment in front of `__LINKEDIT` needs an
+/// extra (empty) entry in the starts table.
+fn add_chained_fixups_segment(data: &mut Vec<u8>, segment_index: usize) -> Result<()> {
+    let Some(command) = load_commands(data)?
+        .into_iter()
+        .find(|cmd| cmd.cmd == macho::LC_DYLD_CHAINED_FIXUPS)
+    else {
+        return Ok(());
+    };
+    let (fixups, _) = pod::from_bytes::<LinkeditDataCommand<LE>>(&data[command.offset..])
+        .map_err(|_| anyhow::anyhow!("LC_DYLD_CHAINED_FIXUPS is truncated"))?;
+    let blob_offset = fixups.dataoff.get(LE) as usize;
+    let blob_size = fixups.datasize.get(LE) as usize;
+    let mut blob = data
+        .get(blob_offset..blob_offset + blob_size)
+        .context("Chained fixups data is out of range")?
+        .to_vec();
+
+    let starts_offset = read_u32(&blob, 4) as usize;
+    let seg_count = read_u32(&blob, starts_offset) as usize;
+    if seg_count <= segment_index {
+        // Trailing segments without fixups may be omitted from the table.
+        return Ok(());
+    }
+
+    // Insert a zero `seg_info_offset` for the new segment and keep the blob
+    // 8-byte aligned.
+    let insert_at = starts_offset + 4 + segment_index * 4;
+    blob.splice(insert_at..insert_at, [0u8; 4]);
+    blob.extend_from_slice(&[0u8; 4]);
+    write_u32(&mut blob, starts_offset, seg_count as u32 + 1);
+    for i in 0..=seg_count {
+        let field = starts_offset + 4 + i * 4;
+        let value = read_u32(&blob, field);
+        if value != 0 {
+            write_u32(&mut blob, field, value + 4);
+        }
+    }
+    for field in [8, 12] {
+        let value = read_u32(&blob, field) as usize;
+        if value > starts_offset {
+            write_u32(&mut blob, field, value as u32 + 4);
+        }
+    }
+
+    let growth = (blob.len() - blob_size) as u32;
+    data.splice(blob_offset..blob_offset + blob_size, blob);
+    shift_linkedit_offsets(data, |offset| offset as usize > blob_offset, growth)?;
+    write_u32(data, command.offset + 12, (blob_size as u32) + growth);
+
+    let linkedit = segments(data)?
+        .into_iter()
+        .find(|seg| seg.name == "__LINKEDIT")
+        .context("Chained fixups present without __LINKEDIT segment")?;
+    let (segment, _) =
+        pod::from_bytes_mut::<SegmentCommand64<LE>>(&mut data[linkedit.cmd_offset..])
+            .map_err(|_| anyhow::anyhow!("Mach-O segment command is truncated"))?;
+    let filesize = linkedit.filesize + growth as u64;
+    segment.filesize.set(LE, filesize);
+    segment
+        .vmsize
+        .set(LE, linkedit.vmsize.max(align_up(filesize, SEGMENT_ALIGN)));
+    Ok(())
+}
+
+/// Add a new read/write segment with a single section holding `payload`.
+///
+/// The segment is placed directly in front of `__LINKEDIT` (which is moved
+/// back in both file and address space) so that `__LINKEDIT` stays the last
+/// segment, as dyld and codesign expect.
+pub fn add_segment(
+    data: &mut Vec<u8>,
+    segname: &str,
+    sectname: &str,
+    payload: &[u8],
+) -> Result<Segment> {
+    let command_size = SEGMENT_COMMAND_SIZE + SECTION_SIZE;
+    if (command_size as u64) > header_padding(data)? {
+        anyhow::bail!(
+            "Not enough Mach-O header padding to add segment {segname} (need {command_size} bytes)"
+        );
+    }
+
+    let existing = segments(data)?;
+    let linkedit_index = existing.iter().position(|seg| seg.name == "__LINKEDIT");
+    let aligned_size = align_up(payload.len() as u64, SEGMENT_ALIGN);
+
+    let (fileoff, vmaddr, insert_before) = match linkedit_index {
+        Some(index) => {
+            let linkedit = &existing[index];
+            (linkedit.fileoff, linkedit.vmaddr, Some(linkedit.cmd_offset))
+        }
+        None => {
+            let vm_end = existing
+                .iter()
+                .map(|seg| seg.vmaddr + seg.vmsize)
+                .max()
+                .unwrap_or(0);
+            (
+                align_up(data.len() as u64, SEGMENT_ALIGN),
+                align_up(vm_end, SEGMENT_ALIGN),
+                None,
+            )
+        }
+    };
+    info!("Adding Mach-O segment {segname} at file offset {fileoff:#x}, vmaddr {vmaddr:#x}");
+
+    let mut command = vec![0u8; command_size];
+    {
+        let (segment, rest) = pod::from_bytes_mut::<SegmentCommand64<LE>>(&mut command)
+            .map_err(|_| anyhow::anyhow!("Failed to build segment command"))?;
+        segment.cmd.set(LE, macho::LC_SEGMENT_64);
+        segment.cmdsize.set(LE, command_size as u32);
+        segment.segname = fixed_name(segname);
+        segment.vmaddr.set(LE, vmaddr);
+        segment.vmsize.set(LE, aligned_size);
+        segment.fileoff.set(LE, fileoff);
+        segment.filesize.set(LE, aligned_size);
+        segment
+            .maxprot
+            .set(LE, macho::VM_PROT_READ | macho::VM_PROT_WRITE);
+        segment
+            .initprot
+            .set(LE, macho::VM_PROT_READ | macho::VM_PROT_WRITE);
+        segment.nsects.set(LE, 1);
+
+        let (section, _) = pod::from_bytes_mut::<macho::Section64<LE>>(rest)
+            .map_err(|_| anyhow::anyhow!("Failed to build section header"))?;
+        section.sectname = fixed_name(sectname);
+        section.segname = fixed_name(segname);
+        section.addr.set(LE, vmaddr);
+        section.size.set(LE, payload.len() as u64);
+        section.offset.set(LE, fileoff as u32);
+        section.align.set(LE, 4);
+        section.flags.set(LE, macho::S_REGULAR);
+    }
+
+    if let Some(index) = linkedit_index {
+        add_chained_fixups_segment(data, index)?;
+
+        // Move __LINKEDIT (and everything it references) behind the payload.
+        let linkedit = segments(data)?.swap_remove(index);
+        shift_linkedit_offsets(
+            data,
+            |offset| offset as u64 >= linkedit.fileoff,
+            aligned_size as u32,
+        )?;
+        let (segment, _) =
+            pod::from_bytes_mut::<SegmentCommand64<LE>>(&mut data[linkedit.cmd_offset..])
+                .map_err(|_| anyhow::anyhow!("Mach-O segment command is truncated"))?;
+        segment.fileoff.set(LE, linkedit.fileoff + aligned_size);
+        segment.vmaddr.set(LE, linkedit.vmaddr + aligned_size);
+
+        let mut contents = payload.to_vec();
+        contents.resize(aligned_size as usize, 0);
+        let at = fileoff as usize;
+        data.splice(at..at, contents);
+    } else {
+        data.resize(fileoff as usize, 0);
+        data.extend_from_slice(payload);
+        data.resize((fileoff + aligned_size) as usize, 0);
+    }
+
+    insert_load_command(data, &command, insert_before)?;
+
+    segments(data)?
+        .into_iter()
+        .find(|seg| seg.name == segname && seg.fileoff == fileoff)
+        .context("Failed to find the added segment")
+}
+
+/// Replace the contents of a segment added by `add_segment`, growing it (and
+/// moving `__LINKEDIT` back) when `payload` no longer fits.
+pub fn set_segment_payload(data: &mut Vec<u8>, segname: &str, payload: &[u8]) -> Result<Segment> {
+    let existing = segments(data)?;
+    let segment = existing
+        .iter()
+        .find(|seg| seg.name == segname)
+        .with_context(|| format!("Failed to find Mach-O segment {segname}"))?
+        .clone();
+
+    let aligned_size = align_up(payload.len() as u64, SEGMENT_ALIGN);
+    if aligned_size > segment.filesize {
+        let delta = aligned_size - segment.filesize;
+        info!("Growing Mach-O segment {segname} by {delta:#x} bytes");
+
+        let segment_end = segment.fileoff + segment.filesize;
+        if let Some(linkedit) = existing
+            .iter()
+            .find(|seg| seg.name == "__LINKEDIT" && seg.fileoff >= segment_end)
+        {
+            shift_linkedit_offsets(
+                data,
+                |offset| offset as u64 >= linkedit.fileoff,
+                delta as u32,
+            )?;
+            let (command, _) =
+                pod::from_bytes_mut::<SegmentCommand64<LE>>(&mut data[linkedit.cmd_offset..])
+                    .map_err(|_| anyhow::anyhow!("Mach-O segment command is truncated"))?;
+            command.fileoff.set(LE, linkedit.fileoff + delta);
+            command.vmaddr.set(LE, linkedit.vmaddr + delta);
+        }
+        let at = segment_end as usize;
+        data.splice(at..at, std::iter::repeat(0u8).take(delta as usize));
+    }
+    let size = aligned_size.max(segment.filesize);
+
+    let (command, rest) =
+        pod::from_bytes_mut::<SegmentCommand64<LE>>(&mut data[segment.cmd_offset..])
+            .map_err(|_| anyhow::anyhow!("Mach-O segment command is truncated"))?;
+    command.vmsize.set(LE, size);
+    command.filesize.set(LE, size);
+    if command.nsects.get(LE) > 0 {
+        let (section, _) = pod::from_bytes_mut::<macho::Section64<LE>>(rest)
+            .map_err(|_| anyhow::anyhow!("Mach-O section header is truncated"))?;
+        section.size.set(LE, payload.len() as u64);
+    }
+
+    let start = segment.fileoff as usize;
+    data[start..start + size as usize].fill(0);
+    data[start..start + payload.len()].copy_from_slice(payload);
+
+    segments(data)?
+        .into_iter()
+        .find(|seg| seg.name == segname)
+        .context("Failed to find the updated segment")
+}
@@ -13,10 +13,24 @@ use std::{
     time::Duration,
 };
 
+mod apk;
+mod authenticode;
 mod binary;
 mod builder;
+mod bundle;
+mod codesign;
+mod compression;
 mod config;
+mod crypto;
+mod dex;
 mod downloader;
+mod inspect;
+mod macho;
+mod pe_resource;
+mod signing;
+#[cfg(test)]
+mod test_util;
+mod validate;
 
 use builder::Builder;
 use config::FripackConfig;
@@ -45,12 +59,40 @@ enum Commands {
     Build {
         /// Specific target to build (optional, builds all if not specified)
         target: Option<String>,
+        /// Print the planned changes to the binaries without writing anything
+        #[arg(long)]
+        dry_run: bool,
     },
     /// Watch and auto-rebuild targets when files change
     Watch {
         /// Specific target to watch (required)
         target: String,
     },
+    /// Decode and print the payload embedded in a built artifact
+    Inspect {
+        /// Path to a built .so/.dll/.dylib, .apk or zygisk .zip
+        artifact: PathBuf,
+        /// Passphrase or package name for payloads encrypted with a derived key
+        #[arg(long)]
+        key: Option<String>,
+    },
+    /// Generate an Ed25519 key pair for `signing.scriptKey`
+    KeygenScript {
+        /// Path of the private key; the public key is written next to it as .pub.pem
+        #[arg(short, long, default_value = "fripack-script.pem")]
+        out: PathBuf,
+    },
+    /// Check the script signature of a built artifact
+    VerifyScript {
+        /// Path to a built .so/.dll/.dylib, .apk or zygisk .zip
+        artifact: PathBuf,
+        /// Public key written by keygen-script
+        #[arg(long)]
+        public_key: PathBuf,
+        /// Passphrase or package name for payloads encrypted with a derived key
+        #[arg(long)]
+        key: Option<String>,
+    },
     /// Cache management commands
     Cache {
         #[command(subcommand)]
@@ -79,12 +121,25 @@ async fn main() -> Result<()> {
         Commands::Init { path } => {
             init_config(path).await?;
         }
-        Commands::Build { target } => {
-            build_target(target).await?;
+        Commands::Build { target, dry_run } => {
+            build_target(target, dry_run).await?;
         }
         Commands::Watch { target } => {
             watch_target(target).await?;
         }
+        Commands::Inspect { artifact, key } => {
+            inspect::inspect_artifact(&artifact, key.as_deref()).await?;
+        }
+        Commands::KeygenScript { out } => {
+            keygen_script(&out)?;
+        }
+        Commands::VerifyScript {
+            artifact,
+            public_key,
+            key,
+        } => {
+            inspect::verify_artifact(&artifact, &public_key, key.as_deref()).await?;
+        }
         Commands::Cache { action } => {
             handle_cache_action(action).await?;
         }
@@ -117,6 +172,16 @@ async fn init_config(path: PathBuf) -> Result<()> {
     Ok(())
 }
 
+fn keygen_script(out: &Path) -> Result<()> {
+    let public_key_path = signing::generate_key_pair(out)?;
+    let public_key = signing::load_verifying_key(&public_key_path)?;
+    info!("✓ Created private key: {}", out.display());
+    info!("✓ Created public key: {}", public_key_path.display());
+    info!("  Fingerprint: {}", signing::fingerprint(&public_key));
+
+    Ok(())
+}
+
 fn load_config(path: &PathBuf, watch_mode: bool) -> Result<ResolvedConfig> {
     let config_content = std::fs::read_to_string(path)?;
     let config: FripackConfig = json5::from_str(&config_content)?;
@@ -133,7 +198,7 @@ fn load_config(path: &PathBuf, watch_mode: bool) -> Result<ResolvedConfig> {
     Ok(resolved_config)
 }
 
-async fn build_target(target: Option<String>) -> Result<()> {
+async fn build_target(target: Option<String>, dry_run: bool) -> Result<()> {
     info!("Building fripack targets...");
 
     let config_path = find_config_file(std::env::current_dir()?)?;
@@ -151,23 +216,33 @@ async fn build_target(target: Option<String>) -> Result<()> {
                 .context("Failed to find the target")?;
             info!("→ Building target: {target_name}");
             let mut builder = Builder::new();
+            builder.set_dry_run(dry_run);
             builder.build_target(&target_name, target_config).await?;
-            info!("✓ Successfully built target: {target_name}");
+            if !dry_run {
+                info!("✓ Successfully built target: {target_name}");
+            }
         }
         None => {
             info!("Building all targets...");
             let mut builder = Builder::new();
+            builder.set_dry_run(dry_run);
 
             for (target_name, target_config) in &resolved_config.targets {
                 info!("→ Building target: {target_name}");
                 builder.build_target(target_name, target_config).await?;
             }
 
-            info!("✓ Successfully built all targets!");
+            if !dry_run {
+                info!("✓ Successfully built all targets!");
+            }
         }
     }
 
-    info!("✓ All builds completed successfully!");
+    if dry_run {
+        info!("✓ Dry run finished, nothing was written");
+    } else {
+        info!("✓ All builds completed successfully!");
+    }
     Ok(())
 }
 
@@ -0,0 +1,353 @@
+use anyhow::{Context, Result};
+use object::{
+    pe,
+    read::pe::{ImageNtHeaders, ResourceDirectoryEntryData, ResourceNameOrId},
+    LittleEndian as LE,
+};
+use std::collections::BTreeMap;
+
+/// Resource type of `VS_VERSIONINFO`.
+pub const RT_VERSION: u16 = 16;
+/// U.S. English, the language every version resource is written in.
+const LANGUAGE_EN_US: u16 = 0x0409;
+/// Unicode (UTF-16LE) code page used for the string table.
+const CODE_PAGE_UNICODE: u16 = 1200;
+
+/// Resource type, name or language. Named entries sort before IDs, as the
+/// resource directory format requires.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ResourceName {
+    Name(Vec<u16>),
+    Id(u16),
+}
+
+#[derive(Debug, Clone)]
+pub struct ResourceData {
+    pub data: Vec<u8>,
+    pub code_page: u32,
+}
+
+/// Type → name → language → data, the fixed three levels of a `.rsrc`
+/// section.
+pub type Resources =
+    BTreeMap<ResourceName, BTreeMap<ResourceName, BTreeMap<ResourceName, ResourceData>>>;
+
+/// Fields of the `VS_VERSIONINFO` resource.
+#[derive(Debug, Clone, Default)]
+pub struct VersionInfo {
+    /// Dotted version, e.g. `1.2.3`. Up to four numeric parts go into the
+    /// fixed file info; the string is stored as is.
+    pub version: String,
+    pub file_type_dll: bool,
+    /// `StringFileInfo` entries such as `CompanyName`, in order.
+    pub strings: Vec<(String, String)>,
+}
+
+impl VersionInfo {
+    /// Parse the numeric parts of `version`, ignoring suffixes like `-beta`.
+    fn version_numbers(&self) -> Result<[u16; 4]> {
+        let mut numbers = [0u16; 4];
+        let parts: Vec<&str> = self.version.split('.').collect();
+        if parts.len() > 4 {
+            anyhow::bail!("Version {} has more than four parts", self.version);
+        }
+        for (number, part) in numbers.iter_mut().zip(parts) {
+            let digits: String = part.chars().take_while(char::is_ascii_digit).collect();
+            *number = digits.parse().with_context(|| {
+                format!(
+                    "Version {} must start each part with a number up to 65535",
+                    self.version
+                )
+            })?;
+        }
+        Ok(numbers)
+    }
+
+    /// The binary `VS_VERSIONINFO` structure.
+    pub fn to_resource(&self) -> Result<Vec<u8>> {
+        let [major, minor, patch, build] = self.version_numbers()?.map(u32::from);
+        let version_ms = (major << 16) | minor;
+        let version_ls = (patch << 16) | build;
+
+        // VFT_DLL or VFT_APP
+        let file_type = if self.file_type_dll { 2 } else { 1 };
+
+        let mut fixed_file_info = Vec::with_capacity(52);
+        for value in [
+            0xfeef04bd, // dwSignature
+            0x00010000, // dwStrucVersion
+            version_ms, // dwFileVersionMS
+            version_ls, // dwFileVersionLS
+            version_ms, // dwProductVersionMS
+            version_ls, // dwProductVersionLS
+            0x3f,       // dwFileFlagsMask
+            0,          // dwFileFlags
+            0x00040004, // dwFileOS: VOS_NT_WINDOWS32
+            file_type,  // dwFileType
+            0,          // dwFileSubtype
+            0,          // dwFileDateMS
+            0,          // dwFileDateLS
+        ] {
+            fixed_file_info.extend_from_slice(&u32::to_le_bytes(value));
+        }
+
+        let strings = self
+            .strings
+            .iter()
+            .map(|(key, value)| {
+                let value = utf16z(value);
+                version_node(key, 1, (value.len() / 2) as u16, &value, &[])
+            })
+            .collect::<Vec<_>>();
+        let string_table = version_node(
+            &format!("{LANGUAGE_EN_US:04X}{CODE_PAGE_UNICODE:04X}"),
+            1,
+            0,
+            &[],
+            &strings,
+        );
+        let string_file_info = version_node("StringFileInfo", 1, 0, &[], &[string_table]);
+
+        let translation = ((CODE_PAGE_UNICODE as u32) << 16) | LANGUAGE_EN_US as u32;
+        let var = version_node("Translation", 0, 4, &translation.to_le_bytes(), &[]);
+        let var_file_info = version_node("VarFileInfo", 1, 0, &[], &[var]);
+
+        Ok(version_node(
+            "VS_VERSION_INFO",
+            0,
+            fixed_file_info.len() as u16,
+            &fixed_file_info,
+            &[string_file_info, var_file_info],
+        ))
+    }
+}
+
+/// One node of a version resource: `wLength`, `wValueLength`, `wType`, the
+/// key, then the value and children, each aligned to 4 bytes.
+fn version_node(
+    key: &str,
+    value_type: u16,
+    value_length: u16,
+    value: &[u8],
+    children: &[Vec<u8>],
+) -> Vec<u8> {
+    let mut node = vec![0; 6];
+    node[2..4].copy_from_slice(&value_length.to_le_bytes());
+    node[4..6].copy_from_slice(&value_type.to_le_bytes());
+    node.extend_from_slice(&utf16z(key));
+    pad4(&mut node);
+    node.extend_from_slice(value);
+    for child in children {
+        pad4(&mut node);
+        node.extend_from_slice(child);
+    }
+    let length = node.len() as u16;
+    node[0..2].copy_from_slice(&length.to_le_bytes());
+    node
+}
+
+fn utf16z(text: &str) -> Vec<u8> {
+    text.encode_utf16()
+        .chain([0])
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+fn pad4(data: &mut Vec<u8>) {
+    data.resize(data.len().next_multiple_of(4), 0);
+}
+
+/// Read the resource tree of a PE image, if it has one.
+pub fn read_resources<Pe: ImageNtHeaders>(data: &[u8]) -> Result<Resources> {
+    let file = object::read::pe::PeFile::<Pe>::parse(data)?;
+    let mut resources = Resources::new();
+    let Some(directory) = file
+        .data_directories()
+        .resource_directory(data, &file.section_table())?
+    else {
+        return Ok(resources);
+    };
+
+    let name = |name_or_id: ResourceNameOrId| -> Result<ResourceName> {
+        Ok(match name_or_id {
+            ResourceNameOrId::Id(id) => ResourceName::Id(id),
+            ResourceNameOrId::Name(name) => {
+                ResourceName::Name(name.data(directory)?.iter().map(|c| c.get(LE)).collect())
+            }
+        })
+    };
+    let table = |entry: &pe::ImageResourceDirectoryEntry| {
+        entry
+            .data(directory)?
+            .table()
+            .context("Unsupported resource directory layout")
+    };
+
+    for type_entry in directory.root()?.entries {
+        let names = resources.entry(name(type_entry.name_or_id())?).or_default();
+        for name_entry in table(type_entry)?.entries {
+            let languages = names.entry(name(name_entry.name_or_id())?).or_default();
+            for language_entry in table(name_entry)?.entries {
+                let ResourceDirectoryEntryData::Data(data_entry) =
+                    language_entry.data(directory)?
+                else {
+                    anyhow::bail!("Unsupported resource directory layout");
+                };
+                let resource_data = file
+                    .section_table()
+                    .pe_data_at(data, data_entry.offset_to_data.get(LE))
+                    .and_then(|bytes| bytes.get(..data_entry.size.get(LE) as usize))
+                    .context("Resource data is outside of the image")?;
+                languages.insert(
+                    name(language_entry.name_or_id())?,
+                    ResourceData {
+                        data: resource_data.to_vec(),
+                        code_page: data_entry.code_page.get(LE),
+                    },
+                );
+            }
+        }
+    }
+    Ok(resources)
+}
+
+/// Replace any version resource with `version_info`.
+pub fn set_version_info(resources: &mut Resources, version_info: &VersionInfo) -> Result<()> {
+    let data = version_info.to_resource()?;
+    let mut languages = BTreeMap::new();
+    languages.insert(
+        ResourceName::Id(LANGUAGE_EN_US),
+        ResourceData { data, code_page: 0 },
+    );
+    let mut names = BTreeMap::new();
+    names.insert(ResourceName::Id(1), languages);
+    resources.insert(ResourceName::Id(RT_VERSION), names);
+    Ok(())
+}
+
+/// Serialize `resources` as a `.rsrc` section placed at `rva`: directory
+/// tables breadth first, then names, data entries and the data itself.
+pub fn build_section(resources: &Resources, rva: u32) -> Vec<u8> {
+    const TABLE_HEADER_SIZE: usize = 16;
+    const ENTRY_SIZE: usize = 8;
+    const DATA_ENTRY_SIZE: usize = 16;
+
+    let table_size = |entries: usize| TABLE_HEADER_SIZE + ENTRY_SIZE * entries;
+    let name_tables: Vec<_> = resources.values().flat_map(BTreeMap::values).collect();
+    let leaves: Vec<_> = name_tables
+        .iter()
+        .flat_map(|languages| languages.values())
+        .collect();
+
+    // Directory tables.
+    let mut offset = table_size(resources.len());
+    let mut type_table_offsets = Vec::new();
+    for names in resources.values() {
+        type_table_offsets.push(offset);
+        offset += table_size(names.len());
+    }
+    let mut name_table_offsets = Vec::new();
+    for languages in &name_tables {
+        name_table_offsets.push(offset);
+        offset += table_size(languages.len());
+    }
+
+    // Name strings, in the order the entries are written.
+    let all_names = resources
+        .keys()
+        .chain(resources.values().flat_map(BTreeMap::keys))
+        .chain(name_tables.iter().flat_map(|languages| languages.keys()));
+    let mut string_offsets = BTreeMap::new();
+    for name in all_names {
+        if let ResourceName::Name(name) = name {
+            string_offsets.entry(name.clone()).or_insert_with(|| {
+                let at = offset;
+                offset += 2 + name.len() * 2;
+                at
+            });
+        }
+    }
+
+    offset = offset.next_multiple_of(4);
+    let data_entries_offset = offset;
+    offset += DATA_ENTRY_SIZE * leaves.len();
+    let mut data_offsets = Vec::new();
+    for leaf in &leaves {
+        offset = offset.next_multiple_of(8);
+        data_offsets.push(offset);
+        offset += leaf.data.len();
+    }
+
+    let mut out = vec![0u8; offset];
+    let subdirectory = |offset: usize| pe::IMAGE_RESOURCE_DATA_IS_DIRECTORY | offset as u32;
+    let mut tables = Vec::new();
+    tables.push((
+        0,
+        resources.keys().collect::<Vec<_>>(),
+        type_table_offsets
+            .iter()
+            .map(|&at| subdirectory(at))
+            .collect::<Vec<_>>(),
+    ));
+    let mut name_table_offsets_iter = name_table_offsets.iter();
+    for (names, &at) in resources.values().zip(&type_table_offsets) {
+        let targets = names
+            .values()
+            .map(|_| subdirectory(*name_table_offsets_iter.next().unwrap()))
+            .collect();
+        tables.push((at, names.keys().collect(), targets));
+    }
+    let mut leaf_index = 0;
+    for (languages, &at) in name_tables.iter().zip(&name_table_offsets) {
+        let targets = (leaf_index..leaf_index + languages.len())
+            .map(|index| (data_entries_offset + index * DATA_ENTRY_SIZE) as u32)
+            .collect();
+        leaf_index += languages.len();
+        tables.push((at, languages.keys().collect(), targets));
+    }
+
+    for (at, names, targets) in tables {
+        let named = names
+            .iter()
+            .filter(|name| matches!(name, ResourceName::Name(_)))
+            .count();
+        write_u16(&mut out, at + 12, named as u16);
+        write_u16(&mut out, at + 14, (names.len() - named) as u16);
+        for (index, (name, target)) in names.into_iter().zip(targets).enumerate() {
+            let entry = at + TABLE_HEADER_SIZE + index * ENTRY_SIZE;
+            let name_or_id = match name {
+                ResourceName::Name(name) => {
+                    pe::IMAGE_RESOURCE_NAME_IS_STRING | string_offsets[name] as u32
+                }
+                ResourceName::Id(id) => *id as u32,
+            };
+            write_u32(&mut out, entry, name_or_id);
+            write_u32(&mut out, entry + 4, target);
+        }
+    }
+
+    for (name, &at) in &string_offsets {
+        write_u16(&mut out, at, name.len() as u16);
+        for (index, &unit) in name.iter().enumerate() {
+            write_u16(&mut out, at + 2 + index * 2, unit);
+        }
+    }
+
+    for (index, (leaf, &data_offset)) in leaves.iter().zip(&data_offsets).enumerate() {
+        let entry = data_entries_offset + index * DATA_ENTRY_SIZE;
+        write_u32(&mut out, entry, rva + data_offset as u32);
+        write_u32(&mut out, entry + 4, leaf.data.len() as u32);
+        write_u32(&mut out, entry + 8, leaf.code_page);
+        out[data_offset..data_offset + leaf.data.len()].copy_from_slice(&leaf.data);
+    }
+
+    out
+}
+
+fn write_u16(data: &mut [u8], at: usize, value: u16) {
+    data[at..at + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(data: &mut [u8], at: usize, value: u32) {
+    data[at..at + 4].copy_from_slice(&value.to_le_bytes());
+}
@@ -0,0 +1,148 @@
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{
+    pkcs8::{
+        spki::der::pem::LineEnding, DecodePrivateKey, DecodePublicKey, EncodePrivateKey,
+        EncodePublicKey,
+    },
+    Signature, Signer, SigningKey, VerifyingKey,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub const SIGNATURE_ALGORITHM: &str = "ed25519";
+
+/// Appended to the payload after the config JSON and a NUL byte. The
+/// signature covers the config JSON exactly as embedded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScriptSignature {
+    pub algorithm: String,
+    /// SHA-256 of the raw public key, hex encoded.
+    pub key_fingerprint: String,
+    /// Base64 encoded signature.
+    pub signature: String,
+}
+
+pub fn fingerprint(key: &VerifyingKey) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Append `signature` to `payload` as a NUL byte followed by its JSON.
+/// Serialized JSON never contains a raw NUL, so the split is unambiguous.
+pub fn append_signature(payload: &mut Vec<u8>, signature: &ScriptSignature) -> Result<()> {
+    payload.push(0);
+    payload.extend_from_slice(&serde_json::to_vec(signature)?);
+    Ok(())
+}
+
+/// Split a payload into the config JSON and the signature appended by
+/// `append_signature`, if any.
+pub fn split_signature(payload: &[u8]) -> Result<(&[u8], Option<ScriptSignature>)> {
+    match payload.iter().position(|&byte| byte == 0) {
+        Some(end) => {
+            let signature = serde_json::from_slice(&payload[end + 1..])
+                .context("Failed to decode the script signature")?;
+            Ok((&payload[..end], Some(signature)))
+        }
+        None => Ok((payload, None)),
+    }
+}
+
+pub fn sign(key: &SigningKey, message: &[u8]) -> ScriptSignature {
+    ScriptSignature {
+        algorithm: SIGNATURE_ALGORITHM.to_string(),
+        key_fingerprint: fingerprint(&key.verifying_key()),
+        signature: STANDARD.encode(key.sign(message).to_bytes()),
+    }
+}
+
+pub fn verify(signature: &ScriptSignature, key: &VerifyingKey, message: &[u8]) -> Result<()> {
+    if signature.algorithm != SIGNATURE_ALGORITHM {
+        anyhow::bail!(
+            "Unsupported script signature algorithm: {}",
+            signature.algorithm
+        );
+    }
+    let expected_fingerprint = fingerprint(key);
+    if signature.key_fingerprint != expected_fingerprint {
+        anyhow::bail!(
+            "Script was signed by key {}, not by the given key {expected_fingerprint}",
+            signature.key_fingerprint
+        );
+    }
+
+    let bytes = STANDARD
+        .decode(&signature.signature)
+        .context("Script signature is not valid base64")?;
+    let signature = Signature::from_slice(&bytes).context("Script signature is malformed")?;
+    key.verify_strict(message, &signature)
+        .map_err(|_| anyhow::anyhow!("Script signature does not match the payload"))
+}
+
+pub fn load_signing_key(path: &Path) -> Result<SigningKey> {
+    let pem = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read script key {}", path.display()))?;
+    SigningKey::from_pkcs8_pem(&pem)
+        .map_err(|e| anyhow::anyhow!("{} is not an Ed25519 PKCS#8 PEM key: {e}", path.display()))
+}
+
+pub fn load_verifying_key(path: &Path) -> Result<VerifyingKey> {
+    let pem = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read public key {}", path.display()))?;
+    VerifyingKey::from_public_key_pem(&pem)
+        .map_err(|e| anyhow::anyhow!("{} is not an Ed25519 public key PEM: {e}", path.display()))
+}
+
+/// Path of the public key written next to the private key at `key_path`.
+pub fn public_key_path(key_path: &Path) -> PathBuf {
+    key_path.with_extension("pub.pem")
+}
+
+/// Generate a key pair: the private key at `key_path` and the public key
+/// next to it. Neither file may exist yet. Returns the public key path.
+pub fn generate_key_pair(key_path: &Path) -> Result<PathBuf> {
+    let key = SigningKey::generate(&mut rand::rngs::OsRng);
+    let private_pem = key
+        .to_pkcs8_pem(LineEnding::LF)
+        .map_err(|e| anyhow::anyhow!("Failed to encode private key: {e}"))?;
+    let public_pem = key
+        .verifying_key()
+        .to_public_key_pem(LineEnding::LF)
+        .map_err(|e| anyhow::anyhow!("Failed to encode public key: {e}"))?;
+
+    let public_key_path = public_key_path(key_path);
+    for path in [key_path, public_key_path.as_path()] {
+        if path.exists() {
+            anyhow::bail!(
+                "{} already exists, refusing to overwrite it",
+                path.display()
+            );
+        }
+    }
+    write_new_file(key_path, private_pem.as_bytes(), 0o600)?;
+    write_new_file(&public_key_path, public_pem.as_bytes(), 0o644)?;
+
+    Ok(public_key_path)
+}
+
+/// Create `path`, failing if it already exists. `mode` sets the permissions
+/// on unix, so a private key is never readable by other users.
+fn write_new_file(path: &Path, data: &[u8], mode: u32) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+    #[cfg(not(unix))]
+    let _ = mode;
+
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    file.write_all(data)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
@@ -0,0 +1,23 @@
+// Byte readers for the tests that parse the formats written by fripack.
+
+pub fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+pub fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Read the ULEB128 value at `*at` and move past it.
+pub fn read_uleb128(data: &[u8], at: &mut usize) -> u32 {
+    let mut value = 0;
+    for shift in (0..35).step_by(7) {
+        let byte = data[*at];
+        *at += 1;
+        value |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    value
+}
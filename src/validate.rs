@@ -0,0 +1,252 @@
+use anyhow::Result;
+use object::{
+    elf, pe,
+    read::elf::{Dyn, FileHeader, ProgramHeader},
+    read::pe::{ImageNtHeaders, ImageOptionalHeader},
+    LittleEndian as LE,
+};
+
+/// Structural checks run after every rewrite, so a broken layout fails the
+/// build instead of the loader on the device.
+pub fn validate(data: &[u8]) -> Result<()> {
+    let issues = match object::FileKind::parse(data)? {
+        object::FileKind::Elf32 => validate_elf::<elf::FileHeader32<object::Endianness>>(data)?,
+        object::FileKind::Elf64 => validate_elf::<elf::FileHeader64<object::Endianness>>(data)?,
+        object::FileKind::Pe32 => validate_pe::<pe::ImageNtHeaders32>(data)?,
+        object::FileKind::Pe64 => validate_pe::<pe::ImageNtHeaders64>(data)?,
+        _ => Vec::new(),
+    };
+
+    if !issues.is_empty() {
+        anyhow::bail!(
+            "Generated binary failed validation:\n  - {}",
+            issues.join("\n  - ")
+        );
+    }
+    Ok(())
+}
+
+fn validate_elf<Elf: FileHeader>(data: &[u8]) -> Result<Vec<String>> {
+    let mut issues = Vec::new();
+    let header = Elf::parse(data)?;
+    let endian = header.endian()?;
+    let phdrs = header.program_headers(endian, data)?;
+
+    let file_len = data.len() as u64;
+    let mut loads = Vec::new();
+    for (index, phdr) in phdrs.iter().enumerate() {
+        let p_type = phdr.p_type(endian);
+        let offset: u64 = phdr.p_offset(endian).into();
+        let vaddr: u64 = phdr.p_vaddr(endian).into();
+        let filesz: u64 = phdr.p_filesz(endian).into();
+        let memsz: u64 = phdr.p_memsz(endian).into();
+        let align: u64 = phdr.p_align(endian).into();
+
+        if offset.checked_add(filesz).is_none_or(|end| end > file_len) {
+            issues.push(format!(
+                "Program header {index} (type {p_type:#x}) file range {offset:#x}+{filesz:#x} is past the end of the file ({file_len:#x})"
+            ));
+        }
+        if p_type != elf::PT_LOAD {
+            continue;
+        }
+        if filesz > memsz {
+            issues.push(format!(
+                "PT_LOAD {index} has p_filesz {filesz:#x} larger than p_memsz {memsz:#x}"
+            ));
+        }
+        if align > 1 {
+            if !align.is_power_of_two() {
+                issues.push(format!(
+                    "PT_LOAD {index} has p_align {align:#x} that is not a power of two"
+                ));
+            } else if offset % align != vaddr % align {
+                issues.push(format!(
+                    "PT_LOAD {index} is misaligned: p_offset {offset:#x} and p_vaddr {vaddr:#x} differ modulo p_align {align:#x}"
+                ));
+            }
+        }
+        loads.push((index, offset, vaddr, filesz, memsz));
+    }
+
+    if loads.windows(2).any(|pair| pair[0].2 > pair[1].2) {
+        issues.push("PT_LOAD segments are not sorted by p_vaddr".to_string());
+    }
+    let mut by_vaddr = loads.clone();
+    by_vaddr.sort_by_key(|load| load.2);
+    for pair in by_vaddr.windows(2) {
+        let (a, _, a_vaddr, _, a_memsz) = pair[0];
+        let (b, _, b_vaddr, _, _) = pair[1];
+        if a_vaddr + a_memsz > b_vaddr {
+            issues.push(format!(
+                "PT_LOAD {a} ({a_vaddr:#x}+{a_memsz:#x}) overlaps PT_LOAD {b} at {b_vaddr:#x}"
+            ));
+        }
+    }
+
+    // The program headers have to be mapped for the dynamic linker to find them.
+    let phoff: u64 = header.e_phoff(endian).into();
+    let phsize = header.e_phnum(endian) as u64 * header.e_phentsize(endian) as u64;
+    let covering_load = loads
+        .iter()
+        .find(|&&(_, offset, _, filesz, _)| offset <= phoff && phoff + phsize <= offset + filesz);
+    match covering_load {
+        None => issues.push(format!(
+            "Program headers at {phoff:#x}+{phsize:#x} are not covered by any PT_LOAD segment"
+        )),
+        Some(&(index, offset, vaddr, _, _)) => {
+            if let Some(phdr) = phdrs
+                .iter()
+                .find(|phdr| phdr.p_type(endian) == elf::PT_PHDR)
+            {
+                let phdr_offset: u64 = phdr.p_offset(endian).into();
+                let phdr_vaddr: u64 = phdr.p_vaddr(endian).into();
+                let phdr_size: u64 = phdr.p_filesz(endian).into();
+                if phdr_offset != phoff || phdr_size < phsize {
+                    issues.push(format!(
+                        "PT_PHDR ({phdr_offset:#x}+{phdr_size:#x}) does not describe the program headers at {phoff:#x}+{phsize:#x}"
+                    ));
+                } else if phdr_vaddr != vaddr + (phoff - offset) {
+                    issues.push(format!(
+                        "PT_PHDR p_vaddr {phdr_vaddr:#x} does not match its position in PT_LOAD {index}"
+                    ));
+                }
+            }
+        }
+    }
+
+    issues.extend(validate_elf_dynamic::<Elf>(data, header, &loads)?);
+    Ok(issues)
+}
+
+/// Check that DT_STRTAB is mapped and every DT_NEEDED/DT_SONAME string is
+/// inside it.
+fn validate_elf_dynamic<Elf: FileHeader>(
+    data: &[u8],
+    header: &Elf,
+    loads: &[(usize, u64, u64, u64, u64)],
+) -> Result<Vec<String>> {
+    let mut issues = Vec::new();
+    let endian = header.endian()?;
+    let Some(dynamic) = header
+        .program_headers(endian, data)?
+        .iter()
+        .find_map(|phdr| phdr.dynamic(endian, data).transpose())
+        .transpose()?
+    else {
+        return Ok(issues);
+    };
+
+    let value = |tag: u32| {
+        dynamic
+            .iter()
+            .find(|entry| entry.tag32(endian) == Some(tag))
+            .map(|entry| entry.d_val(endian).into())
+    };
+    let (Some(strtab), Some(strsz)) = (value(elf::DT_STRTAB), value(elf::DT_STRSZ)) else {
+        issues.push("PT_DYNAMIC has no DT_STRTAB/DT_STRSZ".to_string());
+        return Ok(issues);
+    };
+    let strtab: u64 = strtab;
+    let strsz: u64 = strsz;
+
+    let strtab_offset = loads.iter().find_map(|&(_, offset, vaddr, filesz, _)| {
+        (vaddr <= strtab && strtab + strsz <= vaddr + filesz).then(|| offset + (strtab - vaddr))
+    });
+    let Some(strtab_offset) = strtab_offset else {
+        issues.push(format!(
+            "DT_STRTAB {strtab:#x}+{strsz:#x} is not mapped from the file by a PT_LOAD segment"
+        ));
+        return Ok(issues);
+    };
+    let strings = &data[strtab_offset as usize..(strtab_offset + strsz) as usize];
+
+    for entry in dynamic {
+        let tag = match entry.tag32(endian) {
+            Some(elf::DT_NEEDED) => "DT_NEEDED",
+            Some(elf::DT_SONAME) => "DT_SONAME",
+            Some(elf::DT_NULL) => break,
+            _ => continue,
+        };
+        let index: u64 = entry.d_val(endian).into();
+        let name = strings
+            .get(index as usize..)
+            .and_then(|rest| rest.split(|&b| b == 0).next().filter(|_| rest.contains(&0)));
+        match name {
+            Some(name) if !name.is_empty() => {}
+            _ => issues.push(format!(
+                "{tag} string index {index:#x} does not point to a valid string in DT_STRTAB (size {strsz:#x})"
+            )),
+        }
+    }
+
+    Ok(issues)
+}
+
+fn validate_pe<Pe: ImageNtHeaders>(data: &[u8]) -> Result<Vec<String>> {
+    let mut issues = Vec::new();
+    let dos_header = pe::ImageDosHeader::parse(data)?;
+    let mut offset = dos_header.nt_headers_offset().into();
+    let (nt_headers, data_directories) = Pe::parse(data, &mut offset)?;
+    let optional_header = nt_headers.optional_header();
+    let sections = nt_headers.sections(data, offset)?;
+
+    let file_len = data.len() as u64;
+    let section_alignment = optional_header.section_alignment();
+    let file_alignment = optional_header.file_alignment();
+    let size_of_image = optional_header.size_of_image();
+    let size_of_headers = optional_header.size_of_headers();
+
+    let mut previous_end = size_of_headers;
+    for (index, section) in sections.iter().enumerate() {
+        let name = String::from_utf8_lossy(section.raw_name());
+        let virtual_address = section.virtual_address.get(LE);
+        let virtual_size = section.virtual_size.get(LE);
+        let raw_offset = section.pointer_to_raw_data.get(LE);
+        let raw_size = section.size_of_raw_data.get(LE);
+
+        if raw_size != 0 && raw_offset as u64 + raw_size as u64 > file_len {
+            issues.push(format!(
+                "Section {index} ({name}) raw data {raw_offset:#x}+{raw_size:#x} is past the end of the file ({file_len:#x})"
+            ));
+        }
+        if raw_size != 0 && file_alignment != 0 && raw_offset % file_alignment != 0 {
+            issues.push(format!(
+                "Section {index} ({name}) PointerToRawData {raw_offset:#x} is not aligned to FileAlignment {file_alignment:#x}"
+            ));
+        }
+        if section_alignment != 0 && virtual_address % section_alignment != 0 {
+            issues.push(format!(
+                "Section {index} ({name}) VirtualAddress {virtual_address:#x} is not aligned to SectionAlignment {section_alignment:#x}"
+            ));
+        }
+        if virtual_address < previous_end {
+            issues.push(format!(
+                "Section {index} ({name}) at {virtual_address:#x} overlaps the previous section or headers ending at {previous_end:#x}"
+            ));
+        }
+        let end = virtual_address as u64 + virtual_size.max(raw_size) as u64;
+        if end > size_of_image as u64 {
+            issues.push(format!(
+                "Section {index} ({name}) ends at {end:#x}, past SizeOfImage {size_of_image:#x}"
+            ));
+        }
+        previous_end = virtual_address.saturating_add(virtual_size.max(raw_size).max(1));
+    }
+
+    for (index, directory) in data_directories.iter().enumerate() {
+        let address = directory.virtual_address.get(LE);
+        let size = directory.size.get(LE);
+        // The certificate table uses a file offset instead of an RVA.
+        if address == 0 || index == pe::IMAGE_DIRECTORY_ENTRY_SECURITY {
+            continue;
+        }
+        if address as u64 + size as u64 > size_of_image as u64 {
+            issues.push(format!(
+                "Data directory {index} ({address:#x}+{size:#x}) is past SizeOfImage {size_of_image:#x}"
+            ));
+        }
+    }
+
+    Ok(issues)
+}